@@ -0,0 +1,343 @@
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use log::*;
+use prettytable::*;
+use rocksdb::DB;
+use serde::*;
+
+use crate::{force_get_json, init_db, LogUnwrap};
+use crate::settings::{Config, HeldStudent, Status, Verdict};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Submitted,
+    Held,
+    Failed,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Entry {
+    pub student_id: String,
+    pub outcome: Outcome,
+    pub grade: Option<f64>,
+    pub reasons: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Report {
+    pub started: chrono::DateTime<chrono::Utc>,
+    pub finished: chrono::DateTime<chrono::Utc>,
+    pub interrupted: bool,
+    pub entries: Vec<Entry>,
+}
+
+pub struct Batch<'a> {
+    pub workdir: &'a Path,
+    pub nutshell: &'a Path,
+    pub log_level: &'a str,
+    pub cache_size: u64,
+    pub backend: String,
+    pub shellcheck: PathBuf,
+    pub mount_dir: PathBuf,
+    pub tmp_size: Option<usize>,
+    pub hold: bool,
+    pub review_below: Option<f64>,
+}
+
+const HELD_KEY: &str = "held";
+
+/// Students held for review and not pulled again yet.
+pub fn held(db: &DB) -> Vec<HeldStudent> {
+    db.get(HELD_KEY)
+        .ok().flatten()
+        .and_then(|mut x| simd_json::serde::from_slice::<Vec<HeldStudent>>(x.as_mut_slice()).ok())
+        .unwrap_or_default()
+}
+
+fn save_held(db: &DB, held: &[HeldStudent]) {
+    db.put(HELD_KEY, serde_json::to_vec(held).exit_on_failure()).exit_on_failure();
+}
+
+/// Mark a pulled student again if a batch held it, the mark never reached the server.
+pub fn restore_mark(db: &DB, status: &mut Status) {
+    let student_id = match status.in_progress.as_ref() {
+        Some(x) => x.student_id.as_str(),
+        None => return
+    };
+    let mut held = held(db);
+    if let Some(index) = held.iter().position(|x| x.student_id == student_id) {
+        let student = held.remove(index);
+        info!("{} was held for review: {}", student.student_id, student.reasons.join("; "));
+        status.mark = true;
+        save_held(db, held.as_slice());
+    }
+}
+
+/// What the batch does after a student.
+enum Next {
+    Student(Entry),
+    Stop(Option<Entry>, String),
+    Finished,
+}
+
+impl<'a> Batch<'a> {
+    /// Every step runs as a separate tulip process, so a failing student only fails its own step.
+    /// Children are put into their own process group to finish the current step on Ctrl-C.
+    fn spawn(&self, args: &[&str]) -> Option<ExitStatus> {
+        let exe = std::env::current_exe().exit_on_failure();
+        let mut command = Command::new(exe);
+        command.arg("--tulip-dir").arg(self.workdir)
+            .arg("--nutshell").arg(self.nutshell)
+            .arg("--log-level").arg(self.log_level)
+            .arg("--cache-size").arg(self.cache_size.to_string())
+            .arg("--yes")
+            .args(args)
+            .stdin(Stdio::null())
+            .process_group(0);
+        debug!("batch step: {:?}", command);
+        let _passphrase = crate::credential::share_passphrase(&mut command);
+        match command.spawn().and_then(|mut x| x.wait()) {
+            Ok(e) => Some(e),
+            Err(e) => {
+                error!("unable to start step {}: {}", args[0], e);
+                None
+            }
+        }
+    }
+
+    fn step(&self, args: &[&str]) -> bool {
+        match self.spawn(args) {
+            Some(e) if e.success() => true,
+            Some(e) => {
+                warn!("step {} failed with {}", args[0], e);
+                false
+            }
+            None => false
+        }
+    }
+
+    /// Give the student back to the server so that the next one can be fetched.
+    fn release(&self, mut entry: Entry) -> Next {
+        if self.step(&["skip"]) {
+            Next::Student(entry)
+        } else {
+            entry.outcome = Outcome::Failed;
+            entry.reasons.push(String::from("skip failed"));
+            let reason = format!("unable to skip {}", entry.student_id);
+            Next::Stop(Some(entry), reason)
+        }
+    }
+
+    fn status(&self) -> Status {
+        let db = init_db(self.workdir.join("meta").as_path());
+        force_get_json::<Status>(&db, "status")
+    }
+
+    fn config(&self) -> Config {
+        let db = init_db(self.workdir.join("meta").as_path());
+        force_get_json::<Config>(&db, "config")
+    }
+
+    /// Fetch and grade the next student.
+    fn student(&self) -> Next {
+        let shellcheck = self.shellcheck.to_string_lossy();
+        let fetched = self.spawn(&["fetch", "-b", self.backend.as_str(), "-s", shellcheck.as_ref()]);
+        if fetched.and_then(|x| x.code()) == Some(crate::student::NO_STUDENT) {
+            return Next::Finished;
+        }
+        let status = self.status();
+        let student_id = match status.in_progress.as_ref() {
+            Some(x) if !status.submitted => x.student_id.clone(),
+            _ => return Next::Stop(None, String::from("unable to fetch the next student")),
+        };
+        let mut entry = Entry {
+            student_id,
+            outcome: Outcome::Failed,
+            grade: None,
+            reasons: Vec::new(),
+        };
+        if !fetched.map(|x| x.success()).unwrap_or(false) {
+            entry.reasons.push(String::from("fetch failed"));
+            return self.release(entry);
+        }
+        info!("batch grading {}", entry.student_id);
+
+        let mount_dir = self.mount_dir.to_string_lossy();
+        let tmp_size = self.tmp_size.map(|x| x.to_string());
+        let mut mount = vec!["init-overlay", "-m", mount_dir.as_ref()];
+        if let Some(size) = tmp_size.as_ref() {
+            mount.push("-t");
+            mount.push(size.as_str());
+        }
+        if !self.step(mount.as_slice()) {
+            entry.reasons.push(String::from("mount failed"));
+            return Next::Stop(Some(entry), String::from("unable to mount the overlay"));
+        }
+
+        // a failed step leaves the student without a trustworthy grade
+        let mut failed = true;
+        if crate::risk::high_risk(&self.status()) {
+            // --yes would confirm the build, leave it to the grader instead
+            entry.reasons.push(String::from("high risk patterns in the scripts, not built"));
+        } else if !self.step(&["build", "--rebuild"]) {
+            entry.reasons.push(String::from("build failed"));
+        } else if self.config().test_cases.is_empty() {
+            if self.step(&["run"]) {
+                failed = false;
+            } else {
+                entry.reasons.push(String::from("run failed"));
+            }
+        } else if self.step(&["test"]) {
+            failed = false;
+        } else {
+            entry.reasons.push(String::from("tests failed to run"));
+        }
+
+        let status = self.status();
+        for result in &status.test_results {
+            if result.verdict != Verdict::Accepted {
+                entry.reasons.push(format!("{}: {}", result.name, result.verdict));
+            }
+        }
+        match status.suggested.as_ref().map(|x| x.score) {
+            Some(_) if failed => (),
+            Some(score) => {
+                if self.review_below.map(|x| score < x).unwrap_or(false) {
+                    entry.reasons.push(format!("suggested grade {} below review threshold", score));
+                }
+                if self.step(&["grade", "--accept-suggested", "--override"]) {
+                    entry.grade = self.status().total();
+                } else {
+                    entry.reasons.push(String::from("grading failed"));
+                }
+            }
+            None => entry.reasons.push(String::from("no suggested grade")),
+        }
+        if status.mark {
+            entry.reasons.push(String::from("marked"));
+        }
+
+        if self.hold && !entry.reasons.is_empty() {
+            warn!("holding {} for review: {}", entry.student_id, entry.reasons.join("; "));
+            // skipping clears the local status, so the mark is kept aside until the student is pulled again
+            {
+                let db = init_db(self.workdir.join("meta").as_path());
+                let mut held = held(&db);
+                held.retain(|x| x.student_id != entry.student_id);
+                held.push(HeldStudent {
+                    student_id: entry.student_id.clone(),
+                    reasons: entry.reasons.clone(),
+                    held_at: chrono::Utc::now(),
+                });
+                save_held(&db, held.as_slice());
+            }
+            entry.reasons.push(String::from("not marked on the server, marked again by `tulip pull`"));
+            entry.outcome = Outcome::Held;
+            return self.release(entry);
+        }
+        if entry.grade.is_none() {
+            warn!("{} has no grade, not submitted: {}", entry.student_id, entry.reasons.join("; "));
+            return self.release(entry);
+        }
+        if !self.step(&["submit"]) {
+            entry.reasons.push(String::from("submit failed"));
+            return self.release(entry);
+        }
+        entry.outcome = Outcome::Submitted;
+        if !self.step(&["clear"]) {
+            warn!("unable to clear {} after submitting", entry.student_id);
+        }
+        Next::Student(entry)
+    }
+
+    pub fn handle(&self, count: Option<usize>, report: Option<PathBuf>) {
+        let status = self.status();
+        if status.in_progress.is_some() && !status.submitted {
+            error!("please submit or skip the current project first");
+            std::process::exit(1);
+        }
+        if self.config().scoring.is_none() && !self.hold {
+            error!("no scoring policy configured, every student would be skipped without a grade; use --hold to keep them for review");
+            std::process::exit(1);
+        }
+        {
+            // unlock the credential once, the passphrase is handed to the steps
+            let db = init_db(self.workdir.join("meta").as_path());
+            crate::credential::identity(&db);
+        }
+
+        let interrupted = Arc::new(AtomicBool::new(false));
+        {
+            let interrupted = interrupted.clone();
+            ctrlc::set_handler(move || {
+                warn!("interrupted, stopping after the current student");
+                interrupted.store(true, Ordering::SeqCst);
+            }).exit_on_failure();
+        }
+
+        let started = chrono::Utc::now();
+        let mut entries = Vec::new();
+        while count.map(|x| entries.len() < x).unwrap_or(true) && !interrupted.load(Ordering::SeqCst) {
+            match self.student() {
+                Next::Student(entry) => entries.push(entry),
+                Next::Stop(entry, reason) => {
+                    error!("batch stopped: {}", reason);
+                    entries.extend(entry);
+                    break;
+                }
+                Next::Finished => {
+                    info!("no more students");
+                    break;
+                }
+            }
+        }
+
+        let report_data = Report {
+            started,
+            finished: chrono::Utc::now(),
+            interrupted: interrupted.load(Ordering::SeqCst),
+            entries,
+        };
+        crate::output::emit(&report_data, || { report_table(&report_data).printstd(); });
+        let path = report.unwrap_or_else(|| self.workdir
+            .join(format!("batch-{}.json", started.format("%Y%m%d%H%M%S"))));
+        write_report(path.as_path(), &report_data);
+    }
+}
+
+fn write_report(path: &Path, report: &Report) {
+    match serde_json::to_vec_pretty(report).map_err(|x| x.to_string())
+        .and_then(|x| std::fs::write(path, x).map_err(|x| x.to_string())) {
+        Ok(()) => info!("batch report written to {}", path.display()),
+        Err(e) => error!("failed to write batch report: {}", e)
+    }
+}
+
+pub fn held_table(held: &[HeldStudent]) -> Table {
+    let mut table = Table::new();
+    table.add_row(row![bFb->"ID", bFb->"Held At", bFb->"Reasons"]);
+    for i in held {
+        table.add_row(row![i.student_id, i.held_at, i.reasons.join("\n")]);
+    }
+    table
+}
+
+pub fn report_table(report: &Report) -> Table {
+    let mut table = Table::new();
+    table.add_row(row![bFb->"ID", bFb->"Outcome", bFb->"Grade", bFb->"Reasons"]);
+    for i in &report.entries {
+        let grade = i.grade.map(|x| x.to_string()).unwrap_or_else(|| String::from("N/A"));
+        let reasons = i.reasons.join("\n");
+        match i.outcome {
+            Outcome::Submitted => table.add_row(row![i.student_id, bFg->"submitted", grade, reasons]),
+            Outcome::Held => table.add_row(row![i.student_id, bFy->"held", grade, reasons]),
+            Outcome::Failed => table.add_row(row![i.student_id, bFr->"failed", grade, reasons]),
+        };
+    }
+    table
+}
@@ -9,7 +9,7 @@ use std::thread;
 use log::*;
 use rocksdb::DB;
 
-use crate::{force_get_json, LogUnwrap};
+use crate::{force_get_json, LogUnwrap, prompt};
 use crate::settings::{Config, Status};
 
 pub fn handle(db: &DB, rebuild: bool, workdir: &Path) {
@@ -30,6 +30,18 @@ pub fn handle(db: &DB, rebuild: bool, workdir: &Path) {
         std::process::exit(1);
     }
 
+    // the scripts may be edited after fetching
+    let config = force_get_json::<Config>(db, "config");
+    crate::risk::scan(Some(&config), &mut status, workdir);
+    db.put("status", serde_json::to_string(&status).exit_on_failure()).exit_on_failure();
+    if crate::risk::high_risk(&status) {
+        crate::output::show(&crate::risk::risk_table(&status.risks));
+        if !prompt::confirm("risk", "The scripts contain high risk patterns, build anyway?", false) {
+            error!("build cancelled");
+            std::process::exit(1);
+        }
+    }
+
     let student = status.in_progress.as_ref().unwrap();
 
     let mut child = build_nspawn(&db, &status, workdir, true, true)
@@ -70,6 +82,9 @@ pub fn handle(db: &DB, rebuild: bool, workdir: &Path) {
         .exit_on_failure();
 
     status.built = true;
+    status.test_results.clear();
+    status.verdict = None;
+    status.suggested = None;
     status.build_stdout.replace(String::from_utf8_lossy(out_captured.as_slice()).to_string());
     status.build_stderr.replace(String::from_utf8_lossy(err_captured.as_slice()).to_string());
     db.put("status", serde_json::to_string(&status).exit_on_failure()).exit_on_failure();
@@ -102,7 +117,9 @@ pub fn build_nspawn(db: &DB, status: &Status, workdir: &Path, sync_student: bool
     info!("starting systemd-nspawn");
     let mut builder = std::process::Command::new("sudo");
 
-    builder.arg("-k")
+    builder.env_remove("TULIP_PASSPHRASE")
+        .env_remove("TULIP_PASSPHRASE_FD")
+        .arg("-k")
         .arg("systemd-nspawn")
         .arg("--quiet")
         .arg("-D")
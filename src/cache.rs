@@ -0,0 +1,303 @@
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use chrono::{DateTime, Utc};
+use log::*;
+use prettytable::*;
+use reqwest::{StatusCode, Url};
+use reqwest::header::{ETAG, IF_NONE_MATCH};
+use serde::*;
+
+use crate::credential::{Authenticate, Identity};
+use crate::http::Dispatch;
+use crate::{LogUnwrap, prompt};
+use crate::validate::url_id;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CacheEntry {
+    pub student_id: String,
+    // sha256 of the tarball, also the name of the blob
+    pub hash: String,
+    pub etag: Option<String>,
+    pub size: u64,
+    pub fetched_at: DateTime<Utc>,
+    pub last_used: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct CacheIndex {
+    entries: Vec<CacheEntry>,
+}
+
+/// Student tarballs stored by content hash in the tulip dir, evicted by least recent use.
+pub struct Cache {
+    dir: PathBuf,
+    // in bytes
+    max_size: u64,
+}
+
+fn sha256(path: &Path) -> Result<String, String> {
+    let mut file = std::fs::File::open(path).map_err(|x| x.to_string())?;
+    let mut context = ring::digest::Context::new(&ring::digest::SHA256);
+    let mut buffer = [0u8; 65536];
+    loop {
+        let size = file.read(&mut buffer).map_err(|x| x.to_string())?;
+        if size == 0 {
+            break;
+        }
+        context.update(&buffer[..size]);
+    }
+    Ok(context.finish().as_ref().iter().map(|x| format!("{:02x}", x)).collect())
+}
+
+/// The ETag of the last response in the output of `wget --server-response`, redirects come first.
+fn etag(output: &str) -> Option<String> {
+    let mut etag = None;
+    for line in output.lines().map(|x| x.trim()) {
+        if line.starts_with("HTTP/") {
+            etag = None;
+        } else if line.to_ascii_lowercase().starts_with("etag:") {
+            etag = Some(line[5..].trim().to_string());
+        }
+    }
+    etag
+}
+
+impl Cache {
+    pub fn new(workdir: &Path, max_size_mib: u64) -> Self {
+        Cache {
+            dir: workdir.join("cache"),
+            max_size: max_size_mib * 1024 * 1024,
+        }
+    }
+
+    fn blob(&self, hash: &str) -> PathBuf {
+        self.dir.join("blobs").join(hash)
+    }
+
+    fn load(&self) -> CacheIndex {
+        std::fs::read(self.dir.join("index.json"))
+            .ok()
+            .and_then(|mut x| simd_json::serde::from_slice::<CacheIndex>(x.as_mut_slice()).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self, index: &CacheIndex) {
+        std::fs::create_dir_all(self.dir.as_path()).exit_on_failure();
+        std::fs::write(self.dir.join("index.json"), serde_json::to_vec(index).exit_on_failure())
+            .exit_on_failure();
+    }
+
+    /// Remove blobs no entry refers to any more.
+    fn collect_garbage(&self, index: &CacheIndex) {
+        let entries = match std::fs::read_dir(self.dir.join("blobs")) {
+            Ok(entries) => entries,
+            Err(_) => return
+        };
+        for blob in entries.filter_map(|x| x.ok()) {
+            let name = blob.file_name().to_string_lossy().to_string();
+            if !index.entries.iter().any(|x| x.hash == name) {
+                debug!("removing unused blob {}", name);
+                if let Err(e) = std::fs::remove_file(blob.path()) {
+                    warn!("failed to remove {}: {}", blob.path().display(), e);
+                }
+            }
+        }
+    }
+
+    fn evict(&self, index: &mut CacheIndex, keep: Option<&str>) {
+        index.entries.sort_by_key(|x| x.last_used);
+        let mut total: u64 = index.entries.iter().map(|x| x.size).sum();
+        while total > self.max_size {
+            let position = match index.entries.iter().position(|x| Some(x.student_id.as_str()) != keep) {
+                Some(x) => x,
+                None => break
+            };
+            let entry = index.entries.remove(position);
+            info!("evicting {} from the cache", entry.student_id);
+            total -= entry.size;
+        }
+    }
+
+    /// Revalidate the cached tarball with the server, `None` if it has to be downloaded.
+    fn revalidate(&self, entry: &CacheEntry, url: &Url, identity: &Identity) -> Option<PathBuf> {
+        let blob = self.blob(entry.hash.as_str());
+        if !blob.exists() {
+            return None;
+        }
+        let etag = entry.etag.as_ref()?;
+        match crate::http::client()
+            .head(url.clone())
+            .authenticate(identity)
+            .header(IF_NONE_MATCH, etag.as_str())
+            .dispatch_idempotent() {
+            Ok(response) if response.status() == StatusCode::NOT_MODIFIED => Some(blob),
+            Ok(response) => {
+                debug!("cache revalidation returned {}", response.status());
+                None
+            }
+            Err(e) => {
+                warn!("unable to revalidate the cached tarball of {}: {}", entry.student_id, e);
+                if prompt::confirm("cache-stale", "Use the cached tarball, which may be outdated?", false) {
+                    Some(blob)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// Download with the client of tulip, the ETag comes from the same response.
+    fn download_builtin(url: &Url, identity: &Identity, target: &Path) -> Result<Option<String>, String> {
+        let mut response = crate::http::client()
+            .get(url.clone())
+            .authenticate(identity)
+            .dispatch_idempotent()
+            .and_then(|x| x.error_for_status())
+            .map_err(|x| x.to_string())?;
+        let etag = response.headers().get(ETAG).and_then(|x| x.to_str().ok()).map(|x| x.to_string());
+        let mut file = std::fs::File::create(target).map_err(|x| x.to_string())?;
+        response.copy_to(&mut file).map_err(|x| x.to_string())?;
+        Ok(etag)
+    }
+
+    /// Download the tarball and return the ETag of the response, aria2c does not report it.
+    fn download(backend: &str, url: &Url, identity: &Identity, target: &Path) -> Result<Option<String>, String> {
+        if backend == "builtin" {
+            return Cache::download_builtin(url, identity, target);
+        }
+        crate::http::check_downloader(backend);
+        let auth = format!("Authorization: {}", crate::credential::authorization(identity, "GET", url, &[]));
+        let mut command = match backend {
+            "wget" => {
+                let mut command = std::process::Command::new("wget");
+                // the headers are printed to stderr along with the progress
+                command.arg("--show-progress").arg("--server-response").arg("-O").arg(target)
+                    .stderr(Stdio::piped());
+                command
+            }
+            "aria2c" => {
+                let mut command = std::process::Command::new("aria2c");
+                command.arg("--optimize-concurrent-downloads")
+                    .arg("--auto-file-renaming=false")
+                    .arg("--allow-overwrite=true")
+                    .arg("--dir")
+                    .arg(target.parent().unwrap())
+                    .arg("-o")
+                    .arg(target.file_name().unwrap());
+                command
+            }
+            _ => unreachable!()
+        };
+        let mut child = command.args(crate::http::downloader_args(backend))
+            .arg("--header")
+            .arg(auth)
+            .arg(url.as_str())
+            .spawn()
+            .map_err(|x| x.to_string())?;
+        let mut headers = Vec::new();
+        if let Some(mut stderr) = child.stderr.take() {
+            let mut buffer = [0u8; 8192];
+            while let Ok(size) = stderr.read(&mut buffer) {
+                if size == 0 {
+                    break;
+                }
+                let _ = std::io::stderr().write_all(&buffer[..size]);
+                headers.extend_from_slice(&buffer[..size]);
+            }
+        }
+        child.wait()
+            .map_err(|x| x.to_string())
+            .and_then(|x| if x.success() { Ok(()) } else { Err(format!("{} failed with: {}", backend, x)) })?;
+        Ok(etag(String::from_utf8_lossy(headers.as_slice()).as_ref()))
+    }
+
+    /// Return the path of the tarball of the student, downloading it only if the server has a newer one.
+    pub fn fetch(&self, backend: &str, server: &str, identity: &Identity, student_id: &str) -> PathBuf {
+        let url = format!("{}/student/{}/tar", server, url_id(student_id)).parse::<Url>().exit_on_failure();
+        let mut index = self.load();
+        let latest = index.entries.iter()
+            .filter(|x| x.student_id == student_id)
+            .max_by_key(|x| x.fetched_at)
+            .map(|x| x.hash.clone());
+        let cached = index.entries.iter()
+            .find(|x| x.student_id == student_id && Some(&x.hash) == latest.as_ref())
+            .and_then(|x| self.revalidate(x, &url, identity));
+        if let Some(blob) = cached {
+            info!("using the cached tarball of {}", student_id);
+            if let Some(entry) = index.entries.iter_mut().find(|x| x.student_id == student_id && Some(&x.hash) == latest.as_ref()) {
+                entry.last_used = Utc::now();
+            }
+            self.save(&index);
+            return blob;
+        }
+
+        std::fs::create_dir_all(self.dir.join("blobs")).exit_on_failure();
+        let partial = self.dir.join(format!("{}.part", url_id(student_id)));
+        let etag = Cache::download(backend, &url, identity, partial.as_path()).exit_on_failure();
+        let hash = sha256(partial.as_path()).exit_on_failure();
+        let size = std::fs::metadata(partial.as_path()).exit_on_failure().len();
+        let blob = self.blob(hash.as_str());
+        std::fs::rename(partial.as_path(), blob.as_path()).exit_on_failure();
+
+        let now = Utc::now();
+        // earlier versions are kept for `tulip diff` until they are evicted
+        index.entries.retain(|x| x.student_id != student_id || x.hash != hash);
+        index.entries.push(CacheEntry {
+            student_id: student_id.to_string(),
+            hash,
+            etag,
+            size,
+            fetched_at: now,
+            last_used: now,
+        });
+        self.evict(&mut index, Some(student_id));
+        self.save(&index);
+        self.collect_garbage(&index);
+        blob
+    }
+
+    /// Cached versions of the student, the newest first.
+    pub fn versions(&self, student_id: &str) -> Vec<CacheEntry> {
+        let mut versions = self.load().entries.into_iter()
+            .filter(|x| x.student_id == student_id && self.blob(x.hash.as_str()).exists())
+            .collect::<Vec<_>>();
+        versions.sort_by_key(|x| std::cmp::Reverse(x.fetched_at));
+        versions
+    }
+
+    pub fn blob_path(&self, entry: &CacheEntry) -> PathBuf {
+        self.blob(entry.hash.as_str())
+    }
+
+    pub fn list(&self) {
+        let index = self.load();
+        crate::output::emit(&index.entries, || {
+            let mut table = Table::new();
+            table.add_row(row![bFb->"ID", bFb->"Size", bFb->"Hash", bFb->"ETag", bFb->"Fetched At", bFb->"Last Used"]);
+            for i in &index.entries {
+                table.add_row(row![i.student_id, format!("{} KiB", i.size.div_ceil(1024)), &i.hash[..12.min(i.hash.len())],
+                    i.etag.as_deref().unwrap_or("N/A"), i.fetched_at, i.last_used]);
+            }
+            table.printstd();
+        });
+        let total: u64 = index.entries.iter().map(|x| x.size).sum();
+        info!("{} tarball(s), {} of {} MiB used", index.entries.len(), total / 1024 / 1024, self.max_size / 1024 / 1024);
+    }
+
+    pub fn prune(&self, all: bool, id: Option<String>) {
+        let mut index = self.load();
+        let before = index.entries.len();
+        if all {
+            index.entries.clear();
+        } else if let Some(id) = id {
+            index.entries.retain(|x| x.student_id != id);
+        } else {
+            self.evict(&mut index, None);
+        }
+        info!("{} tarball(s) removed", before - index.entries.len());
+        self.save(&index);
+        self.collect_garbage(&index);
+    }
+}
@@ -4,6 +4,8 @@ use log::*;
 use reqwest::Url;
 use rocksdb::{DB, Options};
 
+use crate::credential::{Authenticate, redact};
+use crate::http::Dispatch;
 use crate::settings::Status;
 
 pub fn handle_clean(workdir: &Path, db: &DB, keep_image: bool) -> bool {
@@ -35,11 +37,17 @@ pub fn handle_clean(workdir: &Path, db: &DB, keep_image: bool) -> bool {
         }
     }
 
-    let uuid;
-    if let Ok(Some(_uuid)) = db.get("uuid") {
-        uuid = String::from_utf8_lossy(_uuid.as_slice()).to_string();
-        info!("clearing uuid: {}", uuid);
-    } else { return false; }
+    let identity;
+    match crate::credential::try_identity(db) {
+        Ok(_identity) => {
+            identity = _identity;
+            info!("clearing uuid: {}", redact(identity.token()));
+        }
+        Err(e) => {
+            error!("{}", e);
+            return false;
+        }
+    }
 
     let server;
     if let Ok(Some(_server)) = db.get("server") {
@@ -57,10 +65,10 @@ pub fn handle_clean(workdir: &Path, db: &DB, keep_image: bool) -> bool {
         }
     }
 
-    match reqwest::blocking::Client::new()
+    match crate::http::client()
         .delete(url)
-        .bearer_auth(uuid.as_str())
-        .send()
+        .authenticate(&identity)
+        .dispatch()
         .map_err(|x| x.to_string())
         .and_then(|x| {
             if !x.status().is_success() {
@@ -68,7 +76,8 @@ pub fn handle_clean(workdir: &Path, db: &DB, keep_image: bool) -> bool {
             } else { Ok(()) }
         }) {
         Ok(_) => {
-            info!("successfully revoked {}", uuid);
+            info!("successfully revoked {}", redact(identity.token()));
+            crate::credential::forget(db);
         }
         Err(error) => {
             error!("{}", error);
@@ -2,6 +2,54 @@ use std::path::PathBuf;
 
 use structopt::*;
 
+use crate::credential::{Secret, Store};
+use crate::output::Format;
+use crate::settings::AdjustmentKind;
+
+#[derive(StructOpt, Debug)]
+pub struct RemoteQuery {
+    #[structopt(long, help = "Only list graded students", conflicts_with = "ungraded")]
+    pub graded: bool,
+    #[structopt(long, help = "Only list ungraded students")]
+    pub ungraded: bool,
+    #[structopt(long, help = "Only list marked students")]
+    pub marked: bool,
+    #[structopt(long, help = "Only list skipped students")]
+    pub skipped: bool,
+    #[structopt(long, help = "Only list finished students")]
+    pub finished: bool,
+    #[structopt(long, help = "Only list students whose id matches the regular expression", parse(try_from_str = regex::Regex::new))]
+    pub id: Option<regex::Regex>,
+    #[structopt(long, help = "Sort the students by the field", default_value = "id", possible_values = & ["id", "grade"])]
+    pub sort: String,
+    #[structopt(long, help = "Sort in descending order")]
+    pub reverse: bool,
+    #[structopt(long, help = "Only show the given page, starting from 1")]
+    pub page: Option<usize>,
+    #[structopt(long, help = "Number of students per page", default_value = "50")]
+    pub per_page: usize,
+    #[structopt(long, help = "Show the counts and the grade distribution instead of the list")]
+    pub stats: bool,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct HttpOptions {
+    #[structopt(long, help = "A PEM bundle of certificates to trust besides the system ones")]
+    pub ca_bundle: Option<PathBuf>,
+    #[structopt(long, help = "Base64 SHA-256 digest of a public key the server may present, can be repeated")]
+    pub pin: Vec<String>,
+    #[structopt(long, help = "The proxy of every request, e.g. http://proxy.example.edu:3128")]
+    pub proxy: Option<String>,
+    #[structopt(long, help = "Connect timeout in seconds")]
+    pub connect_timeout: Option<u64>,
+    #[structopt(long, help = "Timeout of a whole request in seconds, including the download of its body")]
+    pub timeout: Option<u64>,
+    #[structopt(long, help = "Retries of the requests which can safely be sent again")]
+    pub retries: Option<u32>,
+    #[structopt(long, help = "Delay before the first retry in milliseconds, doubled after each one")]
+    pub backoff: Option<u64>,
+}
+
 #[derive(StructOpt, Debug)]
 pub enum StatusWatch {
     #[structopt(about = "Current project status")]
@@ -15,6 +63,8 @@ pub enum StatusWatch {
     Remote {
         #[structopt(short, long, help = "Show student detail")]
         detail: bool,
+        #[structopt(flatten)]
+        query: RemoteQuery,
     },
     #[structopt(about = "Remote student info")]
     RemoteID {
@@ -41,6 +91,11 @@ pub enum StatusWatch {
         #[structopt(short, long, env = "EDITOR", help = "The editor software", default_value = "nano")]
         editor: String
     },
+    #[structopt(about = "Edit the auto-current pipeline")]
+    EditPipeline {
+        #[structopt(short, long, env = "EDITOR", help = "The editor software", default_value = "nano")]
+        editor: String
+    },
     #[structopt(about = "Edit the build script")]
     EditBuildScript {
         #[structopt(short, long, env = "EDITOR", help = "The editor software", default_value = "nano")]
@@ -55,13 +110,72 @@ pub enum StatusWatch {
         #[structopt(short, long, help = "Shellcheck path", env = "SHELL_CHECK_BIN", default_value = "shellcheck")]
         shellcheck: PathBuf,
     },
+    #[structopt(about = "Students held for review by batch --hold")]
+    Held,
     #[structopt(about = "check local uuid")]
-    Uuid,
+    Uuid {
+        #[structopt(long, help = "Show the whole uuid instead of a redacted one")]
+        reveal: bool,
+    },
     #[structopt(about = "check current server")]
     Server {
         #[structopt(short, long, help = "edit server")]
         change_to: Option<String>,
     },
+    #[structopt(about = "Show or change the HTTP settings of the server")]
+    Http {
+        #[structopt(flatten)]
+        options: HttpOptions,
+        #[structopt(long, help = "Go back to the default settings before applying the options")]
+        reset: bool,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+pub enum CacheCommand {
+    #[structopt(about = "List the cached tarballs")]
+    List,
+    #[structopt(about = "Evict the least recently used tarballs over the size limit")]
+    Prune {
+        #[structopt(long, help = "Remove every cached tarball", conflicts_with = "id")]
+        all: bool,
+        #[structopt(long, help = "Remove the tarball of the given student")]
+        id: Option<String>,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+pub struct GradebookColumns {
+    #[structopt(short, long, help = "Column layout of the gradebook", default_value = "polya",
+    possible_values = & ["polya", "canvas", "moodle", "blackboard"])]
+    pub preset: String,
+    #[structopt(short, long, help = "Name of the assignment column in the LMS presets", default_value = "Tulip")]
+    pub assignment: String,
+    #[structopt(short, long, help = "Rename a column as field=header, an empty header drops it. \
+    The fields are student_id, grade, comment, marked, skipped and finished", parse(try_from_str = crate::gradebook::parse_mapping))]
+    pub map: Vec<(String, String)>,
+}
+
+#[derive(StructOpt, Debug)]
+pub enum GradebookCommand {
+    #[structopt(about = "Write the grades of all students to a gradebook")]
+    Export {
+        #[structopt(short, long, help = "Path of the gradebook, a .csv, .json or .xlsx file")]
+        output: PathBuf,
+        #[structopt(flatten)]
+        columns: GradebookColumns,
+    },
+    #[structopt(about = "Upload the grades and comments of a CSV gradebook")]
+    Import {
+        #[structopt(short, long, help = "Path of the CSV gradebook")]
+        input: PathBuf,
+        #[structopt(flatten)]
+        columns: GradebookColumns,
+        #[structopt(long, help = "Only show the changes")]
+        dry_run: bool,
+        #[structopt(long, help = "Replace the grades of students who are already graded")]
+        r#override: bool,
+    },
 }
 
 #[derive(StructOpt, Debug)]
@@ -91,6 +205,17 @@ pub struct Opt {
     pub command: SubCommand,
     #[structopt(short, long, help = "Path to nutshell binary", env = "NUTSHELL_BIN", default_value = "nutshell")]
     pub nutshell: PathBuf,
+    #[structopt(short, long, help = "Answer yes to every question", conflicts_with = "no")]
+    pub yes: bool,
+    #[structopt(long, help = "Answer no to every question")]
+    pub no: bool,
+    #[structopt(long, help = "A json file of scripted answers keyed by question", env = "TULIP_ANSWERS")]
+    pub answers: Option<PathBuf>,
+    #[structopt(long, help = "Size limit of the tarball cache in MiB", env = "TULIP_CACHE_SIZE", default_value = "2048")]
+    pub cache_size: u64,
+    #[structopt(long, help = "Output format of the status and listing commands", env = "TULIP_FORMAT", default_value = "table",
+    possible_values = & ["table", "json", "yaml", "csv"])]
+    pub format: Format,
 }
 
 #[derive(StructOpt, Debug)]
@@ -99,8 +224,16 @@ pub enum SubCommand {
     Register {
         #[structopt(short, long, help = "The server address", env = "TULIP_SERVER")]
         server: String,
-        #[structopt(short, long, help = "Ssh username", env = "TULIP_TOKEN")]
-        token: String,
+        #[structopt(short, long, help = "The registration token, asked for if neither it nor --token-file is given",
+        env = "TULIP_TOKEN", hide_env_values = true)]
+        token: Option<Secret>,
+        #[structopt(long, help = "Read the registration token from the file", conflicts_with = "token")]
+        token_file: Option<PathBuf>,
+        #[structopt(long, help = "Where to keep the bearer credential, encrypted with a passphrase by default",
+        default_value = "encrypted", possible_values = & ["plain", "encrypted", "keyring"])]
+        store: Store,
+        #[structopt(flatten)]
+        http: HttpOptions,
         #[structopt(long, help = "Force to register a new uuid")]
         force: bool,
     },
@@ -108,7 +241,7 @@ pub enum SubCommand {
     PullImage {
         #[structopt(long, help = "Force to renew the current image")]
         force: bool,
-        #[structopt(short, long, help = "backend downloader", default_value = "wget", possible_values = & ["wget", "aria2c"])]
+        #[structopt(short, long, help = "backend downloader", default_value = "wget", possible_values = & ["wget", "aria2c", "builtin"])]
         backend: String,
         #[structopt(long, help = "Use this if you have already untar an image on your own")]
         local_set: bool,
@@ -143,13 +276,100 @@ pub enum SubCommand {
     },
     #[structopt(about = "Delete the current overlay system")]
     DestroyOverlay,
+    #[structopt(about = "Check the dependencies and the environment")]
+    Doctor {
+        #[structopt(short, long, help = "backend downloader", default_value = "wget", possible_values = & ["wget", "aria2c", "builtin"])]
+        backend: String,
+        #[structopt(short, long, help = "Shellcheck path", env = "SHELL_CHECK_BIN", default_value = "shellcheck")]
+        shellcheck: PathBuf,
+        #[structopt(short, long, env = "EDITOR", help = "The editor software", default_value = "nano")]
+        editor: String,
+        #[structopt(short, long, help = "Reader path", env = "TULIP_REPORT_READER", default_value = "xdg-open")]
+        reader: PathBuf,
+        #[structopt(short, long, env = "TULIP_MOUNT_DIR", help = "The diretory to mount the root", default_value = "/mnt")]
+        mount_dir: PathBuf,
+    },
+    #[structopt(about = "Summarise the files of the current project")]
+    Inspect {
+        #[structopt(short, long, help = "Depth of the file tree", default_value = "2")]
+        depth: usize,
+        #[structopt(short, long, help = "Files larger than this size in KiB are reported", default_value = "1024")]
+        large: u64,
+    },
+    #[structopt(about = "Compare the current project with previously fetched ones")]
+    Similarity {
+        #[structopt(short, long, help = "Number of the most similar submissions to list", default_value = "5")]
+        top: usize,
+        #[structopt(long, help = "Similarity from 0 to 1 at which a pair is reported", default_value = "0.5")]
+        threshold: f64,
+        #[structopt(short, long, help = "Show the matching regions with the given student instead of the most similar one")]
+        show: Option<String>,
+        #[structopt(short, long, help = "Mark the current project if a pair reaches the threshold")]
+        mark: bool,
+    },
+    #[structopt(about = "Compare the current project with its previous version, another student or the starter template")]
+    Diff {
+        #[structopt(short, long, help = "Compare with the given student instead of the previous version", conflicts_with = "template")]
+        student: Option<String>,
+        #[structopt(short, long, help = "Compare with the starter template, a directory or a tarball", env = "TULIP_TEMPLATE")]
+        template: Option<PathBuf>,
+        #[structopt(long, help = "Only print the changed files")]
+        summary: bool,
+        #[structopt(short, long, help = "Append the file summary to the comment")]
+        attach: bool,
+        #[structopt(short, long, help = "backend downloader", default_value = "wget", possible_values = & ["wget", "aria2c", "builtin"])]
+        backend: String,
+    },
+    #[structopt(about = "Manage the local cache of student tarballs")]
+    Cache {
+        #[structopt(subcommand)]
+        command: CacheCommand
+    },
+    #[structopt(about = "Open the full-screen dashboard of the grading session")]
+    Tui,
+    #[structopt(about = "Export or import the grades in an LMS gradebook")]
+    Gradebook {
+        #[structopt(subcommand)]
+        command: GradebookCommand
+    },
+    #[structopt(about = "Move the bearer credential to another store or change its passphrase")]
+    Protect {
+        #[structopt(long, help = "Where to keep the bearer credential", default_value = "encrypted",
+        possible_values = & ["plain", "encrypted", "keyring"])]
+        store: Store,
+    },
+    #[structopt(about = "Replace the key pair signing the requests, or start signing them on an upgraded server")]
+    Rekey,
+    #[structopt(about = "Check the recorded status against the mounts and processes, and repair it")]
+    Recover {
+        #[structopt(long, help = "Only report the inconsistencies")]
+        dry_run: bool,
+    },
     #[structopt(about = "Give a grade to the student")]
     Grade {
-        #[structopt(short, long, help = "The score")]
-        score: usize,
+        #[structopt(short, long, help = "The score, use the rubric if not given")]
+        score: Option<f64>,
+        #[structopt(long, help = "Accept the suggested score computed by the last test run", conflicts_with = "score")]
+        accept_suggested: bool,
+        #[structopt(long, help = "Grade a rubric criterion as name=score", conflicts_with_all = & ["score", "accept-suggested"],
+        parse(try_from_str = crate::rubric::parse_criterion))]
+        criterion: Vec<(String, f64)>,
         #[structopt(long, help = "Allow override existing score")]
         r#override: bool,
     },
+    #[structopt(about = "Add or remove a bonus or penalty on the grade")]
+    Adjust {
+        #[structopt(short, long, help = "Kind of the adjustment", required_unless = "remove",
+        possible_values = & ["bonus", "late-penalty", "academic-integrity", "other"])]
+        kind: Option<AdjustmentKind>,
+        #[structopt(short, long, help = "Points of the adjustment, the sign is implied by the kind except for other",
+        required_unless = "remove", allow_hyphen_values = true)]
+        points: Option<f64>,
+        #[structopt(short, long, help = "Reason of the adjustment", required_unless = "remove")]
+        reason: Option<String>,
+        #[structopt(long, help = "Remove the adjustment with the given number", conflicts_with_all = & ["kind", "points", "reason"])]
+        remove: Option<usize>,
+    },
     #[structopt(about = "Open the comment editor")]
     Comment {
         #[structopt(short, long, env = "EDITOR", help = "The editor software", default_value = "nano")]
@@ -157,7 +377,7 @@ pub enum SubCommand {
     },
     #[structopt(about = "Fetch student project")]
     Fetch {
-        #[structopt(short, long, help = "backend downloader", default_value = "wget", possible_values = & ["wget", "aria2c"])]
+        #[structopt(short, long, help = "backend downloader", default_value = "wget", possible_values = & ["wget", "aria2c", "builtin"])]
         backend: String,
         #[structopt(short, long, help = "Do not request next task, only sync current project")]
         download_only: bool,
@@ -166,7 +386,7 @@ pub enum SubCommand {
     },
     #[structopt(about = "Pull the target student project")]
     Pull {
-        #[structopt(short, long, help = "backend downloader", default_value = "wget", possible_values = & ["wget", "aria2c"])]
+        #[structopt(short, long, help = "backend downloader", default_value = "wget", possible_values = & ["wget", "aria2c", "builtin"])]
         backend: String,
         #[structopt(short, long, help = "Student ID")]
         id: String,
@@ -185,6 +405,29 @@ pub enum SubCommand {
         editor: String,
         #[structopt(short, long, help = "Reader path", env = "TULIP_REPORT_READER", default_value = "xdg-open")]
         reader: PathBuf,
+        #[structopt(long, help = "Continue from the last finished step of the current project")]
+        resume: bool,
+    },
+    #[structopt(about = "Grade students unattended until the count is reached or no student is left")]
+    Batch {
+        #[structopt(short, long, help = "Number of students to grade", required_unless = "until-empty")]
+        count: Option<usize>,
+        #[structopt(long, help = "Keep grading until the server has no student left", conflicts_with = "count")]
+        until_empty: bool,
+        #[structopt(short, long, help = "backend downloader", default_value = "wget", possible_values = & ["wget", "aria2c", "builtin"])]
+        backend: String,
+        #[structopt(short, long, help = "Shellcheck path", env = "SHELL_CHECK_BIN", default_value = "shellcheck")]
+        shellcheck: PathBuf,
+        #[structopt(short, long, env = "TULIP_MOUNT_DIR", help = "The diretory to mount the root", default_value = "/mnt")]
+        mount_dir: PathBuf,
+        #[structopt(short, long, help = "When specified, a new tmpfs with the given size will mount in the root")]
+        tmp_size: Option<usize>,
+        #[structopt(long, help = "Skip flagged students for human review instead of submitting, they are marked again when pulled")]
+        hold: bool,
+        #[structopt(long, help = "Flag students whose suggested grade is below this value")]
+        review_below: Option<f64>,
+        #[structopt(short, long, help = "Path of the summary report, default to a timestamped file in the tulip dir")]
+        report: Option<PathBuf>,
     },
     #[structopt(about = "Build the current project")]
     Build {
@@ -198,6 +441,14 @@ pub enum SubCommand {
         without_build: bool
     },
 
+    #[structopt(about = "Run the configured test cases")]
+    Test {
+        #[structopt(long, help = "Force to run without build")]
+        without_build: bool,
+        #[structopt(short, long, help = "Only run the test case with the given name")]
+        case: Option<String>,
+    },
+
     #[structopt(about = "Edit current global settings")]
     Submit {
         #[structopt(long, help = "Allow overriding existing submission")]
@@ -0,0 +1,294 @@
+use std::io::Write;
+use std::path::Path;
+
+use log::*;
+use regex::Regex;
+
+use crate::run::build_firejail;
+use crate::settings::{Comparator, Config, TestCase};
+
+#[derive(Debug)]
+pub struct Outcome {
+    pub passed: bool,
+    pub score: f64,
+    pub message: Option<String>,
+}
+
+impl Outcome {
+    fn accepted() -> Self {
+        Outcome { passed: true, score: 1.0, message: None }
+    }
+
+    fn rejected<S: Into<String>>(message: S) -> Self {
+        Outcome { passed: false, score: 0.0, message: Some(message.into()) }
+    }
+}
+
+/// Compare the actual output against the expected one, an error means the comparator itself failed.
+pub fn compare(config: &Config, mount_point: &Path, case: &TestCase, expected: &str, actual: &str) -> Result<Outcome, String> {
+    let outcome = match &case.comparator {
+        Comparator::Exact => {
+            if expected == actual { Outcome::accepted() } else { first_difference(expected, actual) }
+        }
+        Comparator::TrailingWhitespace => {
+            let expected = trim_lines(expected);
+            let actual = trim_lines(actual);
+            if expected == actual {
+                Outcome::accepted()
+            } else {
+                first_difference(expected.join("\n").as_str(), actual.join("\n").as_str())
+            }
+        }
+        Comparator::Token => tokens(expected, actual, |x, y| x == y),
+        Comparator::CaseInsensitive => {
+            if expected.to_lowercase() == actual.to_lowercase() {
+                Outcome::accepted()
+            } else {
+                first_difference(expected.to_lowercase().as_str(), actual.to_lowercase().as_str())
+            }
+        }
+        Comparator::Float { absolute, relative } => tokens(expected, actual, |x, y| {
+            match (x.parse::<f64>(), y.parse::<f64>()) {
+                (Ok(x), Ok(y)) => {
+                    let diff = (x - y).abs();
+                    diff <= *absolute || diff <= relative * x.abs()
+                }
+                _ => x == y
+            }
+        }),
+        Comparator::Regex => {
+            let expected = trim_lines(expected);
+            let actual = trim_lines(actual);
+            if expected.len() != actual.len() {
+                return Ok(Outcome::rejected(format!("expected {} lines, found {}", expected.len(), actual.len())));
+            }
+            for (line, (pattern, content)) in expected.iter().zip(actual.iter()).enumerate() {
+                let regex = Regex::new(format!("^(?:{})$", pattern).as_str())
+                    .map_err(|x| format!("invalid pattern at line {}: {}", line + 1, x))?;
+                if !regex.is_match(content) {
+                    return Ok(Outcome::rejected(format!("line {} does not match /{}/", line + 1, pattern)));
+                }
+            }
+            Outcome::accepted()
+        }
+        Comparator::UnorderedLines => {
+            let mut expected = trim_lines(expected);
+            let mut actual = trim_lines(actual);
+            expected.sort_unstable();
+            actual.sort_unstable();
+            if expected == actual {
+                Outcome::accepted()
+            } else {
+                Outcome::rejected("lines differ regardless of order")
+            }
+        }
+        Comparator::Checker { path, shell } => {
+            checker(config, mount_point, case, path, shell.as_deref(), actual)?
+        }
+    };
+    Ok(outcome)
+}
+
+fn trim_lines(content: &str) -> Vec<&str> {
+    let mut lines: Vec<&str> = content.lines().map(|x| x.trim_end()).collect();
+    while let Some(&"") = lines.last() {
+        lines.pop();
+    }
+    lines
+}
+
+fn first_difference(expected: &str, actual: &str) -> Outcome {
+    let mut expected_lines = expected.lines();
+    let mut actual_lines = actual.lines();
+    let mut line = 1;
+    loop {
+        match (expected_lines.next(), actual_lines.next()) {
+            (Some(x), Some(y)) if x == y => line += 1,
+            (Some(x), Some(y)) => return Outcome::rejected(format!("line {}: expected {:?}, found {:?}", line, x, y)),
+            (Some(x), None) => return Outcome::rejected(format!("line {}: expected {:?}, found EOF", line, x)),
+            (None, Some(y)) => return Outcome::rejected(format!("line {}: expected EOF, found {:?}", line, y)),
+            (None, None) => return Outcome::rejected("outputs differ in line endings"),
+        }
+    }
+}
+
+fn tokens<F: Fn(&str, &str) -> bool>(expected: &str, actual: &str, equal: F) -> Outcome {
+    let expected: Vec<&str> = expected.split_whitespace().collect();
+    let actual: Vec<&str> = actual.split_whitespace().collect();
+    for (index, (x, y)) in expected.iter().zip(actual.iter()).enumerate() {
+        if !equal(x, y) {
+            return Outcome::rejected(format!("token {}: expected {:?}, found {:?}", index + 1, x, y));
+        }
+    }
+    if expected.len() != actual.len() {
+        return Outcome::rejected(format!("expected {} tokens, found {}", expected.len(), actual.len()));
+    }
+    Outcome::accepted()
+}
+
+/// Run a teacher provided checker inside firejail as `checker <input> <expected> <actual>`.
+/// The first line of its stdout is the verdict: `AC`, `WA` or `PC <score>` with a score in `[0, 1]`.
+/// Everything after the first line is kept as the message.
+fn checker(config: &Config, mount_point: &Path, case: &TestCase, path: &Path, shell: Option<&str>, actual: &str)
+           -> Result<Outcome, String> {
+    let mut file = tempfile::Builder::new()
+        .prefix(".tulip-actual-")
+        .tempfile_in(mount_point.join("data"))
+        .map_err(|x| x.to_string())?;
+    file.write_all(actual.as_bytes()).map_err(|x| x.to_string())?;
+    file.flush().map_err(|x| x.to_string())?;
+    let actual_path = Path::new("/data").join(file.path().file_name().unwrap());
+    let input_path = case.input.as_ref()
+        .map(|x| Path::new("/").join(x))
+        .unwrap_or_else(|| "/dev/null".into());
+
+    debug!("running checker {} for {}", path.display(), case.name);
+    let output = build_firejail(mount_point, config, true)
+        .arg(shell.unwrap_or("/bin/sh"))
+        .arg(Path::new("/").join(path))
+        .arg(input_path)
+        .arg(Path::new("/").join(case.output.as_path()))
+        .arg(actual_path)
+        .output()
+        .map_err(|x| x.to_string())?;
+
+    if !output.status.success() {
+        return Err(format!("checker failed with {}: {}", output.status,
+                           String::from_utf8_lossy(output.stderr.as_slice()).trim()));
+    }
+
+    parse_verdict(String::from_utf8_lossy(output.stdout.as_slice()).as_ref())
+}
+
+fn parse_verdict(stdout: &str) -> Result<Outcome, String> {
+    let mut lines = stdout.splitn(2, '\n');
+    let verdict = lines.next().unwrap_or("").trim();
+    let message = lines.next().map(|x| x.trim().to_string()).filter(|x| !x.is_empty());
+    let mut words = verdict.split_whitespace();
+    match (words.next(), words.next()) {
+        (Some("AC"), None) => Ok(Outcome { passed: true, score: 1.0, message }),
+        (Some("WA"), None) => Ok(Outcome { passed: false, score: 0.0, message }),
+        (Some("PC"), Some(score)) => {
+            let score = score.parse::<f64>().map_err(|x| format!("invalid checker score {}: {}", score, x))?;
+            if !(0.0..=1.0).contains(&score) {
+                return Err(format!("checker score {} out of range", score));
+            }
+            Ok(Outcome { passed: score >= 1.0, score, message })
+        }
+        _ => Err(format!("invalid checker verdict: {}", verdict))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(comparator: Comparator, expected: &str, actual: &str) -> Outcome {
+        let case = TestCase { name: String::from("case"), comparator, ..TestCase::default() };
+        compare(&Config::default(), Path::new("/nonexistent"), &case, expected, actual).unwrap()
+    }
+
+    #[test]
+    fn exact_reports_the_first_different_line() {
+        assert!(run(Comparator::Exact, "1\n2\n", "1\n2\n").passed);
+        let outcome = run(Comparator::Exact, "1\n2\n", "1\n3\n");
+        assert!(!outcome.passed);
+        assert_eq!(outcome.message.unwrap(), "line 2: expected \"2\", found \"3\"");
+        assert_eq!(run(Comparator::Exact, "1\n", "1\n2\n").message.unwrap(), "line 2: expected EOF, found \"2\"");
+        assert_eq!(run(Comparator::Exact, "1\n", "1\r\n").message.unwrap(), "outputs differ in line endings");
+    }
+
+    #[test]
+    fn trailing_whitespace_ignores_line_ends_and_final_blank_lines() {
+        assert!(run(Comparator::TrailingWhitespace, "a b\nc\n", "a b  \nc\t\n\n\n").passed);
+        assert!(run(Comparator::TrailingWhitespace, "a b\n", "a b\r\n").passed);
+        assert!(!run(Comparator::TrailingWhitespace, "a b\n", " a b\n").passed);
+        assert!(!run(Comparator::TrailingWhitespace, "a\n\nb\n", "a\nb\n").passed);
+    }
+
+    #[test]
+    fn token_ignores_the_layout() {
+        assert!(run(Comparator::Token, "1 2\n3\n", "1\n2   3").passed);
+        assert_eq!(run(Comparator::Token, "1 2 3", "1 2 4").message.unwrap(), "token 3: expected \"3\", found \"4\"");
+        assert_eq!(run(Comparator::Token, "1 2 3", "1 2").message.unwrap(), "expected 3 tokens, found 2");
+    }
+
+    #[test]
+    fn case_insensitive_only_ignores_the_case() {
+        assert!(run(Comparator::CaseInsensitive, "Yes\nNO\n", "yes\nno\n").passed);
+        assert!(!run(Comparator::CaseInsensitive, "yes\n", "yes \n").passed);
+    }
+
+    #[test]
+    fn float_accepts_either_tolerance() {
+        let float = |absolute, relative| Comparator::Float { absolute, relative };
+        assert!(run(float(1e-6, 0.0), "3.141592", "3.1415925").passed);
+        assert!(!run(float(1e-6, 0.0), "3.141592", "3.1416").passed);
+        assert!(run(float(0.0, 1e-3), "1000000", "1000500").passed);
+        assert!(!run(float(0.0, 1e-3), "1000000", "1001500").passed);
+        assert!(run(float(0.0, 0.0), "2.0", "2").passed);
+        assert!(!run(float(0.5, 0.5), "nan", "1").passed);
+    }
+
+    #[test]
+    fn float_compares_words_exactly() {
+        let float = Comparator::Float { absolute: 0.1, relative: 0.0 };
+        assert!(run(float.clone(), "answer 1.0", "answer 1.05").passed);
+        assert!(!run(float.clone(), "answer 1.0", "Answer 1.0").passed);
+        assert!(!run(float, "1.0 2.0", "1.0").passed);
+    }
+
+    #[test]
+    fn regex_anchors_every_line() {
+        assert!(run(Comparator::Regex, "\\d+\ntime: .*ms\n", "42\ntime: 12ms  \n").passed);
+        assert!(!run(Comparator::Regex, "\\d+\n", "x42\n").passed);
+        assert!(!run(Comparator::Regex, "\\d+\n", "42x\n").passed);
+        // an alternation stays inside the anchors
+        assert!(!run(Comparator::Regex, "a|b\n", "ab\n").passed);
+        assert_eq!(run(Comparator::Regex, "a\nb\n", "a\n").message.unwrap(), "expected 2 lines, found 1");
+    }
+
+    #[test]
+    fn regex_reports_an_invalid_pattern() {
+        let case = TestCase { comparator: Comparator::Regex, ..TestCase::default() };
+        let result = compare(&Config::default(), Path::new("/nonexistent"), &case, "(\n", "(\n");
+        assert!(result.unwrap_err().starts_with("invalid pattern at line 1"));
+    }
+
+    #[test]
+    fn unordered_lines_keeps_duplicates() {
+        assert!(run(Comparator::UnorderedLines, "b\na\nc\n", "a\nc\nb\n").passed);
+        assert!(run(Comparator::UnorderedLines, "b\na\n", "a \nb\n\n").passed);
+        assert!(!run(Comparator::UnorderedLines, "a\na\nb\n", "a\nb\nb\n").passed);
+        assert!(!run(Comparator::UnorderedLines, "a\nb\n", "a\nb\nb\n").passed);
+    }
+
+    #[test]
+    fn parses_the_checker_verdicts() {
+        let accepted = parse_verdict("AC\n").unwrap();
+        assert!(accepted.passed);
+        assert_eq!(accepted.score, 1.0);
+        assert!(accepted.message.is_none());
+
+        let wrong = parse_verdict("WA\nline 3 is wrong\n").unwrap();
+        assert!(!wrong.passed);
+        assert_eq!(wrong.score, 0.0);
+        assert_eq!(wrong.message.unwrap(), "line 3 is wrong");
+
+        let partial = parse_verdict("PC 0.25\n").unwrap();
+        assert!(!partial.passed);
+        assert_eq!(partial.score, 0.25);
+        assert!(parse_verdict("PC 1").unwrap().passed);
+    }
+
+    #[test]
+    fn rejects_an_invalid_checker_verdict() {
+        assert!(parse_verdict("").is_err());
+        assert!(parse_verdict("OK\n").is_err());
+        assert!(parse_verdict("AC 1\n").is_err());
+        assert!(parse_verdict("PC\n").is_err());
+        assert!(parse_verdict("PC half\n").is_err());
+        assert!(parse_verdict("PC 1.5\n").is_err());
+        assert!(parse_verdict("PC -0.1\n").is_err());
+    }
+}
@@ -0,0 +1,510 @@
+use std::fmt::{Debug, Formatter};
+use std::fs::File;
+use std::io::{PipeReader, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+
+use log::*;
+use reqwest::blocking::RequestBuilder;
+use reqwest::header::AUTHORIZATION;
+use reqwest::Url;
+use ring::aead::{Aad, CHACHA20_POLY1305, LessSafeKey, Nonce, NONCE_LEN, UnboundKey};
+use ring::digest::{digest, SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use rocksdb::DB;
+use serde::*;
+
+use crate::LogUnwrap;
+
+const KEY: &str = "credential";
+// registrations before the credential store keep the bearer token here in plain text
+const LEGACY_KEY: &str = "uuid";
+const SERVICE: &str = "tulip";
+const PASSPHRASE_VARIABLE: &str = "TULIP_PASSPHRASE";
+const PASSPHRASE_FD_VARIABLE: &str = "TULIP_PASSPHRASE_FD";
+
+/// A token or passphrase, never shown by `Debug`, so `tulip invoked with ...` does not leak it.
+#[derive(Clone)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+impl Debug for Secret {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<redacted>")
+    }
+}
+
+impl From<String> for Secret {
+    fn from(x: String) -> Self {
+        Secret(x)
+    }
+}
+
+impl FromStr for Secret {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Secret(s.to_string()))
+    }
+}
+
+/// Keep only the first characters of a token for logs.
+pub fn redact(token: &str) -> String {
+    format!("{}****", token.chars().take(4).collect::<String>())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Store {
+    Plain,
+    Encrypted,
+    Keyring,
+}
+
+impl FromStr for Store {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plain" => Ok(Store::Plain),
+            "encrypted" => Ok(Store::Encrypted),
+            "keyring" => Ok(Store::Keyring),
+            _ => Err(format!("unknown credential store {}", s))
+        }
+    }
+}
+
+/// What a store keeps: the token identifying this client and, for servers taking signed requests, its key.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Identity {
+    token: String,
+    // pkcs8 document of the ed25519 key pair, in hex
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+}
+
+impl Identity {
+    /// Encrypted and keyring credentials saved before signed requests hold the bare token.
+    fn parse(text: String) -> Identity {
+        serde_json::from_str(text.as_str()).unwrap_or(Identity { token: text, key: None })
+    }
+
+    /// The token of this client, sent as a bearer token or as the client of a signature.
+    pub fn token(&self) -> &str {
+        self.token.as_str()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "store", rename_all = "snake_case")]
+enum Stored {
+    Plain {
+        token: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key: Option<String>,
+    },
+    // chacha20-poly1305 with a key derived from the passphrase by argon2
+    Encrypted { salt: String, nonce: String, ciphertext: String },
+    // kept by the Secret Service under the server it was registered with
+    Keyring { server: String },
+}
+
+static IDENTITY: OnceLock<Identity> = OnceLock::new();
+static PASSPHRASE: Mutex<Option<Secret>> = Mutex::new(None);
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+fn unhex(text: &str) -> Result<Vec<u8>, String> {
+    if !text.len().is_multiple_of(2) {
+        return Err(String::from("invalid hex string"));
+    }
+    (0..text.len()).step_by(2)
+        .map(|x| u8::from_str_radix(&text[x..x + 2], 16).map_err(|x| x.to_string()))
+        .collect()
+}
+
+/// Take the passphrase out of the environment before anything is spawned, sandboxes must never see it.
+/// Tulip children get it from the pipe given by `TULIP_PASSPHRASE_FD`.
+pub fn init() {
+    if let Some(passphrase) = std::env::var_os(PASSPHRASE_VARIABLE) {
+        std::env::remove_var(PASSPHRASE_VARIABLE);
+        *PASSPHRASE.lock().unwrap() = Some(Secret(passphrase.to_string_lossy().to_string()));
+    }
+    if let Some(fd) = std::env::var_os(PASSPHRASE_FD_VARIABLE) {
+        std::env::remove_var(PASSPHRASE_FD_VARIABLE);
+        let fd = match fd.to_string_lossy().parse::<RawFd>() {
+            Ok(fd) if unsafe { libc::fcntl(fd, libc::F_GETFD) } != -1 => fd,
+            _ => {
+                warn!("ignoring invalid {}", PASSPHRASE_FD_VARIABLE);
+                return;
+            }
+        };
+        // closed when dropped
+        let mut pipe = unsafe { File::from_raw_fd(fd) };
+        let mut passphrase = String::new();
+        match pipe.read_to_string(&mut passphrase) {
+            Ok(_) => *PASSPHRASE.lock().unwrap() = Some(Secret(passphrase)),
+            Err(e) => warn!("unable to read the passphrase: {}", e)
+        }
+    }
+}
+
+/// Hand the passphrase, if it is known, to a tulip child through a pipe it inherits.
+/// The returned end must be kept until the child is spawned.
+pub fn share_passphrase(command: &mut Command) -> Option<PipeReader> {
+    let passphrase = PASSPHRASE.lock().unwrap().clone()?;
+    let (reader, mut writer) = std::io::pipe().exit_on_failure();
+    // far below the capacity of a pipe, so it does not block without a reader
+    writer.write_all(passphrase.expose().as_bytes()).exit_on_failure();
+    drop(writer);
+    let fd = reader.as_raw_fd();
+    unsafe {
+        command.pre_exec(move || {
+            // pipes are opened close-on-exec
+            if libc::fcntl(fd, libc::F_SETFD, 0) == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    command.env(PASSPHRASE_FD_VARIABLE, fd.to_string());
+    Some(reader)
+}
+
+fn passphrase(confirm: bool) -> Secret {
+    if let Some(passphrase) = PASSPHRASE.lock().unwrap().clone() {
+        return passphrase;
+    }
+    let passphrase = crate::prompt::secret("Passphrase of the credential:");
+    if confirm && crate::prompt::secret("Repeat the passphrase:").expose() != passphrase.expose() {
+        error!("the passphrases do not match");
+        std::process::exit(1);
+    }
+    // kept in memory for the rest of the process and for tulip children
+    *PASSPHRASE.lock().unwrap() = Some(passphrase.clone());
+    passphrase
+}
+
+fn derive(passphrase: &Secret, salt: &[u8]) -> Result<LessSafeKey, String> {
+    let key = argon2::hash_raw(passphrase.expose().as_bytes(), salt, &argon2::Config::default())
+        .map_err(|x| x.to_string())?;
+    UnboundKey::new(&CHACHA20_POLY1305, key.as_slice())
+        .map(LessSafeKey::new)
+        .map_err(|_| String::from("unable to derive the credential key"))
+}
+
+fn encrypt(text: &str, passphrase: &Secret) -> Result<Stored, String> {
+    let random = SystemRandom::new();
+    let mut salt = [0u8; 16];
+    let mut nonce = [0u8; NONCE_LEN];
+    random.fill(&mut salt).and_then(|_| random.fill(&mut nonce))
+        .map_err(|_| String::from("unable to generate random bytes"))?;
+    let key = derive(passphrase, &salt)?;
+    let mut data = text.as_bytes().to_vec();
+    key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(KEY.as_bytes()), &mut data)
+        .map_err(|_| String::from("unable to encrypt the credential"))?;
+    Ok(Stored::Encrypted { salt: hex(&salt), nonce: hex(&nonce), ciphertext: hex(data.as_slice()) })
+}
+
+fn decrypt(salt: &str, nonce: &str, ciphertext: &str, passphrase: &Secret) -> Result<String, String> {
+    let key = derive(passphrase, unhex(salt)?.as_slice())?;
+    let nonce = Nonce::try_assume_unique_for_key(unhex(nonce)?.as_slice())
+        .map_err(|_| String::from("invalid nonce of the credential"))?;
+    let mut data = unhex(ciphertext)?;
+    let token = key.open_in_place(nonce, Aad::from(KEY.as_bytes()), data.as_mut_slice())
+        .map_err(|_| String::from("wrong passphrase or corrupted credential"))?;
+    String::from_utf8(token.to_vec()).map_err(|x| x.to_string())
+}
+
+fn secret_tool(args: &[&str], input: Option<&str>) -> Result<String, String> {
+    let mut child = Command::new("secret-tool")
+        .args(args)
+        .stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|x| format!("unable to run secret-tool: {}", x))?;
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        stdin.write_all(input.as_bytes()).map_err(|x| x.to_string())?;
+    }
+    let output = child.wait_with_output().map_err(|x| x.to_string())?;
+    if !output.status.success() {
+        return Err(format!("secret-tool {} failed with {}", args[0], output.status));
+    }
+    Ok(String::from_utf8_lossy(output.stdout.as_slice()).trim().to_string())
+}
+
+fn load(db: &DB) -> Result<Stored, String> {
+    if let Some(mut stored) = db.get(KEY).map_err(|x| x.to_string())? {
+        return simd_json::serde::from_slice::<Stored>(stored.as_mut_slice()).map_err(|x| x.to_string());
+    }
+    match db.get(LEGACY_KEY).map_err(|x| x.to_string())? {
+        Some(token) => Ok(Stored::Plain { token: String::from_utf8_lossy(token.as_slice()).to_string(), key: None }),
+        None => Err(String::from("not registered"))
+    }
+}
+
+pub fn registered(db: &DB) -> bool {
+    matches!(db.get(KEY), Ok(Some(_))) || matches!(db.get(LEGACY_KEY), Ok(Some(_)))
+}
+
+fn store_of(stored: &Stored) -> Store {
+    match stored {
+        Stored::Plain { .. } => Store::Plain,
+        Stored::Encrypted { .. } => Store::Encrypted,
+        Stored::Keyring { .. } => Store::Keyring,
+    }
+}
+
+/// The identity of this client, decrypted at most once per process.
+pub fn try_identity(db: &DB) -> Result<Identity, String> {
+    if let Some(identity) = IDENTITY.get() {
+        return Ok(identity.clone());
+    }
+    let identity = match load(db)? {
+        Stored::Plain { token, key } => Identity { token, key },
+        Stored::Encrypted { salt, nonce, ciphertext } =>
+            Identity::parse(decrypt(salt.as_str(), nonce.as_str(), ciphertext.as_str(), &passphrase(false))?),
+        Stored::Keyring { server } => secret_tool(&["lookup", "service", SERVICE, "server", server.as_str()], None)
+            .and_then(|x| if x.is_empty() { Err(String::from("no credential in the keyring")) } else { Ok(x) })
+            .map(Identity::parse)?,
+    };
+    Ok(IDENTITY.get_or_init(|| identity).clone())
+}
+
+pub fn identity(db: &DB) -> Identity {
+    try_identity(db).exit_on_failure()
+}
+
+fn forget_keyring(stored: &Stored) {
+    if let Stored::Keyring { server } = stored {
+        if let Err(e) = secret_tool(&["clear", "service", SERVICE, "server", server.as_str()], None) {
+            warn!("unable to remove the credential from the keyring: {}", e);
+        }
+    }
+}
+
+/// Keep the token, with the pkcs8 key pair if the server takes signed requests.
+pub fn save(db: &DB, token: &str, key: Option<&[u8]>, store: Store, server: &str) {
+    let previous = load(db).ok();
+    let identity = Identity { token: token.to_string(), key: key.map(hex) };
+    let text = serde_json::to_string(&identity).exit_on_failure();
+    let stored = match store {
+        Store::Plain => Stored::Plain { token: identity.token.clone(), key: identity.key.clone() },
+        Store::Encrypted => encrypt(text.as_str(), &passphrase(true)).exit_on_failure(),
+        Store::Keyring => {
+            secret_tool(&["store", "--label", "tulip credential", "service", SERVICE, "server", server], Some(text.as_str()))
+                .exit_on_failure();
+            Stored::Keyring { server: server.to_string() }
+        }
+    };
+    db.put(KEY, serde_json::to_vec(&stored).exit_on_failure()).exit_on_failure();
+    db.delete(LEGACY_KEY).exit_on_failure();
+    if let Some(previous) = previous {
+        // storing again under the same server overwrites the keyring entry
+        if !matches!((&previous, &stored), (Stored::Keyring { server: a }, Stored::Keyring { server: b }) if a == b) {
+            forget_keyring(&previous);
+        }
+    }
+    info!("credential {} stored as {:?}", redact(token), store);
+}
+
+/// Move the credential to another store.
+pub fn protect(db: &DB, store: Store) {
+    let identity = identity(db);
+    // ask for the new passphrase instead of reusing the current one
+    *PASSPHRASE.lock().unwrap() = None;
+    let server = crate::force_get(db, "server");
+    let key = identity.key.as_ref().map(|x| unhex(x.as_str()).exit_on_failure());
+    save(db, identity.token.as_str(), key.as_deref(), store, server.as_str());
+}
+
+/// The store the credential is kept in, for saving it again in place.
+pub fn current_store(db: &DB) -> Store {
+    store_of(&load(db).exit_on_failure())
+}
+
+/// Whether requests are signed rather than carrying the bearer token.
+pub fn signing(db: &DB) -> bool {
+    try_identity(db).map(|x| x.key.is_some()).unwrap_or(false)
+}
+
+/// A new ed25519 key pair as a pkcs8 document, with its public key.
+pub fn generate() -> (Vec<u8>, Vec<u8>) {
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).exit_on_failure();
+    let public = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).exit_on_failure().public_key().as_ref().to_vec();
+    (pkcs8.as_ref().to_vec(), public)
+}
+
+pub fn public_key_hex(public: &[u8]) -> String {
+    hex(public)
+}
+
+fn target(url: &Url) -> String {
+    match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string()
+    }
+}
+
+/// The `Authorization` value of a request. With a key pair, the method, the path, the time and the digest of the
+/// body are signed, so a copied token alone does not authenticate; otherwise the token is sent as it is.
+pub fn authorization(identity: &Identity, method: &str, url: &Url, body: &[u8]) -> String {
+    let key = match &identity.key {
+        Some(key) => key,
+        None => return format!("Bearer {}", identity.token)
+    };
+    let pair = unhex(key.as_str())
+        .and_then(|x| Ed25519KeyPair::from_pkcs8(x.as_slice()).map_err(|x| x.to_string()))
+        .exit_on_failure();
+    let timestamp = chrono::Utc::now().timestamp();
+    let message = format!("{}\n{}\n{}\n{}", method, target(url), timestamp, hex(digest(&SHA256, body).as_ref()));
+    format!("Tulip-Ed25519 client=\"{}\", timestamp=\"{}\", signature=\"{}\"",
+            identity.token, timestamp, hex(pair.sign(message.as_bytes()).as_ref()))
+}
+
+pub trait Authenticate {
+    /// Authenticate the request as this client, after its url and body are set.
+    fn authenticate(self, identity: &Identity) -> Self;
+}
+
+impl Authenticate for RequestBuilder {
+    fn authenticate(self, identity: &Identity) -> Self {
+        let request = match self.try_clone().map(|x| x.build()) {
+            Some(Ok(request)) => request,
+            // a streamed body cannot be signed, the server will refuse it if it expects a signature
+            _ => return self.bearer_auth(identity.token())
+        };
+        let body = request.body().and_then(|x| x.as_bytes()).unwrap_or(&[]);
+        let value = authorization(identity, request.method().as_str(), request.url(), body);
+        self.header(AUTHORIZATION, value)
+    }
+}
+
+/// Remove the credential, including the keyring entry.
+pub fn forget(db: &DB) {
+    if let Ok(stored) = load(db) {
+        forget_keyring(&stored);
+    }
+    if let Err(e) = db.delete(KEY).and_then(|_| db.delete(LEGACY_KEY)) {
+        warn!("unable to remove the credential: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encrypted(text: &str, passphrase: &str) -> (String, String, String) {
+        match encrypt(text, &Secret::from(passphrase.to_string())).unwrap() {
+            Stored::Encrypted { salt, nonce, ciphertext } => (salt, nonce, ciphertext),
+            _ => panic!("not encrypted")
+        }
+    }
+
+    #[test]
+    fn decrypts_what_it_encrypts() {
+        let (salt, nonce, ciphertext) = encrypted("token", "passphrase");
+        assert!(!ciphertext.contains(hex(b"token").as_str()));
+        let text = decrypt(salt.as_str(), nonce.as_str(), ciphertext.as_str(), &Secret::from(String::from("passphrase")));
+        assert_eq!(text.unwrap(), "token");
+    }
+
+    #[test]
+    fn salts_every_encryption() {
+        assert_ne!(encrypted("token", "passphrase"), encrypted("token", "passphrase"));
+    }
+
+    #[test]
+    fn rejects_a_wrong_passphrase() {
+        let (salt, nonce, ciphertext) = encrypted("token", "passphrase");
+        let text = decrypt(salt.as_str(), nonce.as_str(), ciphertext.as_str(), &Secret::from(String::from("Passphrase")));
+        assert_eq!(text.unwrap_err(), "wrong passphrase or corrupted credential");
+    }
+
+    #[test]
+    fn rejects_a_tampered_ciphertext() {
+        let (salt, nonce, ciphertext) = encrypted("token", "passphrase");
+        let passphrase = Secret::from(String::from("passphrase"));
+        let mut tampered = unhex(ciphertext.as_str()).unwrap();
+        tampered[0] ^= 1;
+        assert!(decrypt(salt.as_str(), nonce.as_str(), hex(tampered.as_slice()).as_str(), &passphrase).is_err());
+        let truncated = &ciphertext[..ciphertext.len() - 2];
+        assert!(decrypt(salt.as_str(), nonce.as_str(), truncated, &passphrase).is_err());
+        assert!(decrypt(salt.as_str(), "00", ciphertext.as_str(), &passphrase).is_err());
+    }
+
+    fn signed() -> (Identity, Vec<u8>) {
+        let (pkcs8, public) = generate();
+        (Identity { token: String::from("client"), key: Some(hex(pkcs8.as_slice())) }, public)
+    }
+
+    fn field<'a>(value: &'a str, name: &str) -> &'a str {
+        let start = value.find(format!("{}=\"", name).as_str()).unwrap() + name.len() + 2;
+        let end = value[start..].find('"').unwrap();
+        &value[start..start + end]
+    }
+
+    fn verify(public: &[u8], value: &str, method: &str, target: &str, body: &[u8]) -> bool {
+        let message = format!("{}\n{}\n{}\n{}", method, target, field(value, "timestamp"), hex(digest(&SHA256, body).as_ref()));
+        ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, public)
+            .verify(message.as_bytes(), unhex(field(value, "signature")).unwrap().as_slice())
+            .is_ok()
+    }
+
+    #[test]
+    fn signs_the_method_the_target_and_the_body() {
+        let (identity, public) = signed();
+        let url = "https://example.com/student/2019?detail=1".parse::<Url>().unwrap();
+        let value = authorization(&identity, "POST", &url, b"{\"grade\":10}");
+        assert!(value.starts_with("Tulip-Ed25519 "));
+        assert_eq!(field(value.as_str(), "client"), "client");
+        let now = chrono::Utc::now().timestamp();
+        assert!((now - field(value.as_str(), "timestamp").parse::<i64>().unwrap()).abs() < 60);
+        assert!(verify(public.as_slice(), value.as_str(), "POST", "/student/2019?detail=1", b"{\"grade\":10}"));
+    }
+
+    #[test]
+    fn rejects_a_signature_for_another_request() {
+        let (identity, public) = signed();
+        let url = "https://example.com/student/2019".parse::<Url>().unwrap();
+        let value = authorization(&identity, "POST", &url, b"{\"grade\":10}");
+        assert!(!verify(public.as_slice(), value.as_str(), "POST", "/student/2019", b"{\"grade\":100}"));
+        assert!(!verify(public.as_slice(), value.as_str(), "POST", "/student/2020", b"{\"grade\":10}"));
+        assert!(!verify(public.as_slice(), value.as_str(), "PUT", "/student/2019", b"{\"grade\":10}"));
+        let (_, other) = signed();
+        assert!(!verify(other.as_slice(), value.as_str(), "POST", "/student/2019", b"{\"grade\":10}"));
+    }
+
+    #[test]
+    fn sends_the_bearer_token_without_a_key() {
+        let identity = Identity { token: String::from("client"), key: None };
+        let url = "https://example.com/student".parse::<Url>().unwrap();
+        assert_eq!(authorization(&identity, "GET", &url, b""), "Bearer client");
+    }
+
+    #[test]
+    fn reads_a_bare_token_as_an_identity() {
+        let identity = Identity::parse(String::from("client"));
+        assert_eq!(identity.token(), "client");
+        assert!(identity.key.is_none());
+    }
+
+    #[test]
+    fn redacts_a_secret() {
+        let secret = Secret::from(String::from("hunter2"));
+        assert_eq!(format!("{:?}", secret), "<redacted>");
+        assert_eq!(format!("{:?}", Some(secret.clone())), "Some(<redacted>)");
+        assert_eq!(secret.expose(), "hunter2");
+        assert_eq!(redact("abcdefgh"), "abcd****");
+    }
+}
@@ -0,0 +1,563 @@
+use std::io::{BufRead, Stdout};
+use std::path::Path;
+use std::process::Command;
+use std::time::Duration;
+
+use crossterm::event::{self, Event, KeyCode, KeyEvent};
+use crossterm::execute;
+use crossterm::terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen};
+use log::*;
+use tui::backend::CrosstermBackend;
+use tui::layout::{Constraint, Direction, Layout, Rect};
+use tui::style::{Color, Modifier, Style};
+use tui::Terminal;
+use tui::text::{Span, Spans};
+use tui::widgets::{Block, Borders, List, ListItem, ListState, Paragraph, Tabs, Wrap};
+
+use crate::{force_get, force_get_json, init_db, LogUnwrap};
+use crate::settings::{Config, Status};
+use crate::status::StudentDetail;
+
+type Backend = CrosstermBackend<Stdout>;
+
+#[derive(Copy, Clone, PartialEq)]
+enum Pane {
+    Status,
+    Output,
+    Remote,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Filter {
+    All,
+    Pending,
+    Graded,
+    Marked,
+    Skipped,
+}
+
+impl Filter {
+    fn next(self) -> Self {
+        match self {
+            Filter::All => Filter::Pending,
+            Filter::Pending => Filter::Graded,
+            Filter::Graded => Filter::Marked,
+            Filter::Marked => Filter::Skipped,
+            Filter::Skipped => Filter::All,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Filter::All => "all",
+            Filter::Pending => "pending",
+            Filter::Graded => "graded",
+            Filter::Marked => "marked",
+            Filter::Skipped => "skipped",
+        }
+    }
+
+    fn matches(self, student: &StudentDetail) -> bool {
+        match self {
+            Filter::All => true,
+            Filter::Pending => !student.status.finished && !student.status.skipped,
+            Filter::Graded => student.grades.as_ref().and_then(|x| x.graded).is_some(),
+            Filter::Marked => student.grades.as_ref().and_then(|x| x.mark).unwrap_or(false),
+            Filter::Skipped => student.status.skipped,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Action {
+    Build,
+    Run,
+    Grade,
+    Comment,
+    Mark,
+    Skip,
+    Submit,
+    Pull,
+}
+
+enum Input {
+    Normal,
+    Search,
+    Confirm(Action),
+    // the score typed so far
+    Score(String),
+}
+
+const STREAMS: [&str; 4] = ["Build Stdout", "Build Stderr", "Stdout", "Stderr"];
+
+/// A full-screen view of the grading session. The dashboard holds no database handle while it waits,
+/// every operation runs as a tulip subprocess with the terminal handed back to it.
+pub struct Dashboard<'a> {
+    pub workdir: &'a Path,
+    pub nutshell: &'a Path,
+    pub log_level: &'a str,
+    pub cache_size: u64,
+    status: Status,
+    remote: Vec<StudentDetail>,
+    focus: Pane,
+    stream: usize,
+    scroll: u16,
+    output_search: String,
+    remote_search: String,
+    filter: Filter,
+    selected: ListState,
+    input: Input,
+    message: String,
+}
+
+impl<'a> Dashboard<'a> {
+    pub fn new(workdir: &'a Path, nutshell: &'a Path, log_level: &'a str, cache_size: u64) -> Self {
+        Dashboard {
+            workdir,
+            nutshell,
+            log_level,
+            cache_size,
+            status: Status::default(),
+            remote: Vec::new(),
+            focus: Pane::Status,
+            stream: 0,
+            scroll: 0,
+            output_search: String::new(),
+            remote_search: String::new(),
+            filter: Filter::All,
+            selected: ListState::default(),
+            input: Input::Normal,
+            message: String::from("press ? for the key bindings"),
+        }
+    }
+
+    fn refresh(&mut self) {
+        let db = init_db(self.workdir.join("meta").as_path());
+        self.status = force_get_json::<Status>(&db, "status");
+    }
+
+    fn refresh_remote(&mut self) {
+        let (server, identity) = {
+            let db = init_db(self.workdir.join("meta").as_path());
+            (force_get(&db, "server"), crate::credential::identity(&db))
+        };
+        match crate::status::remote_details(server.as_str(), &identity) {
+            Ok(students) => self.remote = students,
+            Err(e) => self.message = format!("unable to list the students: {}", e)
+        }
+        self.selected.select(if self.visible().is_empty() { None } else { Some(0) });
+    }
+
+    fn visible(&self) -> Vec<&StudentDetail> {
+        self.remote.iter()
+            .filter(|x| self.filter.matches(x))
+            .filter(|x| x.student_id.contains(self.remote_search.as_str()))
+            .collect()
+    }
+
+    fn output(&self) -> &str {
+        let stream = match self.stream {
+            0 => &self.status.build_stdout,
+            1 => &self.status.build_stderr,
+            2 => &self.status.stdout,
+            _ => &self.status.stderr,
+        };
+        stream.as_deref().unwrap_or("")
+    }
+
+    /// Scroll to the next line of the output containing the search text.
+    fn next_match(&mut self) {
+        if self.output_search.is_empty() {
+            return;
+        }
+        let found = self.output().lines()
+            .enumerate()
+            .skip(self.scroll as usize + 1)
+            .find(|(_, x)| x.contains(self.output_search.as_str()))
+            .map(|(n, _)| n);
+        match found {
+            Some(n) => self.scroll = n as u16,
+            None => self.message = format!("no more matches of {}", self.output_search)
+        }
+    }
+
+    fn move_selection(&mut self, down: bool) {
+        let count = self.visible().len();
+        if count == 0 {
+            self.selected.select(None);
+            return;
+        }
+        let current = self.selected.selected().unwrap_or(0);
+        let next = if down { (current + 1).min(count - 1) } else { current.saturating_sub(1) };
+        self.selected.select(Some(next));
+    }
+
+    fn args(&self, action: Action) -> Option<Vec<String>> {
+        let args = match action {
+            Action::Build => vec!["build"],
+            Action::Run => vec!["run"],
+            // the rubric asks for the criteria, otherwise the score is typed in the dashboard first
+            Action::Grade if self.status.graded.is_some() => vec!["grade", "--override"],
+            Action::Grade => vec!["grade"],
+            Action::Comment => vec!["comment"],
+            Action::Mark if self.status.mark => vec!["mark", "--remove"],
+            Action::Mark => vec!["mark"],
+            Action::Skip => vec!["skip"],
+            Action::Submit => vec!["submit"],
+            Action::Pull => {
+                let visible = self.visible();
+                let student = visible.get(self.selected.selected()?)?;
+                return Some(vec![String::from("pull"), String::from("--id"), student.student_id.clone()]);
+            }
+        };
+        Some(args.into_iter().map(String::from).collect())
+    }
+
+    /// Leave the alternate screen and run tulip with the terminal, so prompts and editors work as usual.
+    fn step(&mut self, terminal: &mut Terminal<Backend>, args: &[String]) -> Result<(), String> {
+        disable_raw_mode().map_err(|x| x.to_string())?;
+        execute!(terminal.backend_mut(), LeaveAlternateScreen).map_err(|x| x.to_string())?;
+        terminal.show_cursor().map_err(|x| x.to_string())?;
+
+        let exe = std::env::current_exe().map_err(|x| x.to_string())?;
+        let mut command = Command::new(exe);
+        command.arg("--tulip-dir").arg(self.workdir)
+            .arg("--nutshell").arg(self.nutshell)
+            .arg("--log-level").arg(self.log_level)
+            .arg("--cache-size").arg(self.cache_size.to_string())
+            .args(args);
+        let _passphrase = crate::credential::share_passphrase(&mut command);
+        let result = command.spawn().and_then(|mut x| x.wait());
+        self.message = match result {
+            Ok(e) if e.success() => format!("{} finished", args[0]),
+            Ok(e) => format!("{} failed with {}", args[0], e),
+            Err(e) => format!("unable to start {}: {}", args[0], e),
+        };
+        println!("{}, press enter to return to the dashboard", self.message);
+        let mut line = String::new();
+        if let Err(e) = std::io::stdin().lock().read_line(&mut line) {
+            warn!("unable to read from stdin: {}", e);
+        }
+
+        execute!(terminal.backend_mut(), EnterAlternateScreen).map_err(|x| x.to_string())?;
+        enable_raw_mode().map_err(|x| x.to_string())?;
+        terminal.hide_cursor().map_err(|x| x.to_string())?;
+        terminal.clear().map_err(|x| x.to_string())?;
+        self.refresh();
+        if args[0] != "build" && args[0] != "run" {
+            self.refresh_remote();
+        }
+        Ok(())
+    }
+
+    fn act(&mut self, terminal: &mut Terminal<Backend>, action: Action) -> Result<(), String> {
+        match self.args(action) {
+            Some(args) => self.step(terminal, args.as_slice()),
+            None => {
+                self.message = String::from("no student selected");
+                Ok(())
+            }
+        }
+    }
+
+    fn grade(&mut self, terminal: &mut Terminal<Backend>) -> Result<(), String> {
+        let rubric = {
+            let db = init_db(self.workdir.join("meta").as_path());
+            !force_get_json::<Config>(&db, "config").rubric.is_empty()
+        };
+        if rubric {
+            return self.act(terminal, Action::Grade);
+        }
+        self.input = Input::Score(String::new());
+        Ok(())
+    }
+
+    /// Returns `false` when the dashboard should quit.
+    fn key(&mut self, terminal: &mut Terminal<Backend>, key: KeyEvent) -> Result<bool, String> {
+        if let Input::Score(score) = &mut self.input {
+            match key.code {
+                KeyCode::Char(c) if c.is_ascii_digit() || c == '.' => score.push(c),
+                KeyCode::Backspace => {
+                    score.pop();
+                }
+                KeyCode::Esc => {
+                    self.input = Input::Normal;
+                    self.message = String::from("cancelled");
+                }
+                KeyCode::Enter => {
+                    let score = score.clone();
+                    self.input = Input::Normal;
+                    match score.parse::<f64>() {
+                        Ok(_) => {
+                            let mut args = self.args(Action::Grade).unwrap_or_default();
+                            args.push(String::from("--score"));
+                            args.push(score);
+                            self.step(terminal, args.as_slice())?;
+                        }
+                        Err(_) => self.message = format!("invalid score {}", score),
+                    }
+                }
+                _ => ()
+            }
+            return Ok(true);
+        }
+        match &self.input {
+            Input::Search => {
+                let search = if self.focus == Pane::Remote { &mut self.remote_search } else { &mut self.output_search };
+                match key.code {
+                    KeyCode::Char(c) => search.push(c),
+                    KeyCode::Backspace => {
+                        search.pop();
+                    }
+                    KeyCode::Esc => {
+                        search.clear();
+                        self.input = Input::Normal;
+                    }
+                    KeyCode::Enter => {
+                        self.input = Input::Normal;
+                        if self.focus == Pane::Output {
+                            self.scroll = 0;
+                            self.next_match();
+                        }
+                    }
+                    _ => ()
+                }
+                if self.focus == Pane::Remote {
+                    self.selected.select(if self.visible().is_empty() { None } else { Some(0) });
+                }
+                return Ok(true);
+            }
+            Input::Confirm(action) => {
+                let action = *action;
+                self.input = Input::Normal;
+                if key.code == KeyCode::Char('y') {
+                    self.act(terminal, action)?;
+                } else {
+                    self.message = String::from("cancelled");
+                }
+                return Ok(true);
+            }
+            Input::Normal | Input::Score(_) => ()
+        }
+
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return Ok(false),
+            KeyCode::Tab => {
+                self.focus = match self.focus {
+                    Pane::Status => Pane::Output,
+                    Pane::Output => Pane::Remote,
+                    Pane::Remote => Pane::Status,
+                }
+            }
+            KeyCode::Char('?') => {
+                self.message = String::from("b build, r run, g grade, c comment, m mark, s skip, S submit, p pull the selected student, \
+                    R refresh, f filter, / search, n next match, 1-4 output stream, tab switch pane, q quit");
+            }
+            KeyCode::Char('b') => self.act(terminal, Action::Build)?,
+            KeyCode::Char('r') => self.act(terminal, Action::Run)?,
+            KeyCode::Char('g') => self.grade(terminal)?,
+            KeyCode::Char('c') => self.act(terminal, Action::Comment)?,
+            KeyCode::Char('m') => self.act(terminal, Action::Mark)?,
+            KeyCode::Char('s') => {
+                self.input = Input::Confirm(Action::Skip);
+                self.message = String::from("skip the current project? (y/n)");
+            }
+            KeyCode::Char('S') => {
+                self.input = Input::Confirm(Action::Submit);
+                self.message = String::from("submit the current project? (y/n)");
+            }
+            KeyCode::Char('p') => {
+                self.input = Input::Confirm(Action::Pull);
+                self.message = String::from("pull the selected student and replace the current project? (y/n)");
+            }
+            KeyCode::Char('R') => {
+                self.refresh();
+                self.refresh_remote();
+            }
+            KeyCode::Char('/') if self.focus != Pane::Status => {
+                self.input = Input::Search;
+            }
+            KeyCode::Char('n') if self.focus == Pane::Output => self.next_match(),
+            KeyCode::Char('f') if self.focus == Pane::Remote => {
+                self.filter = self.filter.next();
+                self.selected.select(if self.visible().is_empty() { None } else { Some(0) });
+            }
+            KeyCode::Char(c @ '1'..='4') => {
+                self.stream = c as usize - '1' as usize;
+                self.scroll = 0;
+            }
+            KeyCode::Down | KeyCode::Char('j') if self.focus == Pane::Remote => self.move_selection(true),
+            KeyCode::Up | KeyCode::Char('k') if self.focus == Pane::Remote => self.move_selection(false),
+            KeyCode::Down | KeyCode::Char('j') => self.scroll = self.scroll.saturating_add(1),
+            KeyCode::Up | KeyCode::Char('k') => self.scroll = self.scroll.saturating_sub(1),
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_add(20),
+            KeyCode::PageUp => self.scroll = self.scroll.saturating_sub(20),
+            KeyCode::Home => self.scroll = 0,
+            KeyCode::End => self.scroll = self.output().lines().count().saturating_sub(1) as u16,
+            _ => ()
+        }
+        Ok(true)
+    }
+
+    fn block(&self, pane: Pane, title: &'a str) -> Block<'a> {
+        let style = if self.focus == pane { Style::default().fg(Color::Yellow) } else { Style::default() };
+        Block::default().borders(Borders::ALL).title(title).border_style(style)
+    }
+
+    fn status_text(&self) -> Vec<Spans<'static>> {
+        let status = &self.status;
+        let field = |name: &str, value: String| Spans::from(vec![
+            Span::styled(format!("{:<14}", name), Style::default().fg(Color::Blue).add_modifier(Modifier::BOLD)),
+            Span::raw(value),
+        ]);
+        let mut text = vec![
+            field("Student", status.in_progress.as_ref()
+                .map(|x| x.student_id.clone())
+                .unwrap_or_else(|| String::from("N/A"))),
+            field("Mounted", status.mount.is_some().to_string()),
+            field("Built", status.built.to_string()),
+            field("Verdict", status.verdict.as_ref().map(|x| x.to_string()).unwrap_or_else(|| String::from("N/A"))),
+            field("Grade", status.graded.map(|x| x.to_string()).unwrap_or_else(|| String::from("N/A"))),
+        ];
+        if let Some(suggested) = &status.suggested {
+            text.push(field("Suggested", suggested.score.to_string()));
+        }
+        text.push(field("Mark", status.mark.to_string()));
+        text.push(field("Submitted", status.submitted.to_string()));
+        text.push(field("Shellcheck", status.lint.as_ref()
+            .map(|x| format!("{} finding(s)", x.len()))
+            .unwrap_or_else(|| String::from("not run"))));
+        text.push(field("Risks", status.risks.len().to_string()));
+        if !status.similar.is_empty() {
+            text.push(field("Similar To", status.similar.iter()
+                .map(|x| format!("{} ({:.1}%)", x.student_id, x.score * 100.0))
+                .collect::<Vec<_>>()
+                .join(", ")));
+        }
+        if !status.test_results.is_empty() {
+            let passed = status.test_results.iter().filter(|x| x.passed).count();
+            text.push(field("Tests", format!("{}/{} passed", passed, status.test_results.len())));
+        }
+        text.push(Spans::default());
+        text.push(Spans::from(Span::styled("Comment", Style::default().fg(Color::Blue).add_modifier(Modifier::BOLD))));
+        text.extend(status.comment.as_deref().unwrap_or_default().lines().map(|x| Spans::from(x.to_string())));
+        text
+    }
+
+    fn draw_output(&self, f: &mut tui::Frame<Backend>, area: Rect) {
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Length(3), Constraint::Min(0)].as_ref())
+            .split(area);
+        let tabs = Tabs::new(STREAMS.iter().map(|x| Spans::from(*x)).collect())
+            .block(self.block(Pane::Output, "Output"))
+            .select(self.stream)
+            .highlight_style(Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD));
+        f.render_widget(tabs, chunks[0]);
+
+        let highlight = Style::default().fg(Color::Black).bg(Color::Yellow);
+        let lines = self.output().lines()
+            .map(|x| if !self.output_search.is_empty() && x.contains(self.output_search.as_str()) {
+                Spans::from(Span::styled(x.to_string(), highlight))
+            } else {
+                Spans::from(x.to_string())
+            })
+            .collect::<Vec<_>>();
+        let title = if self.output_search.is_empty() { String::from(STREAMS[self.stream]) } else {
+            format!("{} /{}", STREAMS[self.stream], self.output_search)
+        };
+        let paragraph = Paragraph::new(lines)
+            .block(Block::default().borders(Borders::ALL).title(title.as_str()))
+            .scroll((self.scroll, 0));
+        f.render_widget(paragraph, chunks[1]);
+    }
+
+    fn draw_remote(&mut self, f: &mut tui::Frame<Backend>, area: Rect) {
+        let items = self.visible().into_iter()
+            .map(|x| {
+                let grade = x.grades.as_ref().and_then(|x| x.graded).map(|x| x.to_string()).unwrap_or_else(|| String::from("N/A"));
+                let marked = x.grades.as_ref().and_then(|x| x.mark).unwrap_or(false);
+                let flags = format!("{}{}{}", if marked { "M" } else { " " }, if x.status.skipped { "S" } else { " " },
+                                    if x.status.finished { "F" } else { " " });
+                ListItem::new(format!("{:<20} {:>8} {}", x.student_id, grade, flags))
+            })
+            .collect::<Vec<_>>();
+        let title = format!("Students [{}] {}", self.filter.name(),
+                            if self.remote_search.is_empty() { String::new() } else { format!("/{}", self.remote_search) });
+        let style = if self.focus == Pane::Remote { Style::default().fg(Color::Yellow) } else { Style::default() };
+        let list = List::new(items)
+            .block(Block::default().borders(Borders::ALL).title(title.as_str()).border_style(style))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED))
+            .highlight_symbol("> ");
+        f.render_stateful_widget(list, area, &mut self.selected);
+    }
+
+    fn draw(&mut self, f: &mut tui::Frame<Backend>) {
+        let rows = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Min(0), Constraint::Length(1)].as_ref())
+            .split(f.size());
+        let columns = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(35), Constraint::Percentage(65)].as_ref())
+            .split(rows[0]);
+        let left = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Percentage(50), Constraint::Percentage(50)].as_ref())
+            .split(columns[0]);
+
+        let status = self.status_text();
+        let paragraph = Paragraph::new(status)
+            .block(self.block(Pane::Status, "Status"))
+            .wrap(Wrap { trim: false });
+        f.render_widget(paragraph, left[0]);
+        self.draw_remote(f, left[1]);
+        self.draw_output(f, columns[1]);
+
+        let message = match &self.input {
+            Input::Search => Span::styled(format!("/{}", match self.focus {
+                Pane::Remote => self.remote_search.as_str(),
+                _ => self.output_search.as_str()
+            }), Style::default().fg(Color::Yellow)),
+            Input::Score(score) => Span::styled(format!("score: {}", score), Style::default().fg(Color::Yellow)),
+            _ => Span::raw(self.message.clone())
+        };
+        f.render_widget(Paragraph::new(Spans::from(message)), rows[1]);
+    }
+
+    fn event_loop(&mut self, terminal: &mut Terminal<Backend>) -> Result<(), String> {
+        loop {
+            terminal.draw(|f| self.draw(f)).map_err(|x| x.to_string())?;
+            if !event::poll(Duration::from_millis(500)).map_err(|x| x.to_string())? {
+                continue;
+            }
+            if let Event::Key(key) = event::read().map_err(|x| x.to_string())? {
+                if !self.key(terminal, key)? {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    pub fn handle(mut self) {
+        if !atty::is(atty::Stream::Stdout) {
+            error!("tulip tui needs a terminal");
+            std::process::exit(1);
+        }
+        self.refresh();
+        self.refresh_remote();
+
+        enable_raw_mode().exit_on_failure();
+        let mut stdout = std::io::stdout();
+        execute!(stdout, EnterAlternateScreen).exit_on_failure();
+        let mut terminal = Terminal::new(CrosstermBackend::new(stdout)).exit_on_failure();
+        terminal.hide_cursor().exit_on_failure();
+
+        let result = self.event_loop(&mut terminal);
+
+        disable_raw_mode().exit_on_failure();
+        execute!(terminal.backend_mut(), LeaveAlternateScreen).exit_on_failure();
+        terminal.show_cursor().exit_on_failure();
+        result.exit_on_failure();
+    }
+}
@@ -0,0 +1,289 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+use log::*;
+use prettytable::*;
+use rocksdb::DB;
+use serde::*;
+use tempfile::TempDir;
+
+use crate::{force_get, force_get_json, LogUnwrap};
+use crate::cache::Cache;
+use crate::inspect::{BUILD_OUTPUTS, VCS};
+use crate::settings::{Config, Status};
+
+pub enum Source {
+    Previous,
+    Student(String),
+    Template(PathBuf),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Change {
+    Added,
+    Removed,
+    Modified,
+}
+
+#[derive(Serialize)]
+struct FileDiff {
+    path: PathBuf,
+    change: Change,
+    added: usize,
+    removed: usize,
+    patch: String,
+}
+
+pub struct Diff {
+    pub source: Source,
+    pub summary: bool,
+    pub attach: bool,
+    pub backend: String,
+}
+
+pub fn extract(tarball: &Path) -> TempDir {
+    let dir = tempfile::tempdir().exit_on_failure();
+    std::process::Command::new("tar")
+        .arg("-C")
+        .arg(dir.path())
+        .arg("-xf")
+        .arg(tarball)
+        .spawn()
+        .exit_on_failure()
+        .wait()
+        .map_err(|x| x.to_string())
+        .and_then(|x| if x.success() { Ok(()) } else { Err(format!("tar failed with: {}", x)) })
+        .exit_on_failure();
+    dir
+}
+
+fn copy(dir: &Path) -> TempDir {
+    let copy = tempfile::tempdir().exit_on_failure();
+    std::process::Command::new("cp")
+        .arg("-a")
+        .arg(dir.join("."))
+        .arg(copy.path())
+        .spawn()
+        .exit_on_failure()
+        .wait()
+        .map_err(|x| x.to_string())
+        .and_then(|x| if x.success() { Ok(()) } else { Err(format!("cp failed with: {}", x)) })
+        .exit_on_failure();
+    copy
+}
+
+/// Text files of the tree by relative path, leaving out build outputs, VCS data, binaries and symlinks.
+fn files(dir: &Path, relative: &Path, result: &mut BTreeMap<PathBuf, PathBuf>) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return
+    };
+    for entry in entries.filter_map(|x| x.ok()) {
+        let name = entry.file_name();
+        let relative = relative.join(&name);
+        let file_type = match entry.file_type() {
+            Ok(x) => x,
+            Err(_) => continue
+        };
+        let name = name.to_string_lossy();
+        if file_type.is_dir() {
+            if !BUILD_OUTPUTS.contains(&name.as_ref()) && !VCS.contains(&name.as_ref()) {
+                files(entry.path().as_path(), relative.as_path(), result);
+            }
+        } else if file_type.is_file() {
+            let path = entry.path();
+            if crate::inspect::head(path.as_path()).contains(&0) {
+                debug!("skipping binary file {}", relative.display());
+                continue;
+            }
+            result.insert(relative, path);
+        }
+    }
+}
+
+fn unified(relative: &Path, old: Option<&Path>, new: Option<&Path>) -> Result<String, String> {
+    let label = |prefix: &str, path: Option<&Path>| match path {
+        Some(_) => format!("{}/{}", prefix, relative.display()),
+        None => String::from("/dev/null")
+    };
+    let output = std::process::Command::new("diff")
+        .arg("-u")
+        .arg("--label")
+        .arg(label("a", old))
+        .arg("--label")
+        .arg(label("b", new))
+        .arg(old.unwrap_or_else(|| Path::new("/dev/null")))
+        .arg(new.unwrap_or_else(|| Path::new("/dev/null")))
+        .output()
+        .map_err(|x| x.to_string())?;
+    // diff exits with 1 when the files differ
+    match output.status.code() {
+        Some(0) | Some(1) => Ok(String::from_utf8_lossy(output.stdout.as_slice()).to_string()),
+        _ => Err(String::from_utf8_lossy(output.stderr.as_slice()).to_string())
+    }
+}
+
+fn compare(old: &Path, new: &Path) -> Vec<FileDiff> {
+    let mut old_files = BTreeMap::new();
+    let mut new_files = BTreeMap::new();
+    files(old, Path::new(""), &mut old_files);
+    files(new, Path::new(""), &mut new_files);
+    let paths = old_files.keys().chain(new_files.keys()).cloned().collect::<BTreeSet<_>>();
+
+    let mut result = Vec::new();
+    for path in paths {
+        let before = old_files.get(&path).map(|x| x.as_path());
+        let after = new_files.get(&path).map(|x| x.as_path());
+        let change = match (before, after) {
+            (Some(a), Some(b)) => {
+                if std::fs::read(a).ok() == std::fs::read(b).ok() {
+                    continue;
+                }
+                Change::Modified
+            }
+            (None, Some(_)) => Change::Added,
+            _ => Change::Removed
+        };
+        let patch = match unified(path.as_path(), before, after) {
+            Ok(patch) => patch,
+            Err(e) => {
+                warn!("unable to diff {}: {}", path.display(), e);
+                continue;
+            }
+        };
+        let added = patch.lines().filter(|x| x.starts_with('+') && !x.starts_with("+++")).count();
+        let removed = patch.lines().filter(|x| x.starts_with('-') && !x.starts_with("---")).count();
+        result.push(FileDiff { path, change, added, removed, patch });
+    }
+    result
+}
+
+fn colourise(patch: &str) -> String {
+    patch.lines()
+        .map(|x| if x.starts_with("+++") || x.starts_with("---") {
+            format!("\x1b[1m{}\x1b[0m", x)
+        } else if x.starts_with('+') {
+            format!("\x1b[32m{}\x1b[0m", x)
+        } else if x.starts_with('-') {
+            format!("\x1b[31m{}\x1b[0m", x)
+        } else if x.starts_with("@@") {
+            format!("\x1b[36m{}\x1b[0m", x)
+        } else {
+            x.to_string()
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn summary_table(diffs: &[FileDiff]) -> Table {
+    let mut table = Table::new();
+    table.add_row(row![bFb->"Path", bFb->"Change", bFb->"Added", bFb->"Removed"]);
+    for i in diffs {
+        let change = match i.change {
+            Change::Added => cell!(Fg->"Added"),
+            Change::Removed => cell!(Fr->"Removed"),
+            Change::Modified => cell!(Fy->"Modified"),
+        };
+        table.add_row(Row::new(vec![cell!(i.path.display()), change, cell!(Fg->i.added), cell!(Fr->i.removed)]));
+    }
+    table
+}
+
+fn summary_text(label: &str, diffs: &[FileDiff]) -> String {
+    let count = |change: Change| diffs.iter().filter(|x| x.change == change).count();
+    let mut text = format!("Changes against {}: {} added, {} removed, {} modified file(s), +{}/-{} line(s)",
+                           label, count(Change::Added), count(Change::Removed), count(Change::Modified),
+                           diffs.iter().map(|x| x.added).sum::<usize>(), diffs.iter().map(|x| x.removed).sum::<usize>());
+    for i in diffs {
+        let mark = match i.change {
+            Change::Added => 'A',
+            Change::Removed => 'D',
+            Change::Modified => 'M',
+        };
+        text.push_str(format!("\n{} {} (+{}/-{})", mark, i.path.display(), i.added, i.removed).as_str());
+    }
+    text
+}
+
+impl Diff {
+    /// Label and tree to compare with, the temporary dir keeps an extracted tarball alive.
+    fn baseline(&self, db: &DB, cache: &Cache, student_id: &str) -> (String, PathBuf, Option<TempDir>) {
+        let (label, tarball) = match &self.source {
+            Source::Previous => match cache.versions(student_id).get(1) {
+                Some(entry) => (format!("the version fetched at {}", entry.fetched_at), cache.blob_path(entry)),
+                None => {
+                    error!("no earlier version of {} in the cache", student_id);
+                    std::process::exit(1);
+                }
+            },
+            Source::Student(id) => {
+                crate::validate::student_id(id.as_str()).exit_on_failure();
+                let server = force_get(db, "server");
+                let identity = crate::credential::identity(db);
+                (format!("student {}", id), cache.fetch(self.backend.as_str(), server.as_str(), &identity, id.as_str()))
+            }
+            Source::Template(path) if path.is_dir() => {
+                return (String::from("the starter template"), path.clone(), None);
+            }
+            Source::Template(path) => (String::from("the starter template"), path.clone())
+        };
+        let dir = extract(tarball.as_path());
+        (label, dir.path().to_path_buf(), Some(dir))
+    }
+
+    pub fn handle(&self, db: &DB, cache: &Cache, workdir: &Path) {
+        let mut status = force_get_json::<Status>(db, "status");
+        let student_id = match status.in_progress.as_ref() {
+            Some(x) => x.student_id.clone(),
+            None => {
+                error!("no current project");
+                std::process::exit(1);
+            }
+        };
+        let (label, baseline, extracted) = self.baseline(db, cache, student_id.as_str());
+        // the current project went through the normalizer when it was fetched, the baseline has to as well
+        let config = db.get("config")
+            .ok().flatten()
+            .and_then(|mut x| simd_json::serde::from_slice::<Config>(x.as_mut_slice()).ok());
+        let copied = match (config.as_ref().and_then(|x| x.normalize.as_ref()), status.in_progress.as_ref()) {
+            (Some(policy), Some(project)) => {
+                // never rewrite a template directory in place
+                let copied = if extracted.is_none() { Some(copy(baseline.as_path())) } else { None };
+                crate::normalize::normalize(policy, project, copied.as_ref().map(|x| x.path()).unwrap_or(baseline.as_path()));
+                copied
+            }
+            _ => None
+        };
+        let baseline = copied.as_ref().map(|x| x.path()).unwrap_or(baseline.as_path());
+        let diffs = compare(baseline, workdir.join("student").as_path());
+        if diffs.is_empty() {
+            info!("no differences against {}", label);
+            return;
+        }
+
+        crate::output::emit(&diffs, || {
+            if !self.summary {
+                let colour = crate::output::colour();
+                for i in &diffs {
+                    if colour {
+                        println!("{}", colourise(i.patch.as_str()));
+                    } else {
+                        print!("{}", i.patch);
+                    }
+                }
+            }
+            summary_table(&diffs).printstd();
+        });
+
+        if self.attach {
+            let text = summary_text(label.as_str(), &diffs);
+            status.comment = Some(match status.comment.take() {
+                Some(comment) if !comment.trim().is_empty() => format!("{}\n\n{}", comment, text),
+                _ => text
+            });
+            db.put("status", serde_json::to_vec(&status).exit_on_failure()).exit_on_failure();
+            info!("diff summary attached to the comment");
+        }
+    }
+}
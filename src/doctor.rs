@@ -0,0 +1,290 @@
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use log::*;
+use prettytable::*;
+use serde::Serialize;
+
+use crate::credential::Store;
+use crate::init_db;
+use crate::settings::{Comparator, Config, Status};
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Level {
+    Pass,
+    Warn,
+    Fail,
+}
+
+#[derive(Serialize)]
+struct Check {
+    name: String,
+    level: Level,
+    detail: String,
+    hint: String,
+}
+
+impl Check {
+    fn new<N: Into<String>, D: Into<String>>(name: N, level: Level, detail: D) -> Self {
+        Check { name: name.into(), level, detail: detail.into(), hint: String::new() }
+    }
+
+    fn hint<H: Into<String>>(mut self, hint: H) -> Self {
+        if self.level != Level::Pass {
+            self.hint = hint.into();
+        }
+        self
+    }
+}
+
+pub struct Doctor<'a> {
+    pub workdir: &'a Path,
+    pub nutshell: &'a Path,
+    pub backend: String,
+    pub shellcheck: PathBuf,
+    pub editor: String,
+    pub reader: PathBuf,
+    pub mount_dir: PathBuf,
+}
+
+fn find_binary(name: &Path) -> Option<PathBuf> {
+    if name.components().count() > 1 {
+        return if name.is_file() { Some(name.to_path_buf()) } else { None };
+    }
+    std::env::var_os("PATH")
+        .and_then(|x| std::env::split_paths(&x).map(|dir| dir.join(name)).find(|x| x.is_file()))
+}
+
+fn version(binary: &Path, arg: &str) -> Option<String> {
+    Command::new(binary)
+        .arg(arg)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()
+        .and_then(|x| String::from_utf8_lossy(x.stdout.as_slice())
+            .lines()
+            .map(|x| x.trim())
+            .find(|x| !x.is_empty())
+            .map(|x| x.to_string()))
+}
+
+/// Check the binary, `version_arg` is `None` for programs without a version flag.
+fn binary(name: &Path, version_arg: Option<&str>, required: bool, hint: &str) -> Check {
+    let display = name.display().to_string();
+    match find_binary(name) {
+        Some(path) => {
+            let detail = version_arg.and_then(|x| version(path.as_path(), x))
+                .map(|x| format!("{} ({})", path.display(), x))
+                .unwrap_or_else(|| path.display().to_string());
+            Check::new(display, Level::Pass, detail)
+        }
+        None => Check::new(display, if required { Level::Fail } else { Level::Warn }, "not found")
+            .hint(hint)
+    }
+}
+
+fn filesystem(name: &str) -> Check {
+    let supported = std::fs::read_to_string("/proc/filesystems")
+        .map(|x| x.lines().any(|x| x.split_whitespace().last() == Some(name)))
+        .unwrap_or(false);
+    if supported {
+        Check::new(format!("kernel {}", name), Level::Pass, "supported")
+    } else {
+        Check::new(format!("kernel {}", name), Level::Warn, "not listed in /proc/filesystems")
+            .hint(format!("it may be a module, try `sudo modprobe {}`", name))
+    }
+}
+
+/// Free space in MiB, read from `df` as the rest of tulip drives external programs.
+fn free_space(path: &Path) -> Option<u64> {
+    let output = Command::new("df").arg("-Pk").arg(path).stderr(Stdio::null()).output().ok()?;
+    String::from_utf8_lossy(output.stdout.as_slice())
+        .lines()
+        .nth(1)
+        .and_then(|x| x.split_whitespace().nth(3).map(|x| x.to_string()))
+        .and_then(|x| x.parse::<u64>().ok())
+        .map(|x| x / 1024)
+}
+
+fn disk(name: &str, path: &Path) -> Check {
+    match free_space(path) {
+        None => Check::new(format!("free space in {}", name), Level::Warn, format!("unable to check {}", path.display()))
+            .hint("make sure `df` is available and the directory exists"),
+        Some(x) if x < 256 => Check::new(format!("free space in {}", name), Level::Fail, format!("{} MiB", x))
+            .hint("free some space, an image and a project need at least several hundreds MiB"),
+        Some(x) if x < 2048 => Check::new(format!("free space in {}", name), Level::Warn, format!("{} MiB", x))
+            .hint("large projects or images may not fit"),
+        Some(x) => Check::new(format!("free space in {}", name), Level::Pass, format!("{} MiB", x)),
+    }
+}
+
+fn mount_dir(path: &Path) -> Check {
+    match std::fs::metadata(path) {
+        Ok(meta) if meta.is_dir() => {
+            let mounted = !crate::recover::mounted_under(path).is_empty();
+            if mounted {
+                Check::new("mount dir", Level::Warn, format!("{} is already a mount point", path.display()))
+                    .hint("it is fine if it is the current overlay, otherwise run `tulip recover`")
+            } else {
+                Check::new("mount dir", Level::Pass, path.display().to_string())
+            }
+        }
+        Ok(_) => Check::new("mount dir", Level::Fail, format!("{} is not a directory", path.display()))
+            .hint("set TULIP_MOUNT_DIR to a directory"),
+        Err(e) => Check::new("mount dir", Level::Fail, format!("{}: {}", path.display(), e))
+            .hint(format!("create it with `sudo mkdir -p {}`", path.display())),
+    }
+}
+
+fn effective_uid() -> Option<u32> {
+    std::fs::read_to_string("/proc/self/status").ok()?
+        .lines()
+        .find(|x| x.starts_with("Uid:"))
+        .and_then(|x| x.split_whitespace().nth(2).map(|x| x.to_string()))
+        .and_then(|x| x.parse().ok())
+}
+
+fn privilege() -> Check {
+    let user = std::env::var("USER").unwrap_or_default();
+    match effective_uid() {
+        Some(0) if user == "root" => Check::new("privilege", Level::Pass, "running as root"),
+        Some(0) => Check::new("privilege", Level::Warn, format!("euid is 0 but USER is {:?}", user))
+            .hint("commands requiring root check USER, run them with `sudo -E` or set USER=root"),
+        Some(uid) => {
+            let sudo = Command::new("sudo").arg("-n").arg("true")
+                .stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null())
+                .status()
+                .map(|x| x.success())
+                .unwrap_or(false);
+            if sudo {
+                Check::new("privilege", Level::Pass, format!("euid {}, sudo without password", uid))
+            } else {
+                Check::new("privilege", Level::Warn, format!("euid {}, sudo asks for a password", uid))
+                    .hint("expect password prompts, `batch` and `clean-all` must run as root")
+            }
+        }
+        None => Check::new("privilege", Level::Warn, "unable to read /proc/self/status")
+            .hint("make sure /proc is mounted"),
+    }
+}
+
+fn memory_controller() -> Check {
+    let controllers = std::fs::read_to_string(Path::new(crate::verdict::CGROUP_ROOT).join("cgroup.controllers"));
+    match controllers {
+        Ok(x) if x.split_whitespace().any(|x| x == "memory") =>
+            Check::new("cgroup memory", Level::Pass, "memory controller available"),
+        Ok(_) => Check::new("cgroup memory", Level::Warn, "no memory controller in cgroup v2")
+            .hint("enable the memory controller, out of memory kills are only guessed from stderr"),
+        Err(_) => Check::new("cgroup memory", Level::Warn, "cgroup v2 is not mounted")
+            .hint("boot with cgroup v2, out of memory kills are only guessed from stderr"),
+    }
+}
+
+/// The checker programs live in the image, so they can only be found while a project is mounted.
+fn checker(name: &str, path: &Path, shell: Option<&str>, mount: Option<&Path>) -> Check {
+    let name = format!("checker of {}", name);
+    let mount = match mount {
+        Some(mount) => mount,
+        None => return Check::new(name, Level::Warn, format!("{} is in the image, nothing is mounted", path.display()))
+            .hint("run `tulip doctor` again after `tulip build`"),
+    };
+    let shell = shell.unwrap_or("/bin/sh");
+    let missing = [Path::new(shell), path].iter()
+        .map(|x| mount.join(x.strip_prefix("/").unwrap_or(x)))
+        .find(|x| !x.is_file());
+    match missing {
+        Some(x) => Check::new(name, Level::Fail, format!("{} not found", x.display()))
+            .hint("the image does not match the config, run `tulip pull-image`"),
+        None => Check::new(name, Level::Pass, path.display().to_string()),
+    }
+}
+
+fn meta(workdir: &Path) -> (Option<Config>, Option<Status>, Option<Store>) {
+    let meta = workdir.join("meta");
+    if !meta.exists() {
+        return (None, None, None);
+    }
+    let db = init_db(meta.as_path());
+    let config = db.get("config").ok().flatten()
+        .and_then(|mut x| simd_json::serde::from_slice::<Config>(x.as_mut_slice()).ok());
+    let status = db.get("status").ok().flatten()
+        .and_then(|mut x| simd_json::serde::from_slice::<Status>(x.as_mut_slice()).ok());
+    let store = if crate::credential::registered(&db) { Some(crate::credential::current_store(&db)) } else { None };
+    (config, status, store)
+}
+
+impl<'a> Doctor<'a> {
+    fn checks(&self) -> Vec<Check> {
+        let (config, status, store) = meta(self.workdir);
+        let mut checks = vec![
+            binary(Path::new("sudo"), Some("--version"), true, "install sudo"),
+            binary(self.nutshell, Some("--version"), true, "install it with `cargo install nutshell` or set NUTSHELL_BIN"),
+            binary(Path::new("rsync"), Some("--version"), true, "install rsync"),
+            binary(Path::new("umount"), Some("--version"), true, "install util-linux"),
+            binary(Path::new("tar"), Some("--version"), true, "install tar"),
+            binary(Path::new("systemd-nspawn"), Some("--version"), true, "install systemd-container"),
+            binary(Path::new("firejail"), Some("--version"), true, "install firejail"),
+        ];
+        // the builtin backend downloads with tulip itself
+        if self.backend != "builtin" {
+            checks.push(binary(Path::new(self.backend.as_str()), Some("--version"), true,
+                               format!("install {} or choose another backend", self.backend).as_str()));
+        }
+        checks.push(binary(self.shellcheck.as_path(), Some("--version"), false, "install shellcheck or set SHELL_CHECK_BIN"));
+        checks.push(binary(Path::new(self.editor.as_str()), None, false, "set EDITOR to an installed editor"));
+        checks.push(binary(self.reader.as_path(), None, false, "set TULIP_REPORT_READER to an installed reader"));
+        checks.push(binary(Path::new("diff"), Some("--version"), false, "install diffutils, `tulip diff` needs it"));
+        if let Some(Store::Keyring) = store {
+            checks.push(binary(Path::new("secret-tool"), None, true, "install libsecret-tools, the credential is in the keyring"));
+        }
+
+        match config.as_ref() {
+            Some(config) => {
+                if config.firejail.has_x {
+                    checks.push(binary(Path::new("xhost"), None, true, "install xhost, the config allows X"));
+                }
+                if config.firejail.limit.is_some() && !config.test_cases.is_empty() {
+                    checks.push(memory_controller());
+                }
+                let mount = status.as_ref().and_then(|x| x.mount.as_deref());
+                for case in &config.test_cases {
+                    if let Comparator::Checker { path, shell } = &case.comparator {
+                        checks.push(checker(case.name.as_str(), path, shell.as_deref(), mount));
+                    }
+                }
+            }
+            None => checks.push(Check::new("config", Level::Warn, "no global config")
+                .hint("run `tulip pull-image` or `tulip refresh-config`, config specific checks are skipped")),
+        }
+
+        checks.push(filesystem("overlay"));
+        checks.push(filesystem("squashfs"));
+        checks.push(disk("tulip dir", self.workdir));
+        checks.push(mount_dir(self.mount_dir.as_path()));
+        checks.push(disk("mount dir", self.mount_dir.as_path()));
+        checks.push(privilege());
+        checks
+    }
+
+    pub fn handle(&self) {
+        let checks = self.checks();
+        crate::output::emit(&checks, || {
+            let mut table = Table::new();
+            table.add_row(row![bFb->"Check", bFb->"Result", bFb->"Detail", bFb->"Hint"]);
+            for i in &checks {
+                match i.level {
+                    Level::Pass => table.add_row(row![i.name, bFg->"pass", i.detail, i.hint]),
+                    Level::Warn => table.add_row(row![i.name, bFy->"warn", i.detail, i.hint]),
+                    Level::Fail => table.add_row(row![i.name, bFr->"fail", i.detail, i.hint]),
+                };
+            }
+            table.printstd();
+        });
+        if checks.iter().any(|x| x.level == Level::Fail) {
+            error!("some checks failed");
+            std::process::exit(1);
+        }
+    }
+}
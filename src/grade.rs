@@ -0,0 +1,265 @@
+use std::str::FromStr;
+
+use log::*;
+use prettytable::*;
+use rocksdb::DB;
+use serde::*;
+
+use crate::{force_get_json, LogUnwrap, rubric};
+use crate::settings::{Adjustment, AdjustmentKind, Config, Status};
+
+const EPSILON: f64 = 1e-9;
+
+#[derive(Serialize)]
+struct ScoreShow<'a> {
+    base: Option<f64>,
+    adjustments: &'a [Adjustment],
+    total: Option<f64>,
+}
+
+impl FromStr for AdjustmentKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bonus" => Ok(AdjustmentKind::Bonus),
+            "late-penalty" => Ok(AdjustmentKind::LatePenalty),
+            "academic-integrity" => Ok(AdjustmentKind::AcademicIntegrity),
+            "other" => Ok(AdjustmentKind::Other),
+            _ => Err(format!("unknown adjustment kind: {}", s))
+        }
+    }
+}
+
+pub fn round_to_step(config: &Config, value: f64) -> f64 {
+    match config.grade_step {
+        Some(step) if step > 0.0 => (value / step).round() * step,
+        _ => value
+    }
+}
+
+pub fn check_step(config: &Config, value: f64) -> Result<(), String> {
+    if !value.is_finite() {
+        return Err(format!("{} is not a number of points", value));
+    }
+    match config.grade_step {
+        Some(step) if step > 0.0 && (value / step - (value / step).round()).abs() > EPSILON => {
+            Err(format!("{} is not a multiple of the grade step {}", value, step))
+        }
+        _ => Ok(())
+    }
+}
+
+/// Deduction for submitting after the deadline, one `per_day` for every started day.
+fn late_penalty(config: &Config, status: &Status) -> Option<Adjustment> {
+    let policy = config.late_policy.as_ref()?;
+    let deadline = config.deadline?;
+    let submitted = status.in_progress.as_ref()?.submitted_at?;
+    let late = submitted.signed_duration_since(deadline).num_seconds();
+    if late <= 0 {
+        return None;
+    }
+    let days = (late + 86399) / 86400;
+    let mut points = if policy.percent {
+        status.graded.unwrap_or(0.0) * policy.per_day / 100.0 * days as f64
+    } else {
+        policy.per_day * days as f64
+    };
+    if let Some(max) = policy.max {
+        points = points.min(max);
+    }
+    Some(Adjustment {
+        kind: AdjustmentKind::LatePenalty,
+        points: -round_to_step(config, points),
+        reason: format!("{} day(s) late", days),
+        automatic: true,
+    })
+}
+
+pub fn handle(db: &DB, score: Option<f64>, accept_suggested: bool, criterion: Vec<(String, f64)>, r#override: bool) {
+    let mut status = force_get_json::<Status>(db, "status");
+    if status.in_progress.is_none() {
+        error!("no current project");
+        std::process::exit(1);
+    }
+    if status.graded.is_some() && !r#override {
+        error!("grading exists");
+        std::process::exit(1);
+    }
+    let config = force_get_json::<Config>(db, "config");
+    let score = if accept_suggested {
+        status.suggested.as_ref().map(|x| x.score).unwrap_or_else(|| {
+            error!("no suggested score, please run the tests with a scoring policy first");
+            std::process::exit(1);
+        })
+    } else if let Some(score) = score {
+        score
+    } else if !config.rubric.is_empty() {
+        if criterion.is_empty() {
+            rubric::interactive(&config, &mut status);
+        } else {
+            rubric::assign(&config, &mut status, criterion).exit_on_failure();
+        }
+        match rubric::total(&config, &status) {
+            Some(total) => total,
+            None => {
+                db.put("status", serde_json::to_string(&status)
+                    .exit_on_failure()).exit_on_failure();
+                return;
+            }
+        }
+    } else {
+        error!("no rubric configured, please give a score");
+        std::process::exit(1);
+    };
+    if !score.is_finite() || score < 0.0 || config.max_grade as f64 + EPSILON < score {
+        error!("score out of range");
+        std::process::exit(1);
+    }
+    check_step(&config, score).exit_on_failure();
+    status.graded.replace(score);
+    status.adjustments.retain(|x| !x.automatic);
+    if let Some(penalty) = late_penalty(&config, &status) {
+        warn!("late penalty applied: {} ({})", penalty.points, penalty.reason);
+        status.adjustments.push(penalty);
+    }
+    db.put("status", serde_json::to_string(&status)
+        .exit_on_failure()).exit_on_failure();
+}
+
+pub fn adjust(db: &DB, kind: Option<AdjustmentKind>, points: Option<f64>, reason: Option<String>, remove: Option<usize>) {
+    let mut status = force_get_json::<Status>(db, "status");
+    if status.in_progress.is_none() {
+        error!("no current project");
+        std::process::exit(1);
+    }
+    if let Some(index) = remove {
+        if index == 0 || index > status.adjustments.len() {
+            error!("no such adjustment: {}", index);
+            std::process::exit(1);
+        }
+        let removed = status.adjustments.remove(index - 1);
+        info!("removed {:?} adjustment: {}", removed.kind, removed.reason);
+    } else {
+        let config = force_get_json::<Config>(db, "config");
+        let kind = kind.unwrap();
+        let points = points.unwrap();
+        check_step(&config, points).exit_on_failure();
+        let points = match kind {
+            AdjustmentKind::Bonus => points.abs(),
+            AdjustmentKind::LatePenalty | AdjustmentKind::AcademicIntegrity => -points.abs(),
+            AdjustmentKind::Other => points,
+        };
+        status.adjustments.push(Adjustment {
+            kind,
+            points,
+            reason: reason.unwrap(),
+            automatic: false,
+        });
+    }
+    let score = ScoreShow { base: status.graded, adjustments: status.adjustments.as_slice(), total: status.total() };
+    crate::output::emit(&score, || { score_table(&status).printstd(); });
+    db.put("status", serde_json::to_string(&status)
+        .exit_on_failure()).exit_on_failure();
+}
+
+pub fn score_table(status: &Status) -> Table {
+    let mut table = Table::new();
+    table.add_row(row![bFb->"#", bFb->"Item", bFb->"Points", bFb->"Reason"]);
+    table.add_row(row!["", "base", status.graded.map(|x| x.to_string()).unwrap_or_else(|| String::from("N/A")), ""]);
+    for (index, i) in status.adjustments.iter().enumerate() {
+        let kind = format!("{:?}{}", i.kind, if i.automatic { " (auto)" } else { "" });
+        if i.points < 0.0 {
+            table.add_row(row![index + 1, kind, bFr->format!("{:+}", i.points), i.reason]);
+        } else {
+            table.add_row(row![index + 1, kind, bFg->format!("{:+}", i.points), i.reason]);
+        }
+    }
+    table.add_row(row!["", bFy->"total", bFy->status.total().map(|x| x.to_string()).unwrap_or_else(|| String::from("N/A")), ""]);
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Duration, Utc};
+
+    use crate::settings::{LatePolicy, StudentConfig};
+
+    use super::*;
+
+    fn config(step: Option<f64>) -> Config {
+        Config { grade_step: step, ..Config::default() }
+    }
+
+    fn late(policy: LatePolicy, late: Duration, graded: Option<f64>) -> Option<Adjustment> {
+        let deadline = "2020-06-01T00:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let config = Config { deadline: Some(deadline), late_policy: Some(policy), ..Config::default() };
+        let status = Status {
+            graded,
+            in_progress: Some(StudentConfig { submitted_at: Some(deadline + late), ..StudentConfig::default() }),
+            ..Status::default()
+        };
+        late_penalty(&config, &status)
+    }
+
+    #[test]
+    fn rounds_to_the_nearest_step() {
+        assert_eq!(round_to_step(&config(Some(0.5)), 7.3), 7.5);
+        assert_eq!(round_to_step(&config(Some(0.5)), 7.2), 7.0);
+        assert_eq!(round_to_step(&config(None), 7.3), 7.3);
+        assert_eq!(round_to_step(&config(Some(0.0)), 7.3), 7.3);
+        assert_eq!(round_to_step(&config(Some(-1.0)), 7.3), 7.3);
+        assert!(round_to_step(&config(Some(0.5)), f64::NAN).is_nan());
+    }
+
+    #[test]
+    fn checks_the_step() {
+        assert!(check_step(&config(Some(0.5)), 7.5).is_ok());
+        assert!(check_step(&config(Some(0.1)), 0.3).is_ok());
+        assert!(check_step(&config(Some(0.5)), 7.25).is_err());
+        assert!(check_step(&config(None), 7.25).is_ok());
+        assert!(check_step(&config(Some(0.0)), 7.25).is_ok());
+    }
+
+    #[test]
+    fn rejects_non_finite_points() {
+        for value in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY].iter() {
+            assert!(check_step(&config(None), *value).is_err());
+            assert!(check_step(&config(Some(0.5)), *value).is_err());
+        }
+    }
+
+    #[test]
+    fn no_penalty_on_time() {
+        let policy = || LatePolicy { per_day: 1.0, percent: false, max: None };
+        assert!(late(policy(), Duration::seconds(0), Some(10.0)).is_none());
+        assert!(late(policy(), Duration::seconds(-3600), Some(10.0)).is_none());
+    }
+
+    #[test]
+    fn counts_every_started_day() {
+        let policy = || LatePolicy { per_day: 1.0, percent: false, max: None };
+        assert_eq!(late(policy(), Duration::seconds(1), Some(10.0)).unwrap().points, -1.0);
+        assert_eq!(late(policy(), Duration::days(1), Some(10.0)).unwrap().points, -1.0);
+        let penalty = late(policy(), Duration::days(1) + Duration::seconds(1), Some(10.0)).unwrap();
+        assert_eq!(penalty.points, -2.0);
+        assert_eq!(penalty.reason, "2 day(s) late");
+        assert!(penalty.automatic);
+    }
+
+    #[test]
+    fn caps_and_scales_the_penalty() {
+        let capped = LatePolicy { per_day: 3.0, percent: false, max: Some(5.0) };
+        assert_eq!(late(capped, Duration::days(3), Some(10.0)).unwrap().points, -5.0);
+        let percent = LatePolicy { per_day: 10.0, percent: true, max: None };
+        assert_eq!(late(percent, Duration::days(2), Some(50.0)).unwrap().points, -10.0);
+        let ungraded = LatePolicy { per_day: 10.0, percent: true, max: None };
+        assert_eq!(late(ungraded, Duration::days(2), None).unwrap().points, 0.0);
+    }
+
+    #[test]
+    fn needs_a_deadline_and_a_submission_time() {
+        let config = Config { late_policy: Some(LatePolicy { per_day: 1.0, percent: false, max: None }), ..Config::default() };
+        assert!(late_penalty(&config, &Status::default()).is_none());
+    }
+}
@@ -0,0 +1,525 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use log::*;
+use prettytable::*;
+use reqwest::Url;
+use rocksdb::DB;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{force_get, force_get_json, LogUnwrap};
+use crate::credential::Authenticate;
+use crate::http::Dispatch;
+use crate::cli::GradebookColumns;
+use crate::output::emit;
+use crate::settings::{Config, Submission};
+use crate::status::{remote_details, StudentDetail};
+use crate::student::SubmissionResponse;
+use crate::validate::url_id;
+
+const EPSILON: f64 = 1e-6;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    Blank,
+    StudentId,
+    Grade,
+    Comment,
+    Marked,
+    Skipped,
+    Finished,
+}
+
+impl Field {
+    fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "student_id" => Ok(Field::StudentId),
+            "grade" => Ok(Field::Grade),
+            "comment" => Ok(Field::Comment),
+            "marked" => Ok(Field::Marked),
+            "skipped" => Ok(Field::Skipped),
+            "finished" => Ok(Field::Finished),
+            _ => Err(format!("unknown gradebook field {}, expected student_id, grade, comment, marked, skipped or finished", name))
+        }
+    }
+
+    fn value(self, student: &StudentDetail) -> Value {
+        let grades = student.grades.as_ref();
+        match self {
+            Field::Blank => Value::Null,
+            Field::StudentId => Value::from(student.student_id.as_str()),
+            Field::Grade => grades.and_then(|x| x.graded).map(Value::from).unwrap_or(Value::Null),
+            Field::Comment => grades.and_then(|x| x.comment.clone()).map(Value::from).unwrap_or(Value::Null),
+            Field::Marked => Value::from(grades.and_then(|x| x.mark).unwrap_or(false)),
+            Field::Skipped => Value::from(student.status.skipped),
+            Field::Finished => Value::from(student.status.finished),
+        }
+    }
+}
+
+struct Column {
+    header: String,
+    field: Field,
+}
+
+fn column<H: Into<String>>(header: H, field: Field) -> Column {
+    Column { header: header.into(), field }
+}
+
+/// Parse a `field=Header` mapping, an empty header drops the column.
+pub fn parse_mapping(input: &str) -> Result<(String, String), String> {
+    let mut split = input.splitn(2, '=');
+    let field = split.next().unwrap_or("").trim();
+    let header = split.next().ok_or_else(|| format!("expected field=header, found {}", input))?;
+    Field::parse(field)?;
+    Ok((field.to_string(), header.trim().to_string()))
+}
+
+/// The columns of the preset, with the mappings applied in order.
+fn columns(options: &GradebookColumns, max_grade: usize) -> Result<Vec<Column>, String> {
+    let assignment = options.assignment.as_str();
+    let mut columns = match options.preset.as_str() {
+        // the identifying columns Canvas expects, only the SIS user id is filled
+        "canvas" => vec![
+            column("Student", Field::Blank),
+            column("ID", Field::Blank),
+            column("SIS User ID", Field::StudentId),
+            column("SIS Login ID", Field::Blank),
+            column("Section", Field::Blank),
+            column(assignment, Field::Grade),
+        ],
+        "moodle" => vec![
+            column("ID number", Field::StudentId),
+            column(assignment, Field::Grade),
+            column(format!("{} (Feedback)", assignment), Field::Comment),
+        ],
+        "blackboard" => vec![
+            column("Username", Field::StudentId),
+            column(format!("{} [Total Pts: {} Score] |", assignment, max_grade), Field::Grade),
+            column("Feedback to Learner", Field::Comment),
+        ],
+        _ => vec![
+            column("student_id", Field::StudentId),
+            column("grade", Field::Grade),
+            column("comment", Field::Comment),
+            column("marked", Field::Marked),
+            column("skipped", Field::Skipped),
+            column("finished", Field::Finished),
+        ]
+    };
+    for (name, header) in &options.map {
+        let field = Field::parse(name.as_str())?;
+        if header.is_empty() {
+            columns.retain(|x| x.field != field);
+        } else if let Some(column) = columns.iter_mut().find(|x| x.field == field) {
+            column.header = header.clone();
+        } else {
+            columns.push(column(header.as_str(), field));
+        }
+    }
+    Ok(columns)
+}
+
+fn header(columns: &[Column], field: Field) -> Result<&str, String> {
+    columns.iter()
+        .find(|x| x.field == field)
+        .map(|x| x.header.as_str())
+        .ok_or_else(|| format!("the gradebook has no {:?} column, add one with --map", field))
+}
+
+fn text(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(x) => x.clone(),
+        x => x.to_string()
+    }
+}
+
+fn write_csv(path: &Path, columns: &[Column], students: &[StudentDetail]) -> Result<(), String> {
+    let mut writer = csv::Writer::from_path(path).map_err(|x| x.to_string())?;
+    writer.write_record(columns.iter().map(|x| x.header.as_str())).map_err(|x| x.to_string())?;
+    for i in students {
+        writer.write_record(columns.iter().map(|x| text(&x.field.value(i)))).map_err(|x| x.to_string())?;
+    }
+    writer.flush().map_err(|x| x.to_string())
+}
+
+fn write_json(path: &Path, columns: &[Column], students: &[StudentDetail]) -> Result<(), String> {
+    let rows = students.iter()
+        .map(|i| columns.iter()
+            .map(|x| (x.header.clone(), x.field.value(i)))
+            .collect::<serde_json::Map<_, _>>())
+        .collect::<Vec<_>>();
+    let content = serde_json::to_vec_pretty(&rows).map_err(|x| x.to_string())?;
+    std::fs::write(path, content).map_err(|x| x.to_string())
+}
+
+fn write_xlsx(path: &Path, columns: &[Column], students: &[StudentDetail]) -> Result<(), String> {
+    use simple_excel_writer::{Row, Workbook};
+    let mut workbook = Workbook::create(path.to_str().ok_or("the path of the gradebook must be UTF-8")?);
+    let mut sheet = workbook.create_sheet("Grades");
+    workbook.write_sheet(&mut sheet, |writer| {
+        let mut row = Row::new();
+        for i in columns {
+            row.add_cell(i.header.clone());
+        }
+        writer.append_row(row)?;
+        for student in students {
+            let mut row = Row::new();
+            for i in columns {
+                match i.field.value(student) {
+                    Value::Null => row.add_cell(()),
+                    Value::Bool(x) => row.add_cell(x),
+                    Value::Number(x) => row.add_cell(x.as_f64().unwrap_or(0.0)),
+                    x => row.add_cell(text(&x)),
+                }
+            }
+            writer.append_row(row)?;
+        }
+        Ok(())
+    }).map_err(|x| x.to_string())?;
+    workbook.close().map(|_| ()).map_err(|x| x.to_string())
+}
+
+pub fn export(db: &DB, output: &Path, options: &GradebookColumns) {
+    let server = force_get(db, "server");
+    let identity = crate::credential::identity(db);
+    let config = force_get_json::<Config>(db, "config");
+    let columns = columns(options, config.max_grade).exit_on_failure();
+    let mut students = remote_details(server.as_str(), &identity).exit_on_failure();
+    students.sort_by(|a, b| a.student_id.cmp(&b.student_id));
+    let extension = output.extension().and_then(|x| x.to_str()).unwrap_or("").to_ascii_lowercase();
+    match extension.as_str() {
+        "csv" => write_csv(output, columns.as_slice(), students.as_slice()),
+        "json" => write_json(output, columns.as_slice(), students.as_slice()),
+        "xlsx" => write_xlsx(output, columns.as_slice(), students.as_slice()),
+        _ => Err(format!("unknown gradebook type {}, use a .csv, .json or .xlsx file", output.display()))
+    }.exit_on_failure();
+    info!("{} student(s) exported to {}", students.len(), output.display());
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum Action {
+    New,
+    Update,
+    Unchanged,
+    // the student already has a different grade and `--override` is not given
+    Protected,
+    UnknownStudent,
+    Invalid,
+}
+
+#[derive(Debug, Serialize)]
+struct Change {
+    student_id: String,
+    action: Action,
+    current_grade: Option<f64>,
+    grade: Option<f64>,
+    current_comment: Option<String>,
+    comment: Option<String>,
+    detail: String,
+}
+
+fn read_rows(input: &Path, columns: &[Column], max_grade: usize) -> Result<Vec<Change>, String> {
+    let mut reader = csv::Reader::from_path(input).map_err(|x| x.to_string())?;
+    let headers = reader.headers().map_err(|x| x.to_string())?.clone();
+    let position = |field: Field| -> Result<Option<usize>, String> {
+        let name = header(columns, field)?;
+        Ok(headers.iter().position(|x| x.trim() == name))
+    };
+    let id = position(Field::StudentId)?.ok_or_else(|| format!("no {} column in {}", header(columns, Field::StudentId).unwrap(), input.display()))?;
+    let grade = position(Field::Grade)?.ok_or_else(|| format!("no {} column in {}", header(columns, Field::Grade).unwrap(), input.display()))?;
+    let comment = position(Field::Comment).ok().flatten();
+
+    let mut rows = Vec::new();
+    for record in reader.records() {
+        let record = record.map_err(|x| x.to_string())?;
+        let student_id = record.get(id).unwrap_or("").trim().to_string();
+        let value = record.get(grade).unwrap_or("").trim();
+        if student_id.is_empty() || value.is_empty() {
+            continue;
+        }
+        let mut change = Change {
+            student_id,
+            action: Action::Invalid,
+            current_grade: None,
+            grade: None,
+            current_comment: None,
+            comment: comment.and_then(|x| record.get(x)).map(|x| x.trim().to_string()).filter(|x| !x.is_empty()),
+            detail: String::new(),
+        };
+        if let Err(e) = crate::validate::student_id(change.student_id.as_str()) {
+            change.detail = e;
+        } else {
+            match value.parse::<f64>() {
+                Ok(x) if x >= 0.0 && x <= max_grade as f64 + EPSILON => change.grade = Some(x),
+                Ok(x) => change.detail = format!("grade {} is not within 0..={}", x, max_grade),
+                Err(e) => change.detail = format!("invalid grade {}: {}", value, e),
+            }
+        }
+        rows.push(change);
+    }
+    Ok(rows)
+}
+
+fn plan(rows: &mut [Change], remote: &HashMap<String, &StudentDetail>, r#override: bool, max_grade: usize) {
+    for i in rows.iter_mut().filter(|x| x.grade.is_some()) {
+        let student = match remote.get(&i.student_id) {
+            Some(student) => student,
+            None => {
+                i.action = Action::UnknownStudent;
+                i.detail = String::from("not on the server");
+                continue;
+            }
+        };
+        i.current_grade = student.grades.as_ref().and_then(|x| x.graded);
+        i.current_comment = student.grades.as_ref().and_then(|x| x.comment.clone());
+        let same_grade = i.current_grade.map(|x| (x - i.grade.unwrap()).abs() < EPSILON).unwrap_or(false);
+        let same_comment = i.comment.is_none() || i.comment == i.current_comment;
+        i.action = match i.current_grade {
+            None => Action::New,
+            Some(_) if same_grade && same_comment => Action::Unchanged,
+            Some(_) if r#override => Action::Update,
+            Some(_) => {
+                i.detail = String::from("already graded, use --override to replace it");
+                Action::Protected
+            }
+        };
+        // the gradebook holds the total, the recorded adjustments stay on top of the base
+        let adjustments = adjustments(student.grades.as_ref());
+        let base = i.grade.unwrap() - adjustments;
+        if (i.action == Action::New || i.action == Action::Update)
+            && (base < 0.0 || max_grade as f64 + EPSILON < base) {
+            i.action = Action::Invalid;
+            i.detail = format!("the adjustments ({:+}) would need a base grade of {}", adjustments, base);
+        }
+    }
+}
+
+fn adjustments(submission: Option<&crate::status::Submission>) -> f64 {
+    submission.map(|x| x.adjustments.iter().map(|x| x.points).sum()).unwrap_or(0.0)
+}
+
+fn changes_table(rows: &[Change]) {
+    let show = |x: Option<f64>| x.map(|x| x.to_string()).unwrap_or_else(|| String::from("N/A"));
+    let mut table = Table::new();
+    table.add_row(row![bFb->"ID", bFb->"Action", bFb->"Grade", bFb->"Comment", bFb->"Detail"]);
+    for i in rows {
+        let action = match i.action {
+            Action::New => cell!(bFg->"new"),
+            Action::Update => cell!(bFy->"update"),
+            Action::Unchanged => cell!("unchanged"),
+            Action::Protected => cell!(bFr->"protected"),
+            Action::UnknownStudent => cell!(bFr->"unknown"),
+            Action::Invalid => cell!(bFr->"invalid"),
+        };
+        let grade = if i.current_grade.is_some() && i.action != Action::Unchanged {
+            format!("{} -> {}", show(i.current_grade), show(i.grade))
+        } else {
+            show(i.grade)
+        };
+        let comment = match &i.comment {
+            Some(x) if i.comment != i.current_comment => x.clone(),
+            _ => String::new()
+        };
+        table.add_row(Row::new(vec![cell!(i.student_id), action, cell!(grade), cell!(comment), cell!(i.detail)]));
+    }
+    table.printstd();
+}
+
+pub fn import(db: &DB, input: &Path, options: &GradebookColumns, dry_run: bool, r#override: bool) {
+    let server = force_get(db, "server");
+    let identity = crate::credential::identity(db);
+    let config = force_get_json::<Config>(db, "config");
+    let columns = columns(options, config.max_grade).exit_on_failure();
+    let mut rows = read_rows(input, columns.as_slice(), config.max_grade).exit_on_failure();
+    let details = remote_details(server.as_str(), &identity).exit_on_failure();
+    let remote = details.iter().map(|x| (x.student_id.clone(), x)).collect::<HashMap<_, _>>();
+    plan(rows.as_mut_slice(), &remote, r#override, config.max_grade);
+    emit(&rows, || changes_table(rows.as_slice()));
+
+    let uploads = rows.iter().filter(|x| x.action == Action::New || x.action == Action::Update).collect::<Vec<_>>();
+    if rows.iter().any(|x| x.action == Action::Protected) {
+        warn!("some students are already graded and will be left unchanged, use --override to replace their grades");
+    }
+    if dry_run || uploads.is_empty() {
+        info!("{} grade(s) to upload", uploads.len());
+        return;
+    }
+    if !crate::prompt::confirm("gradebook-import", format!("Upload {} grade(s)?", uploads.len()).as_str(), false) {
+        return;
+    }
+
+    let client = crate::http::client();
+    let mut failed = 0;
+    for i in &uploads {
+        // everything recorded stays, only the grade and the comment come from the gradebook
+        let existing = remote.get(&i.student_id).and_then(|x| x.grades.as_ref());
+        let mut submission = existing.cloned().map(Submission::from).unwrap_or_default();
+        submission.base = i.grade.map(|x| x - adjustments(existing));
+        submission.graded = i.grade;
+        if i.comment.is_some() {
+            submission.comment = i.comment.clone();
+        }
+        submission.r#override = i.action == Action::Update;
+        let result = format!("{}/student/{}/grades", server, url_id(i.student_id.as_str()))
+            .parse::<Url>()
+            .map_err(|x| x.to_string())
+            .and_then(|url| client.put(url)
+                .json(&submission)
+                .authenticate(&identity)
+                .dispatch()
+                .and_then(|x| x.error_for_status())
+                .and_then(|x| x.json::<SubmissionResponse>())
+                .map_err(|x| x.to_string()))
+            .and_then(|x| match x.failure {
+                None => Ok(()),
+                Some(e) => Err(e)
+            });
+        if let Err(e) = result {
+            error!("failed to upload the grade of {}: {}", i.student_id, e);
+            failed += 1;
+        }
+    }
+    info!("{} grade(s) uploaded, {} failed", uploads.len() - failed, failed);
+    if failed > 0 {
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::settings::{Adjustment, AdjustmentKind};
+    use crate::status::{StudentStatus, Submission as Recorded};
+
+    use super::*;
+
+    fn options(preset: &str, map: &[&str]) -> GradebookColumns {
+        GradebookColumns {
+            preset: preset.to_string(),
+            assignment: String::from("Lab 1"),
+            map: map.iter().map(|x| parse_mapping(x).unwrap()).collect(),
+        }
+    }
+
+    fn headers(columns: &[Column]) -> Vec<&str> {
+        columns.iter().map(|x| x.header.as_str()).collect()
+    }
+
+    fn rows(csv: &str, max_grade: usize) -> Vec<Change> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("grades.csv");
+        std::fs::write(path.as_path(), csv).unwrap();
+        read_rows(path.as_path(), columns(&options("polya", &[]), max_grade).unwrap().as_slice(), max_grade).unwrap()
+    }
+
+    fn change(student_id: &str, grade: f64, comment: Option<&str>) -> Change {
+        Change {
+            student_id: student_id.to_string(),
+            action: Action::Invalid,
+            current_grade: None,
+            grade: Some(grade),
+            current_comment: None,
+            comment: comment.map(|x| x.to_string()),
+            detail: String::new(),
+        }
+    }
+
+    fn recorded(student_id: &str, graded: f64, bonus: f64) -> StudentDetail {
+        let adjustments = if bonus == 0.0 {
+            Vec::new()
+        } else {
+            vec![Adjustment { kind: AdjustmentKind::Bonus, points: bonus, reason: String::new(), automatic: false }]
+        };
+        StudentDetail {
+            student_id: student_id.to_string(),
+            grades: Some(Recorded { graded: Some(graded), comment: Some(String::from("ok")), adjustments, ..Recorded::default() }),
+            status: StudentStatus { skipped: false, finished: true },
+        }
+    }
+
+    #[test]
+    fn parses_mappings() {
+        assert_eq!(parse_mapping("grade=Points"), Ok((String::from("grade"), String::from("Points"))));
+        assert_eq!(parse_mapping(" comment = A=B "), Ok((String::from("comment"), String::from("A=B"))));
+        assert_eq!(parse_mapping("marked="), Ok((String::from("marked"), String::new())));
+        assert!(parse_mapping("grade").is_err());
+        assert!(parse_mapping("=Points").is_err());
+        assert!(parse_mapping("score=Points").is_err());
+        assert!(parse_mapping("").is_err());
+    }
+
+    #[test]
+    fn mappings_rename_drop_and_add_columns() {
+        let columns = columns(&options("moodle", &["comment=", "grade=Score", "marked=Flag"]), 10).unwrap();
+        assert_eq!(headers(columns.as_slice()), vec!["ID number", "Score", "Flag"]);
+        let columns = super::columns(&options("blackboard", &[]), 10).unwrap();
+        assert_eq!(headers(columns.as_slice())[1], "Lab 1 [Total Pts: 10 Score] |");
+        assert!(header(columns.as_slice(), Field::Marked).is_err());
+    }
+
+    #[test]
+    fn reads_only_valid_grades() {
+        let rows = rows("student_id,grade,comment\n\
+                         a,10,good\n\
+                         b,10.0000001,\n\
+                         c,10.1,\n\
+                         d,-0.5,\n\
+                         e,NaN,\n\
+                         f,inf,\n\
+                         g,ten,\n\
+                         -h,5,\n\
+                         i,,\n\
+                         ,5,\n", 10);
+        let grades = rows.iter().map(|x| (x.student_id.as_str(), x.grade)).collect::<Vec<_>>();
+        assert_eq!(grades, vec![("a", Some(10.0)), ("b", Some(10.0000001)), ("c", None), ("d", None),
+                                ("e", None), ("f", None), ("g", None), ("-h", None)]);
+        assert_eq!(rows[0].comment.as_deref(), Some("good"));
+        assert_eq!(rows[1].comment, None);
+        assert!(rows[2..].iter().all(|x| x.action == Action::Invalid && !x.detail.is_empty()));
+    }
+
+    #[test]
+    fn refuses_a_gradebook_without_the_columns() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("grades.csv");
+        std::fs::write(path.as_path(), "student_id,points\na,1\n").unwrap();
+        let columns = columns(&options("polya", &[]), 10).unwrap();
+        assert!(read_rows(path.as_path(), columns.as_slice(), 10).is_err());
+    }
+
+    #[test]
+    fn plans_the_action_of_every_row() {
+        let students = [recorded("graded", 7.0, 0.0), recorded("same", 8.0, 0.0), StudentDetail {
+            student_id: String::from("new"),
+            grades: None,
+            status: StudentStatus { skipped: false, finished: false },
+        }];
+        let remote = students.iter().map(|x| (x.student_id.clone(), x)).collect::<HashMap<_, _>>();
+        let mut rows = vec![change("new", 5.0, None), change("same", 8.0, Some("ok")), change("same", 8.0, None),
+                            change("graded", 9.0, None), change("same", 8.0, Some("better")), change("missing", 1.0, None)];
+        plan(rows.as_mut_slice(), &remote, false, 10);
+        let actions = rows.iter().map(|x| x.action).collect::<Vec<_>>();
+        assert_eq!(actions, vec![Action::New, Action::Unchanged, Action::Unchanged, Action::Protected,
+                                 Action::Protected, Action::UnknownStudent]);
+        plan(rows.as_mut_slice(), &remote, true, 10);
+        assert_eq!(rows[3].action, Action::Update);
+        assert_eq!(rows[3].current_grade, Some(7.0));
+        assert_eq!(rows[4].action, Action::Update);
+    }
+
+    #[test]
+    fn keeps_the_base_within_range_with_adjustments() {
+        let students = [recorded("bonus", 9.0, 2.0), recorded("penalty", 3.0, -4.0)];
+        let remote = students.iter().map(|x| (x.student_id.clone(), x)).collect::<HashMap<_, _>>();
+        let mut rows = vec![change("bonus", 1.0, None), change("bonus", 2.0, None), change("penalty", 8.0, None),
+                            change("penalty", 5.0, None)];
+        plan(rows.as_mut_slice(), &remote, true, 10);
+        let actions = rows.iter().map(|x| x.action).collect::<Vec<_>>();
+        assert_eq!(actions, vec![Action::Invalid, Action::Update, Action::Invalid, Action::Update]);
+        assert_eq!(adjustments(students[1].grades.as_ref()), -4.0);
+        assert_eq!(adjustments(None), 0.0);
+    }
+}
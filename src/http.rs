@@ -0,0 +1,395 @@
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use log::*;
+use reqwest::{Certificate, Proxy, StatusCode, Url};
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use ring::digest::{digest, SHA256};
+use rocksdb::DB;
+use rustls::{ClientConfig, RootCertStore, ServerCertVerified, ServerCertVerifier, TLSError, WebPKIVerifier};
+use webpki::DNSNameRef;
+
+use crate::cli::HttpOptions;
+use crate::LogUnwrap;
+use crate::settings::HttpSettings;
+
+const KEY: &str = "http";
+const MAX_BACKOFF: u64 = 30_000;
+
+static SETTINGS: Mutex<Option<HttpSettings>> = Mutex::new(None);
+static CLIENT: OnceLock<Client> = OnceLock::new();
+
+fn load(db: &DB) -> HttpSettings {
+    match db.get(KEY) {
+        Ok(Some(mut settings)) => simd_json::serde::from_slice::<HttpSettings>(settings.as_mut_slice())
+            .unwrap_or_else(|e| {
+                warn!("invalid http settings, using the defaults: {}", e);
+                HttpSettings::default()
+            }),
+        _ => HttpSettings::default()
+    }
+}
+
+/// Load the settings of the server, the first database opened by the process wins.
+pub fn init(db: &DB) {
+    let mut settings = SETTINGS.lock().unwrap();
+    if settings.is_none() {
+        *settings = Some(load(db));
+    }
+}
+
+pub fn settings() -> HttpSettings {
+    SETTINGS.lock().unwrap().clone().unwrap_or_default()
+}
+
+impl HttpOptions {
+    pub fn is_empty(&self) -> bool {
+        self.ca_bundle.is_none() && self.pin.is_empty() && self.proxy.is_none() && self.connect_timeout.is_none()
+            && self.timeout.is_none() && self.retries.is_none() && self.backoff.is_none()
+    }
+
+    fn apply(&self, settings: &mut HttpSettings) {
+        if let Some(path) = &self.ca_bundle {
+            settings.ca_bundle = Some(path.canonicalize().exit_on_failure());
+        }
+        if !self.pin.is_empty() {
+            settings.pins = self.pin.iter().map(|x| x.trim_start_matches("sha256//").to_string()).collect();
+        }
+        if let Some(proxy) = &self.proxy {
+            settings.proxy = Some(proxy.clone());
+        }
+        if let Some(x) = self.connect_timeout {
+            settings.connect_timeout = Some(x);
+        }
+        if let Some(x) = self.timeout {
+            settings.timeout = Some(x);
+        }
+        if let Some(x) = self.retries {
+            settings.retries = x;
+        }
+        if let Some(x) = self.backoff {
+            settings.backoff = x;
+        }
+    }
+}
+
+/// Change and save the settings, requests of this process made afterwards use them.
+pub fn configure(db: &DB, options: &HttpOptions, reset: bool) -> HttpSettings {
+    let mut settings = if reset { HttpSettings::default() } else { load(db) };
+    options.apply(&mut settings);
+    if let Err(e) = build(&settings) {
+        error!("invalid http settings: {}", e);
+        std::process::exit(1);
+    }
+    db.put(KEY, serde_json::to_vec(&settings).exit_on_failure()).exit_on_failure();
+    if CLIENT.get().is_some() {
+        warn!("http settings changed after the first request, they apply from the next command");
+    }
+    *SETTINGS.lock().unwrap() = Some(settings.clone());
+    settings
+}
+
+/// A bundle holds several certificates, each of them is parsed on its own.
+fn certificates(path: &Path) -> Result<Vec<Certificate>, String> {
+    let text = std::fs::read_to_string(path).map_err(|x| format!("unable to read {}: {}", path.display(), x))?;
+    let certificates = text.split_inclusive("-----END CERTIFICATE-----")
+        .filter(|x| x.contains("-----BEGIN CERTIFICATE-----"))
+        .map(|x| Certificate::from_pem(x.trim().as_bytes()).map_err(|x| x.to_string()))
+        .collect::<Result<Vec<_>, _>>()?;
+    if certificates.is_empty() {
+        return Err(format!("no certificate in {}", path.display()));
+    }
+    Ok(certificates)
+}
+
+/// The first DER element of some input.
+struct Element<'a> {
+    tag: u8,
+    whole: &'a [u8],
+    content: &'a [u8],
+    rest: &'a [u8],
+}
+
+fn element(input: &[u8]) -> Result<Element<'_>, String> {
+    let invalid = || String::from("invalid DER in the certificate");
+    let tag = *input.first().ok_or_else(invalid)?;
+    let first = *input.get(1).ok_or_else(invalid)? as usize;
+    let (header, length) = match first {
+        x if x < 0x80 => (2, x),
+        // long form, more than 4 length bytes would not fit a certificate anyway
+        x if (0x81..=0x84).contains(&x) => {
+            let bytes = input.get(2..2 + (x & 0x7f)).ok_or_else(invalid)?;
+            (2 + bytes.len(), bytes.iter().fold(0usize, |length, byte| length << 8 | *byte as usize))
+        }
+        _ => return Err(invalid())
+    };
+    let end = header.checked_add(length).filter(|x| *x <= input.len()).ok_or_else(invalid)?;
+    Ok(Element { tag, whole: &input[..end], content: &input[header..end], rest: &input[end..] })
+}
+
+/// The `subjectPublicKeyInfo` of a DER certificate, as it is encoded in the certificate.
+fn subject_public_key_info(certificate: &[u8]) -> Result<&[u8], String> {
+    const SEQUENCE: u8 = 0x30;
+    const VERSION: u8 = 0xa0;
+    let sequence = |input| match element(input)? {
+        x if x.tag == SEQUENCE => Ok(x),
+        _ => Err(String::from("unexpected DER element in the certificate"))
+    };
+    let certificate = sequence(certificate)?;
+    let mut rest = sequence(certificate.content)?.content;
+    if rest.first() == Some(&VERSION) {
+        rest = element(rest)?.rest;
+    }
+    // serial number, signature algorithm, issuer, validity and subject come first
+    for _ in 0..5 {
+        rest = element(rest)?.rest;
+    }
+    sequence(rest).map(|x| x.whole)
+}
+
+/// Base64 SHA-256 digest of the subject public key info of a DER certificate.
+fn fingerprint(certificate: &[u8]) -> Result<String, String> {
+    let spki = subject_public_key_info(certificate)?;
+    Ok(base64::encode(digest(&SHA256, spki).as_ref()))
+}
+
+/// Checks the chain as usual, then the public key of the server against the pins, during every handshake.
+struct Pinned {
+    pins: Vec<String>,
+    chain: WebPKIVerifier,
+}
+
+impl ServerCertVerifier for Pinned {
+    fn verify_server_cert(&self, roots: &RootCertStore, presented: &[rustls::Certificate], dns_name: DNSNameRef,
+                          ocsp_response: &[u8]) -> Result<ServerCertVerified, TLSError> {
+        let verified = self.chain.verify_server_cert(roots, presented, dns_name, ocsp_response)?;
+        let certificate = presented.first().ok_or(TLSError::NoCertificatesPresented)?;
+        let fingerprint = fingerprint(certificate.0.as_slice()).map_err(TLSError::General)?;
+        if !self.pins.contains(&fingerprint) {
+            let host: &str = dns_name.into();
+            return Err(TLSError::General(format!("the public key of {} (sha256//{}) matches none of the pins", host, fingerprint)));
+        }
+        Ok(verified)
+    }
+}
+
+/// The TLS configuration of a client with pins, the same roots as without them.
+fn pinned(settings: &HttpSettings) -> Result<ClientConfig, String> {
+    let mut tls = ClientConfig::new();
+    tls.root_store = match rustls_native_certs::load_native_certs() {
+        Ok(roots) => roots,
+        Err((Some(roots), e)) => {
+            warn!("some system certificates could not be loaded: {}", e);
+            roots
+        }
+        Err((None, e)) => return Err(format!("unable to load the system certificates: {}", e))
+    };
+    if let Some(path) = &settings.ca_bundle {
+        let file = std::fs::File::open(path).map_err(|x| format!("unable to read {}: {}", path.display(), x))?;
+        tls.root_store.add_pem_file(&mut BufReader::new(file))
+            .map_err(|_| format!("invalid certificate in {}", path.display()))?;
+    }
+    tls.set_protocols(&[b"http/1.1".to_vec()]);
+    tls.dangerous().set_certificate_verifier(Arc::new(Pinned { pins: settings.pins.clone(), chain: WebPKIVerifier::new() }));
+    Ok(tls)
+}
+
+fn build(settings: &HttpSettings) -> Result<Client, String> {
+    let mut builder = Client::builder();
+    if !settings.pins.is_empty() {
+        builder = builder.use_preconfigured_tls(pinned(settings)?);
+    } else if let Some(path) = &settings.ca_bundle {
+        for i in certificates(path.as_path())? {
+            builder = builder.add_root_certificate(i);
+        }
+    }
+    if let Some(proxy) = &settings.proxy {
+        builder = builder.proxy(Proxy::all(proxy.as_str()).map_err(|x| x.to_string())?);
+    }
+    if let Some(x) = settings.connect_timeout {
+        builder = builder.connect_timeout(Duration::from_secs(x));
+    }
+    if let Some(x) = settings.timeout {
+        builder = builder.timeout(Duration::from_secs(x));
+    }
+    builder.build().map_err(|x| x.to_string())
+}
+
+/// The client of every request to the server, built once from the settings.
+pub fn client() -> &'static Client {
+    CLIENT.get_or_init(|| build(&settings()).exit_on_failure())
+}
+
+/// Refuse wget and aria2c when pins are set, only the client of tulip checks them.
+pub fn check_downloader(backend: &str) {
+    if backend != "builtin" && !settings().pins.is_empty() {
+        error!("{} cannot check the pinned public keys, download with `-b builtin` instead", backend);
+        std::process::exit(1);
+    }
+}
+
+/// The same settings as the arguments of the downloader.
+pub fn downloader_args(backend: &str) -> Vec<String> {
+    let settings = settings();
+    let mut args = Vec::new();
+    match backend {
+        "wget" => {
+            if let Some(path) = &settings.ca_bundle {
+                args.push(format!("--ca-certificate={}", path.display()));
+            }
+            if let Some(proxy) = &settings.proxy {
+                args.extend(["-e", "use_proxy=yes", "-e"].iter().map(|x| x.to_string()));
+                args.push(format!("http_proxy={}", proxy));
+                args.push(String::from("-e"));
+                args.push(format!("https_proxy={}", proxy));
+            }
+            if let Some(x) = settings.connect_timeout {
+                args.push(format!("--connect-timeout={}", x));
+            }
+            args.push(format!("--tries={}", settings.retries + 1));
+            args.push(format!("--waitretry={}", (settings.backoff / 1000).max(1)));
+        }
+        "aria2c" => {
+            if let Some(path) = &settings.ca_bundle {
+                args.push(format!("--ca-certificate={}", path.display()));
+            }
+            if let Some(proxy) = &settings.proxy {
+                args.push(format!("--all-proxy={}", proxy));
+            }
+            if let Some(x) = settings.connect_timeout {
+                args.push(format!("--connect-timeout={}", x));
+            }
+            args.push(format!("--max-tries={}", settings.retries + 1));
+            args.push(format!("--retry-wait={}", (settings.backoff / 1000).max(1)));
+        }
+        _ => unreachable!()
+    }
+    args
+}
+
+fn transient(result: &reqwest::Result<Response>) -> bool {
+    match result {
+        Ok(response) => response.status().is_server_error()
+            || response.status() == StatusCode::TOO_MANY_REQUESTS
+            || response.status() == StatusCode::REQUEST_TIMEOUT,
+        Err(e) => e.is_timeout() || e.is_connect()
+    }
+}
+
+/// Pins only hold over https, refuse to send anything else.
+fn check_scheme(url: &Url) {
+    if url.scheme() != "https" && !settings().pins.is_empty() {
+        error!("certificate pinning needs an https server, not {}", url);
+        std::process::exit(1);
+    }
+}
+
+pub trait Dispatch {
+    /// Send the request once.
+    fn dispatch(self) -> reqwest::Result<Response>;
+    /// Send a request which can safely be sent again, retrying it with exponential backoff.
+    fn dispatch_idempotent(self) -> reqwest::Result<Response>;
+}
+
+impl Dispatch for RequestBuilder {
+    fn dispatch(self) -> reqwest::Result<Response> {
+        if let Some(Ok(request)) = self.try_clone().map(|x| x.build()) {
+            check_scheme(request.url());
+        }
+        self.send()
+    }
+
+    fn dispatch_idempotent(self) -> reqwest::Result<Response> {
+        let request = match self.try_clone().map(|x| x.build()) {
+            Some(Ok(request)) => request,
+            // let reqwest report the error, a streamed body is sent once
+            _ => return self.send()
+        };
+        check_scheme(request.url());
+        let settings = settings();
+        let mut delay = settings.backoff;
+        for attempt in 1..=settings.retries {
+            let result = match self.try_clone() {
+                Some(x) => x.send(),
+                None => break
+            };
+            if !transient(&result) {
+                return result;
+            }
+            let reason = match &result {
+                Ok(response) => response.status().to_string(),
+                Err(e) => e.to_string()
+            };
+            warn!("{} {} failed with {}, retrying in {} ms ({}/{})",
+                  request.method(), request.url(), reason, delay, attempt, settings.retries);
+            std::thread::sleep(Duration::from_millis(delay));
+            delay = (delay * 2).min(MAX_BACKOFF);
+        }
+        self.send()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+        let mut result = vec![tag];
+        match content.len() {
+            x if x < 0x80 => result.push(x as u8),
+            x if x < 0x100 => result.extend_from_slice(&[0x81, x as u8]),
+            x => result.extend_from_slice(&[0x82, (x >> 8) as u8, x as u8]),
+        }
+        result.extend_from_slice(content);
+        result
+    }
+
+    fn certificate(version: bool, spki: &[u8]) -> Vec<u8> {
+        let name = tlv(0x30, &tlv(0x31, &tlv(0x30, b"common name")));
+        let mut tbs = Vec::new();
+        if version {
+            tbs.extend(tlv(0xa0, &tlv(0x02, &[2])));
+        }
+        tbs.extend(tlv(0x02, &[0x01, 0x02, 0x03]));
+        tbs.extend(tlv(0x30, &tlv(0x06, &[0x2a, 0x86, 0x48])));
+        tbs.extend(name.clone());
+        tbs.extend(tlv(0x30, &[tlv(0x17, b"200101000000Z"), tlv(0x17, b"300101000000Z")].concat()));
+        tbs.extend(name);
+        tbs.extend_from_slice(spki);
+        tbs.extend(tlv(0xa3, &tlv(0x30, b"extensions")));
+        tlv(0x30, &[tlv(0x30, &tbs), tlv(0x30, &tlv(0x06, &[0x2a])), tlv(0x03, &[0; 64])].concat())
+    }
+
+    fn spki() -> Vec<u8> {
+        tlv(0x30, &[tlv(0x30, &tlv(0x06, &[0x2a, 0x86, 0x48, 0xce, 0x3d])), tlv(0x03, &[0x04; 300])].concat())
+    }
+
+    #[test]
+    fn finds_the_public_key_with_and_without_a_version() {
+        let spki = spki();
+        for version in &[true, false] {
+            let certificate = certificate(*version, spki.as_slice());
+            assert_eq!(subject_public_key_info(certificate.as_slice()).unwrap(), spki.as_slice());
+        }
+    }
+
+    #[test]
+    fn fingerprints_the_public_key_only() {
+        let spki = spki();
+        let expected = base64::encode(digest(&SHA256, spki.as_slice()).as_ref());
+        assert_eq!(fingerprint(certificate(true, spki.as_slice()).as_slice()).unwrap(), expected);
+        assert_eq!(fingerprint(certificate(false, spki.as_slice()).as_slice()).unwrap(), expected);
+    }
+
+    #[test]
+    fn rejects_an_invalid_certificate() {
+        let certificate = certificate(true, spki().as_slice());
+        assert!(fingerprint(&certificate[..certificate.len() - 1]).is_err());
+        assert!(fingerprint(&certificate[..40]).is_err());
+        assert!(fingerprint(&[]).is_err());
+        assert!(fingerprint(&[0x30, 0x85, 0, 0, 0, 0, 1]).is_err());
+        assert!(fingerprint(tlv(0x31, &certificate).as_slice()).is_err());
+    }
+}
@@ -0,0 +1,248 @@
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use log::*;
+use prettytable::*;
+use rocksdb::DB;
+use serde::*;
+
+use crate::{force_get_json, LogUnwrap};
+use crate::settings::{Notable, NotableKind, Status};
+
+pub const BUILD_OUTPUTS: &[&str] = &["target", "build", "bin", "obj", "dist", "out", "node_modules", "__pycache__"];
+pub const VCS: &[&str] = &[".git", ".svn", ".hg", ".bzr"];
+const OBJECTS: &[&str] = &["o", "obj", "a", "so", "dll", "lib", "exe", "class", "pyc", "jar"];
+
+fn language(path: &Path) -> Option<&'static str> {
+    let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+    let lang = match ext.as_str() {
+        "c" | "h" => "C",
+        "cc" | "cpp" | "cxx" | "hpp" | "hh" | "hxx" => "C++",
+        "rs" => "Rust",
+        "py" => "Python",
+        "java" => "Java",
+        "kt" => "Kotlin",
+        "go" => "Go",
+        "js" | "mjs" => "JavaScript",
+        "ts" => "TypeScript",
+        "hs" => "Haskell",
+        "ml" | "mli" => "OCaml",
+        "scala" => "Scala",
+        "rb" => "Ruby",
+        "sh" | "bash" => "Shell",
+        "s" | "asm" => "Assembly",
+        "md" | "txt" | "tex" => "Text",
+        _ => return None
+    };
+    Some(lang)
+}
+
+#[derive(Default)]
+struct Summary {
+    files: Vec<(PathBuf, u64)>,
+    languages: BTreeMap<&'static str, (usize, usize)>,
+    notable: Vec<Notable>,
+}
+
+#[derive(Serialize)]
+struct FileShow<'a> {
+    path: &'a Path,
+    size: u64,
+}
+
+#[derive(Serialize)]
+struct LanguageShow {
+    language: &'static str,
+    files: usize,
+    lines: usize,
+}
+
+#[derive(Serialize)]
+struct InspectShow<'a> {
+    files: Vec<FileShow<'a>>,
+    languages: Vec<LanguageShow>,
+    notable: &'a [Notable],
+}
+
+fn notable(summary: &mut Summary, kind: NotableKind, path: &Path, detail: String) {
+    summary.notable.push(Notable { kind, path: path.to_path_buf(), detail });
+}
+
+pub(crate) fn head(path: &Path) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    if let Ok(file) = std::fs::File::open(path) {
+        if let Err(e) = file.take(8192).read_to_end(&mut buffer) {
+            warn!("unable to read {}: {}", path.display(), e);
+        }
+    }
+    buffer
+}
+
+fn inspect_file(summary: &mut Summary, path: &Path, relative: &Path, size: u64, large: u64) {
+    summary.files.push((relative.to_path_buf(), size));
+    if size > large {
+        notable(summary, NotableKind::Oversized, relative, format!("{} KiB", size / 1024));
+    }
+    let head = head(path);
+    if head.starts_with(b"\x7fELF") {
+        notable(summary, NotableKind::Binary, relative, String::from("ELF executable or library"));
+        return;
+    }
+    if head.starts_with(b"MZ") {
+        notable(summary, NotableKind::Binary, relative, String::from("PE executable"));
+        return;
+    }
+    let ext = relative.extension().and_then(|x| x.to_str()).map(|x| x.to_ascii_lowercase());
+    if ext.as_ref().map(|x| OBJECTS.contains(&x.as_str())).unwrap_or(false) {
+        notable(summary, NotableKind::Object, relative, String::from("compiled object"));
+        return;
+    }
+    if head.contains(&0) {
+        return;
+    }
+    // a cut in the middle of a multi-byte character is not an error
+    if let Err(e) = std::str::from_utf8(head.as_slice()) {
+        if e.error_len().is_some() {
+            notable(summary, NotableKind::NonUtf8, relative, format!("invalid UTF-8 at byte {}", e.valid_up_to()));
+        }
+    }
+    if let Some(lang) = language(relative) {
+        let lines = std::fs::read(path)
+            .map(|x| x.iter().filter(|x| **x == b'\n').count())
+            .unwrap_or(0);
+        let entry = summary.languages.entry(lang).or_insert((0, 0));
+        entry.0 += 1;
+        entry.1 += lines;
+    }
+}
+
+fn walk(summary: &mut Summary, dir: &Path, relative: &Path, large: u64) {
+    let mut entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(|x| x.ok()).collect::<Vec<_>>(),
+        Err(e) => {
+            warn!("unable to read {}: {}", dir.display(), e);
+            return;
+        }
+    };
+    entries.sort_by_key(|x| x.file_name());
+    for entry in entries {
+        let name = entry.file_name();
+        let name_str = name.to_string_lossy();
+        let path = entry.path();
+        let relative = relative.join(&name);
+        let meta = match std::fs::symlink_metadata(path.as_path()) {
+            Ok(meta) => meta,
+            Err(_) => continue
+        };
+        if meta.is_dir() {
+            if VCS.contains(&name_str.as_ref()) {
+                notable(summary, NotableKind::Vcs, relative.as_path(), String::from("version control metadata"));
+                continue;
+            }
+            if BUILD_OUTPUTS.contains(&name_str.as_ref()) || name_str.starts_with("cmake-build-") {
+                notable(summary, NotableKind::BuildOutput, relative.as_path(), String::from("committed build output"));
+            }
+            walk(summary, path.as_path(), relative.as_path(), large);
+        } else if meta.is_file() {
+            inspect_file(summary, path.as_path(), relative.as_path(), meta.len(), large);
+        }
+    }
+}
+
+fn check_script(summary: &mut Summary, student_dir: &Path, script: &Path, name: &str) {
+    let meta = match crate::validate::contained(student_dir, script) {
+        Ok(path) => std::fs::metadata(path).ok(),
+        // it exists, so the link leads out of the project
+        Err(e) if student_dir.join(script).exists() => {
+            notable(summary, NotableKind::OutsideProject, script, e);
+            return;
+        }
+        Err(_) => None
+    };
+    match meta {
+        Some(meta) if meta.is_file() => {
+            if meta.permissions().mode() & 0o111 == 0 {
+                notable(summary, NotableKind::NotExecutable, script, format!("{} is not executable", name));
+            }
+        }
+        _ => notable(summary, NotableKind::MissingScript, script, format!("{} is missing", name)),
+    }
+}
+
+fn tree_table(files: &[(PathBuf, u64)], depth: usize) -> Table {
+    let mut sizes = BTreeMap::<PathBuf, u64>::new();
+    for (path, size) in files {
+        let mut prefix = PathBuf::new();
+        for component in path.components().take(depth) {
+            prefix.push(component);
+            *sizes.entry(prefix.clone()).or_insert(0) += size;
+        }
+    }
+    let mut table = Table::new();
+    table.add_row(row![bFb->"Path", bFb->"Size"]);
+    for (path, size) in sizes {
+        let indent = "  ".repeat(path.components().count() - 1);
+        let name = path.file_name().map(|x| x.to_string_lossy().to_string()).unwrap_or_default();
+        table.add_row(row![format!("{}{}", indent, name), format!("{} KiB", size.div_ceil(1024))]);
+    }
+    table
+}
+
+fn language_table(languages: &BTreeMap<&'static str, (usize, usize)>) -> Table {
+    let mut table = Table::new();
+    table.add_row(row![bFb->"Language", bFb->"Files", bFb->"Lines"]);
+    for (lang, (files, lines)) in languages {
+        table.add_row(row![lang, files, lines]);
+    }
+    table
+}
+
+pub fn notable_table(notable: &[Notable]) -> Table {
+    let mut table = Table::new();
+    table.add_row(row![bFb->"Kind", bFb->"Path", bFb->"Detail"]);
+    for i in notable {
+        table.add_row(row![bFy->format!("{:?}", i.kind), i.path.display(), i.detail]);
+    }
+    table
+}
+
+pub fn handle(db: &DB, workdir: &Path, depth: usize, large: u64) {
+    let mut status = force_get_json::<Status>(db, "status");
+    let project = match status.in_progress.as_ref() {
+        Some(project) => project,
+        None => {
+            error!("no current project");
+            std::process::exit(1);
+        }
+    };
+    let student_dir = workdir.join("student");
+    let mut summary = Summary::default();
+    walk(&mut summary, student_dir.as_path(), Path::new(""), large * 1024);
+    check_script(&mut summary, student_dir.as_path(), project.build_shell.as_path(), "build script");
+    check_script(&mut summary, student_dir.as_path(), project.run_shell.as_path(), "run script");
+
+    let total: u64 = summary.files.iter().map(|x| x.1).sum();
+    info!("{} file(s), {} KiB in total", summary.files.len(), total.div_ceil(1024));
+    let show = InspectShow {
+        files: summary.files.iter().map(|(path, size)| FileShow { path, size: *size }).collect(),
+        languages: summary.languages.iter()
+            .map(|(language, (files, lines))| LanguageShow { language, files: *files, lines: *lines })
+            .collect(),
+        notable: summary.notable.as_slice(),
+    };
+    crate::output::emit(&show, || {
+        tree_table(&summary.files, depth).printstd();
+        language_table(&summary.languages).printstd();
+        if !summary.notable.is_empty() {
+            notable_table(&summary.notable).printstd();
+        }
+    });
+    if summary.notable.is_empty() {
+        info!("nothing notable found");
+    }
+
+    status.notable = summary.notable;
+    db.put("status", serde_json::to_string(&status).exit_on_failure()).exit_on_failure();
+}
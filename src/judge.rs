@@ -0,0 +1,149 @@
+use std::fs::File;
+use std::process::Stdio;
+
+use log::*;
+use prettytable::*;
+use rocksdb::DB;
+use serde::*;
+
+use crate::{force_get_json, LogUnwrap};
+use crate::compare::compare;
+use crate::scoring::{suggest, suggestion_table};
+use crate::run::{build_firejail, capture};
+use crate::settings::{Config, Status, SuggestedGrade, TestResult, Verdict};
+use crate::verdict::Probe;
+
+#[derive(Serialize)]
+struct TestShow<'a> {
+    results: &'a [TestResult],
+    suggested: Option<&'a SuggestedGrade>,
+}
+
+pub fn handle(db: &DB, without_build: bool, case: Option<String>) {
+    let config = force_get_json::<Config>(db, "config");
+
+    let mut status = force_get_json::<Status>(db, "status");
+
+    if status.in_progress.is_none() {
+        error!("no current project");
+        std::process::exit(1);
+    }
+
+    if status.mount.is_none() {
+        error!("please init overlay layer first");
+        std::process::exit(1);
+    }
+
+    if !status.built && !without_build {
+        error!("please build the project first");
+        std::process::exit(1);
+    }
+
+    if config.test_cases.is_empty() {
+        error!("no test case configured");
+        std::process::exit(1);
+    }
+
+    let cases = config.test_cases.iter()
+        .filter(|x| case.as_ref().map(|y| y == &x.name).unwrap_or(true))
+        .collect::<Vec<_>>();
+
+    if cases.is_empty() {
+        error!("no such test case: {}", case.unwrap_or_default());
+        std::process::exit(1);
+    }
+
+    let student = status.in_progress.as_ref().unwrap();
+    let mount_point = status.mount.as_ref().unwrap();
+    let shell = config.firejail.shell.as_deref().unwrap_or("/bin/sh");
+
+    let mut results = Vec::new();
+
+    for case in cases {
+        info!("running test case {}", case.name);
+        let mut command = build_firejail(mount_point, &config, true);
+        match &case.input {
+            Some(input) => command.stdin(File::open(mount_point.join(input)).exit_on_failure()),
+            None => command.stdin(Stdio::null()),
+        };
+        let probe = Probe::start(&mut command, &config);
+        let mut child = command.arg(shell)
+            .arg(format!("/data/{}", student.run_shell.display()))
+            .stderr(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .exit_on_failure();
+        let captured = capture(&mut child, config.output_limit, false);
+        let exit = child.wait().exit_on_failure();
+        let expected = std::fs::read_to_string(mount_point.join(case.output.as_path())).exit_on_failure();
+        let actual = String::from_utf8_lossy(captured.stdout.as_slice());
+        let stderr = String::from_utf8_lossy(captured.stderr.as_slice());
+        let verdict = probe.classify(&config, &exit, captured.exceeded, &stderr);
+        let result = if verdict == Verdict::Accepted {
+            match compare(&config, mount_point, case, expected.as_str(), &actual) {
+                Ok(outcome) => TestResult {
+                    name: case.name.clone(),
+                    verdict: if outcome.passed { Verdict::Accepted } else { Verdict::WrongAnswer },
+                    passed: outcome.passed,
+                    score: outcome.score,
+                    message: outcome.message,
+                },
+                Err(reason) => TestResult {
+                    name: case.name.clone(),
+                    verdict: Verdict::SandboxError { reason },
+                    passed: false,
+                    score: 0.0,
+                    message: None,
+                }
+            }
+        } else {
+            TestResult {
+                name: case.name.clone(),
+                verdict,
+                passed: false,
+                score: 0.0,
+                message: Some(format!("exited with {}", exit)),
+            }
+        };
+        debug!("test result: {:#?}", result);
+        results.push(result);
+    }
+
+    if config.firejail.has_x {
+        info!("ban connections to xhost");
+        std::process::Command::new("xhost").arg("-")
+            .spawn()
+            .exit_on_failure()
+            .wait()
+            .exit_on_failure();
+    }
+
+    for result in &results {
+        status.test_results.retain(|x| x.name != result.name);
+        status.test_results.push(result.clone());
+    }
+
+    status.suggested = suggest(&config, &status);
+    let show = TestShow { results: results.as_slice(), suggested: status.suggested.as_ref() };
+    crate::output::emit(&show, || {
+        results_table(&results).printstd();
+        if let Some(suggested) = &status.suggested {
+            suggestion_table(suggested).printstd();
+        }
+    });
+    db.put("status", serde_json::to_vec(&status).exit_on_failure()).exit_on_failure();
+}
+
+pub fn results_table(results: &[TestResult]) -> Table {
+    let mut table = Table::new();
+    table.add_row(row![bFb->"Case", bFb->"Verdict", bFb->"Score", bFb->"Message"]);
+    for i in results {
+        table.add_row(Row::new(vec![
+            Cell::new(i.name.as_str()),
+            i.verdict.cell(),
+            Cell::new(i.score.to_string().as_str()),
+            Cell::new(i.message.as_deref().unwrap_or("")),
+        ]));
+    }
+    table
+}
@@ -0,0 +1,91 @@
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+
+use log::*;
+use prettytable::*;
+
+use crate::settings::{LintFinding, LintSeverity, Status};
+
+impl Display for LintSeverity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LintSeverity::Error => write!(f, "error"),
+            LintSeverity::Warning => write!(f, "warning"),
+            LintSeverity::Info => write!(f, "info"),
+            LintSeverity::Style => write!(f, "style"),
+        }
+    }
+}
+
+/// Run shellcheck with json output on the given scripts, paths are relative to the student dir.
+/// Returns `None` if shellcheck itself failed.
+pub fn shellcheck(shellcheck: &Path, student_dir: &Path, scripts: &[&Path]) -> Option<Vec<LintFinding>> {
+    let output = std::process::Command::new(shellcheck)
+        .current_dir(student_dir)
+        .arg("-f")
+        .arg("json")
+        .args(scripts)
+        .output()
+        .map_err(|x| warn!("failed to start shellcheck: {}", x))
+        .ok()?;
+    // 0 means clean, 1 means there are findings, others are failures of shellcheck itself
+    match output.status.code() {
+        Some(0) | Some(1) => (),
+        _ => {
+            warn!("shellcheck failed with {}: {}", output.status, String::from_utf8_lossy(output.stderr.as_slice()).trim());
+            return None;
+        }
+    }
+    let mut stdout = output.stdout;
+    simd_json::serde::from_slice::<Vec<LintFinding>>(stdout.as_mut_slice())
+        .map_err(|x| warn!("unable to parse shellcheck output: {}", x))
+        .ok()
+}
+
+/// Check the build and run scripts of the current project and record the findings.
+pub fn check(status: &mut Status, shellcheck_bin: &Path, workdir: &Path) {
+    let project = match status.in_progress.as_ref() {
+        Some(project) => project,
+        None => return
+    };
+    info!("shellchecking build and run scripts");
+    let student_dir = workdir.join("student");
+    for script in &[project.build_shell.as_path(), project.run_shell.as_path()] {
+        if let Err(e) = crate::validate::contained(student_dir.as_path(), script) {
+            warn!("not running shellcheck: {}", e);
+            status.lint = None;
+            return;
+        }
+    }
+    let scripts = [project.build_shell.as_path(), project.run_shell.as_path()];
+    status.lint = shellcheck(shellcheck_bin, student_dir.as_path(), &scripts);
+    match &status.lint {
+        Some(findings) if findings.is_empty() => info!("shellcheck found nothing"),
+        Some(findings) => warn!("shellcheck found {} problem(s), see `tulip status current`", findings.len()),
+        None => ()
+    }
+}
+
+pub fn lint_table(findings: &[LintFinding]) -> Table {
+    let mut table = Table::new();
+    for severity in &[LintSeverity::Error, LintSeverity::Warning, LintSeverity::Info, LintSeverity::Style] {
+        let group = findings.iter().filter(|x| &x.level == severity).collect::<Vec<_>>();
+        if group.is_empty() {
+            continue;
+        }
+        let mut inner = Table::new();
+        for i in group {
+            inner.add_row(row![format!("{}:{}:{}", i.file, i.line, i.column), format!("SC{}", i.code), i.message]);
+        }
+        let name = format!("{} ({})", severity, findings.iter().filter(|x| &x.level == severity).count());
+        match severity {
+            LintSeverity::Error => table.add_row(row![bFr->name, inner]),
+            LintSeverity::Warning => table.add_row(row![bFy->name, inner]),
+            _ => table.add_row(row![bFb->name, inner]),
+        };
+    }
+    if findings.is_empty() {
+        table.add_row(row![bFg->"clean"]);
+    }
+    table
+}
@@ -20,6 +20,31 @@ mod status;
 mod pull_image;
 mod build;
 mod run;
+mod compare;
+mod judge;
+mod verdict;
+mod scoring;
+mod rubric;
+mod grade;
+mod batch;
+mod prompt;
+mod pipeline;
+mod recover;
+mod doctor;
+mod lint;
+mod risk;
+mod inspect;
+mod validate;
+mod normalize;
+mod similarity;
+mod cache;
+mod diff;
+mod dashboard;
+mod output;
+mod remote;
+mod gradebook;
+mod credential;
+mod http;
 
 #[global_allocator]
 static ALLOC: MiMalloc = MiMalloc;
@@ -57,6 +82,7 @@ fn init_db(path: &Path) -> DB {
         std::process::exit(2);
     });
     debug!("database initialized");
+    http::init(&db);
     db
 }
 
@@ -107,13 +133,24 @@ fn main() {
     let opt: Opt = Opt::from_args();
     std::env::set_var("TULIP_LOG_LEVEL", opt.log_level.as_str());
     pretty_env_logger::init_custom_env("TULIP_LOG_LEVEL");
+    credential::init();
     debug!("tulip invoked with {:#?}", opt);
+    prompt::init(opt.yes, opt.no, opt.answers.as_deref());
+    output::init(opt.format);
+
+    let skip_check = matches!(opt.command, SubCommand::Register { .. } | SubCommand::CleanAll { .. }
+        | SubCommand::Recover { .. } | SubCommand::Batch { .. } | SubCommand::Doctor { .. }
+        | SubCommand::Cache { .. });
+    if !skip_check && opt.tulip_dir.join("meta").exists() {
+        recover::startup_check(&init_db(opt.tulip_dir.join("meta").as_path()), opt.tulip_dir.as_path());
+    }
 
     match opt.command {
-        SubCommand::Register { server, token, force } => {
+        SubCommand::Register { server, token, token_file, store, http, force } => {
             create_workdir(opt.tulip_dir.as_path());
             let db = opt.tulip_dir.join("meta");
-            register::handle(opt.tulip_dir.as_path(), server.as_str(), token.as_str(), &init_db(db.as_path()), force);
+            let token = register::token(token, token_file.as_deref());
+            register::handle(opt.tulip_dir.as_path(), server.as_str(), &token, store, &http, &init_db(db.as_path()), force);
         }
         SubCommand::CleanAll { force, keep_image } => {
             must_sudo();
@@ -142,8 +179,8 @@ fn main() {
         SubCommand::RefreshConfig => {
             let db = init_db(opt.tulip_dir.join("meta").as_path());
             let server = force_get(&db, "server");
-            let uuid = force_get(&db, "uuid");
-            pull_image::refresh_config(server.as_str(), uuid.as_str(), &db);
+            let identity = crate::credential::identity(&db);
+            pull_image::refresh_config(server.as_str(), &identity, &db);
         }
         SubCommand::InitOverlay { print_result, shell, mount_dir, tmp_size, force } => {
             let db = init_db(opt.tulip_dir.join("meta").as_path());
@@ -153,33 +190,84 @@ fn main() {
             let db = init_db(opt.tulip_dir.join("meta").as_path());
             overlay::handle_destroy(&db, opt.tulip_dir.as_path());
         }
-        SubCommand::Fetch { backend, download_only, shellcheck } => {
+        SubCommand::Doctor { backend, shellcheck, editor, reader, mount_dir } => {
+            let doctor = doctor::Doctor {
+                workdir: opt.tulip_dir.as_path(),
+                nutshell: opt.nutshell.as_path(),
+                backend,
+                shellcheck,
+                editor,
+                reader,
+                mount_dir,
+            };
+            doctor.handle();
+        }
+        SubCommand::Inspect { depth, large } => {
             let db = init_db(opt.tulip_dir.join("meta").as_path());
-            student::handle_request(&db, backend.as_str(), opt.tulip_dir.as_path(), download_only, shellcheck.as_path(), None);
+            inspect::handle(&db, opt.tulip_dir.as_path(), depth, large);
         }
-        SubCommand::Pull { backend, id, shellcheck } => {
+        SubCommand::Similarity { top, threshold, show, mark } => {
             let db = init_db(opt.tulip_dir.join("meta").as_path());
-            student::pull(opt.tulip_dir.as_path(), id, &db, backend.as_str(), shellcheck.as_path());
+            let cache = cache::Cache::new(opt.tulip_dir.as_path(), opt.cache_size);
+            similarity::Similarity { top, threshold, show, mark }.handle(&db, &cache, opt.tulip_dir.as_path());
         }
-        SubCommand::Grade { score, r#override } => {
+        SubCommand::Diff { student, template, summary, attach, backend } => {
             let db = init_db(opt.tulip_dir.join("meta").as_path());
-            let mut status = force_get_json::<Status>(&db, "status");
-            if status.in_progress.is_none() {
-                error!("no current project");
-                std::process::exit(1);
-            }
-            if status.graded.is_some() && !r#override {
-                error!("grading exists");
-                std::process::exit(1);
+            let cache = cache::Cache::new(opt.tulip_dir.as_path(), opt.cache_size);
+            let source = match (student, template) {
+                (Some(id), _) => diff::Source::Student(id),
+                (None, Some(path)) => diff::Source::Template(path),
+                (None, None) => diff::Source::Previous
+            };
+            diff::Diff { source, summary, attach, backend }.handle(&db, &cache, opt.tulip_dir.as_path());
+        }
+        SubCommand::Cache { command } => {
+            let cache = cache::Cache::new(opt.tulip_dir.as_path(), opt.cache_size);
+            match command {
+                cli::CacheCommand::List => cache.list(),
+                cli::CacheCommand::Prune { all, id } => cache.prune(all, id),
             }
-            let config = force_get_json::<Config>(&db, "config");
-            if config.max_grade < score {
-                error!("score out of range");
-                std::process::exit(1);
+        }
+        SubCommand::Tui => {
+            dashboard::Dashboard::new(opt.tulip_dir.as_path(), opt.nutshell.as_path(), opt.log_level.as_str(), opt.cache_size).handle();
+        }
+        SubCommand::Gradebook { command } => {
+            let db = init_db(opt.tulip_dir.join("meta").as_path());
+            match command {
+                cli::GradebookCommand::Export { output, columns } => gradebook::export(&db, output.as_path(), &columns),
+                cli::GradebookCommand::Import { input, columns, dry_run, r#override } =>
+                    gradebook::import(&db, input.as_path(), &columns, dry_run, r#override),
             }
-            status.graded.replace(score);
-            db.put("status", serde_json::to_string(&status)
-                .exit_on_failure()).exit_on_failure();
+        }
+        SubCommand::Protect { store } => {
+            let db = init_db(opt.tulip_dir.join("meta").as_path());
+            credential::protect(&db, store);
+        }
+        SubCommand::Rekey => {
+            let db = init_db(opt.tulip_dir.join("meta").as_path());
+            register::rekey(&db);
+        }
+        SubCommand::Recover { dry_run } => {
+            let db = init_db(opt.tulip_dir.join("meta").as_path());
+            recover::handle(&db, opt.tulip_dir.as_path(), dry_run);
+        }
+        SubCommand::Fetch { backend, download_only, shellcheck } => {
+            let db = init_db(opt.tulip_dir.join("meta").as_path());
+            let cache = cache::Cache::new(opt.tulip_dir.as_path(), opt.cache_size);
+            student::handle_request(&db, backend.as_str(), &cache, opt.tulip_dir.as_path(), download_only, shellcheck.as_path(), None);
+        }
+        SubCommand::Pull { backend, id, shellcheck } => {
+            let db = init_db(opt.tulip_dir.join("meta").as_path());
+            let cache = cache::Cache::new(opt.tulip_dir.as_path(), opt.cache_size);
+            student::pull(opt.tulip_dir.as_path(), id, &db, backend.as_str(), &cache, shellcheck.as_path());
+        }
+        SubCommand::Grade { score, accept_suggested, criterion, r#override } => {
+            let db = init_db(opt.tulip_dir.join("meta").as_path());
+            grade::handle(&db, score, accept_suggested, criterion, r#override);
+        }
+        SubCommand::Adjust { kind, points, reason, remove } => {
+            let db = init_db(opt.tulip_dir.join("meta").as_path());
+            grade::adjust(&db, kind, points, reason, remove);
         }
         SubCommand::Comment { editor } => {
             let db = init_db(opt.tulip_dir.join("meta").as_path());
@@ -208,6 +296,22 @@ fn main() {
             db.put("status", serde_json::to_string(&status)
                 .exit_on_failure()).exit_on_failure();
         }
+        SubCommand::Batch { count, until_empty, backend, shellcheck, mount_dir, tmp_size, hold, review_below, report } => {
+            must_sudo();
+            let batch = batch::Batch {
+                workdir: opt.tulip_dir.as_path(),
+                nutshell: opt.nutshell.as_path(),
+                log_level: opt.log_level.as_str(),
+                cache_size: opt.cache_size,
+                backend,
+                shellcheck,
+                mount_dir,
+                tmp_size,
+                hold,
+                review_below,
+            };
+            batch.handle(if until_empty { None } else { count }, report);
+        }
         SubCommand::Build { rebuild } => {
             let db = init_db(opt.tulip_dir.join("meta").as_path());
             build::handle(&db, rebuild, opt.tulip_dir.as_path());
@@ -216,6 +320,10 @@ fn main() {
             let db = init_db(opt.tulip_dir.join("meta").as_path());
             run::run(&db, without_build);
         }
+        SubCommand::Test { without_build, case } => {
+            let db = init_db(opt.tulip_dir.join("meta").as_path());
+            judge::handle(&db, without_build, case);
+        }
         SubCommand::Submit { r#override } => {
             let db = init_db(opt.tulip_dir.join("meta").as_path());
             student::handle_submit(&db, r#override);
@@ -273,11 +381,18 @@ fn main() {
             let db = init_db(opt.tulip_dir.join("meta").as_path());
             student::skip(&db, force, opt.tulip_dir.as_path());
         }
-        SubCommand::AutoCurrent { tmp_size, mount_point, shellcheck, editor, reader } => {
+        SubCommand::AutoCurrent { tmp_size, mount_point, shellcheck, editor, reader, resume } => {
             let db = init_db(opt.tulip_dir.join("meta").as_path());
-            student::auto_current(opt.tulip_dir.as_path(), &db, opt.nutshell.as_path(), tmp_size,
-                                  mount_point.as_path(), shellcheck.as_path(), editor.as_str(), reader.as_path(),
-            )
+            let auto = pipeline::AutoCurrent {
+                workdir: opt.tulip_dir.as_path(),
+                nutshell: opt.nutshell.as_path(),
+                tmp_size,
+                mount_point: mount_point.as_path(),
+                shellcheck: shellcheck.as_path(),
+                editor: editor.as_str(),
+                reader: reader.as_path(),
+            };
+            auto.handle(&db, resume);
         }
         SubCommand::Report { reader } => {
             let db = init_db(opt.tulip_dir.join("meta").as_path());
@@ -301,6 +416,16 @@ fn clear_status(db: &DB, status: &mut Status, workdir: &Path) {
         stderr: None,
         build_stdout: None,
         build_stderr: None,
+        test_results: Vec::new(),
+        verdict: None,
+        lint: None,
+        suggested: None,
+        rubric: Vec::new(),
+        adjustments: Vec::new(),
+        risks: Vec::new(),
+        notable: Vec::new(),
+        normalized: Vec::new(),
+        similar: Vec::new(),
     };
     if let Err(e) = std::fs::remove_dir_all(workdir.join("student")) {
         warn!("failed to remove student dir: {}", e);
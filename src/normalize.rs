@@ -0,0 +1,227 @@
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use encoding_rs::Encoding;
+use log::*;
+use prettytable::*;
+
+use crate::settings::{NormalizeAction, NormalizeChange, NormalizePolicy, Status, StudentConfig};
+
+const BOM: &[u8] = b"\xef\xbb\xbf";
+
+fn change(changes: &mut Vec<NormalizeChange>, path: &Path, action: NormalizeAction, detail: String) {
+    info!("normalized {}: {}", path.display(), detail);
+    changes.push(NormalizeChange { path: path.to_path_buf(), action, detail });
+}
+
+fn script(student_dir: &Path, script: &Path, shebang: &str, changes: &mut Vec<NormalizeChange>) -> Result<(), String> {
+    let path = crate::validate::contained(student_dir, script)?;
+    let mut content = std::fs::read(path.as_path()).map_err(|x| x.to_string())?;
+    let original = content.len();
+
+    if content.starts_with(BOM) {
+        content.drain(..BOM.len());
+        change(changes, script, NormalizeAction::StripBom, String::from("removed the UTF-8 byte order mark"));
+    }
+    let crlf = content.windows(2).filter(|x| x == b"\r\n").count();
+    if crlf > 0 {
+        let mut converted = Vec::with_capacity(content.len());
+        let mut iter = content.iter().peekable();
+        while let Some(byte) = iter.next() {
+            if *byte == b'\r' && iter.peek() == Some(&&b'\n') {
+                continue;
+            }
+            converted.push(*byte);
+        }
+        content = converted;
+        change(changes, script, NormalizeAction::CrlfToLf, format!("converted {} CRLF line ending(s)", crlf));
+    }
+    if !content.starts_with(b"#!") {
+        let mut with_shebang = format!("#!{}\n", shebang).into_bytes();
+        with_shebang.append(&mut content);
+        content = with_shebang;
+        change(changes, script, NormalizeAction::AddShebang, format!("added #!{}", shebang));
+    }
+    if content.len() != original || crlf > 0 {
+        std::fs::write(path.as_path(), content).map_err(|x| x.to_string())?;
+    }
+
+    let mut permissions = std::fs::metadata(path.as_path()).map_err(|x| x.to_string())?.permissions();
+    if permissions.mode() & 0o111 != 0o111 {
+        let mode = permissions.mode();
+        permissions.set_mode(mode | 0o111);
+        std::fs::set_permissions(path.as_path(), permissions).map_err(|x| x.to_string())?;
+        change(changes, script, NormalizeAction::SetExecutable, format!("mode {:o} to {:o}", mode & 0o7777, (mode | 0o111) & 0o7777));
+    }
+    Ok(())
+}
+
+fn transcode(dir: &Path, relative: &Path, encoding: &'static Encoding, extensions: &[String], changes: &mut Vec<NormalizeChange>) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return
+    };
+    for entry in entries.filter_map(|x| x.ok()) {
+        let name = entry.file_name();
+        let path = entry.path();
+        let relative = relative.join(&name);
+        let file_type = match entry.file_type() {
+            Ok(x) => x,
+            Err(_) => continue
+        };
+        if file_type.is_dir() && name != ".git" {
+            transcode(path.as_path(), relative.as_path(), encoding, extensions, changes);
+            continue;
+        }
+        let matched = path.extension()
+            .and_then(|x| x.to_str())
+            .map(|x| extensions.iter().any(|y| y.eq_ignore_ascii_case(x)))
+            .unwrap_or(false);
+        if !file_type.is_file() || !matched {
+            continue;
+        }
+        let content = match std::fs::read(path.as_path()) {
+            Ok(content) => content,
+            Err(_) => continue
+        };
+        if std::str::from_utf8(content.as_slice()).is_ok() {
+            continue;
+        }
+        let (decoded, _, malformed) = encoding.decode(content.as_slice());
+        if malformed {
+            warn!("{} is neither UTF-8 nor {}, leaving it unchanged", relative.display(), encoding.name());
+            continue;
+        }
+        match std::fs::write(path.as_path(), decoded.as_bytes()) {
+            Ok(()) => change(changes, relative.as_path(), NormalizeAction::Transcode,
+                             format!("converted from {} to UTF-8", encoding.name())),
+            Err(e) => warn!("unable to convert {}: {}", relative.display(), e)
+        }
+    }
+}
+
+/// Fix the entry scripts and convert legacy encoded text files, recording every change in the status.
+pub fn handle(policy: &NormalizePolicy, status: &mut Status, workdir: &Path) {
+    let project = match status.in_progress.as_ref() {
+        Some(project) => project,
+        None => return
+    };
+    status.normalized = normalize(policy, project, workdir.join("student").as_path());
+}
+
+/// Apply the policy to a project tree, also used on the trees `tulip diff` compares with.
+pub fn normalize(policy: &NormalizePolicy, project: &StudentConfig, student_dir: &Path) -> Vec<NormalizeChange> {
+    let mut changes = Vec::new();
+    if policy.scripts {
+        let shebang = policy.shebang.as_deref().unwrap_or("/bin/sh");
+        for path in &[project.build_shell.as_path(), project.run_shell.as_path()] {
+            if let Err(e) = script(student_dir, path, shebang, &mut changes) {
+                warn!("unable to normalize {}: {}", path.display(), e);
+            }
+        }
+    }
+    if let Some(label) = &policy.encoding {
+        match Encoding::for_label(label.as_bytes()) {
+            Some(encoding) => transcode(student_dir, Path::new(""), encoding, &policy.extensions, &mut changes),
+            None => warn!("unknown encoding {}, skipping conversion", label)
+        }
+    }
+    changes
+}
+
+pub fn manifest_table(changes: &[NormalizeChange]) -> Table {
+    let mut table = Table::new();
+    table.add_row(row![bFb->"Path", bFb->"Action", bFb->"Detail"]);
+    for i in changes {
+        table.add_row(row![i.path.display(), format!("{:?}", i.action), i.detail]);
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn actions(changes: &[NormalizeChange]) -> Vec<NormalizeAction> {
+        changes.iter().map(|x| x.action).collect()
+    }
+
+    fn write(dir: &Path, name: &str, content: &[u8], mode: u32) {
+        let path = dir.join(name);
+        std::fs::write(path.as_path(), content).unwrap();
+        std::fs::set_permissions(path.as_path(), std::fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    #[test]
+    fn fixes_a_windows_script() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "build.sh", b"\xef\xbb\xbfmake\r\nmake install\r\n", 0o644);
+        let mut changes = Vec::new();
+        script(dir.path(), Path::new("build.sh"), "/bin/bash", &mut changes).unwrap();
+        assert_eq!(actions(&changes), vec![NormalizeAction::StripBom, NormalizeAction::CrlfToLf,
+                                           NormalizeAction::AddShebang, NormalizeAction::SetExecutable]);
+        assert_eq!(std::fs::read(dir.path().join("build.sh")).unwrap(), b"#!/bin/bash\nmake\nmake install\n");
+        assert_eq!(std::fs::metadata(dir.path().join("build.sh")).unwrap().permissions().mode() & 0o777, 0o755);
+    }
+
+    #[test]
+    fn leaves_a_clean_script_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "run.sh", b"#!/bin/sh\n./main\r\0\n", 0o755);
+        let mut changes = Vec::new();
+        script(dir.path(), Path::new("run.sh"), "/bin/sh", &mut changes).unwrap();
+        assert!(changes.is_empty());
+        assert_eq!(std::fs::read(dir.path().join("run.sh")).unwrap(), b"#!/bin/sh\n./main\r\0\n");
+    }
+
+    #[test]
+    fn adds_a_shebang_to_an_empty_script() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "run.sh", b"", 0o755);
+        let mut changes = Vec::new();
+        script(dir.path(), Path::new("run.sh"), "/bin/sh", &mut changes).unwrap();
+        assert_eq!(actions(&changes), vec![NormalizeAction::AddShebang]);
+        assert_eq!(std::fs::read(dir.path().join("run.sh")).unwrap(), b"#!/bin/sh\n");
+    }
+
+    #[test]
+    fn refuses_a_script_outside_the_project() {
+        let outside = tempfile::tempdir().unwrap();
+        write(outside.path(), "target", b"echo", 0o644);
+        let dir = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(outside.path().join("target"), dir.path().join("build.sh")).unwrap();
+        let mut changes = Vec::new();
+        assert!(script(dir.path(), Path::new("build.sh"), "/bin/sh", &mut changes).is_err());
+        assert!(script(dir.path(), Path::new("missing.sh"), "/bin/sh", &mut changes).is_err());
+        assert!(changes.is_empty());
+        assert_eq!(std::fs::read(outside.path().join("target")).unwrap(), b"echo");
+    }
+
+    #[test]
+    fn transcodes_only_legacy_files_with_the_extensions() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("src")).unwrap();
+        // "你好" in gbk
+        write(dir.path(), "src/main.c", b"// \xc4\xe3\xba\xc3\n", 0o644);
+        write(dir.path(), "src/data.bin", b"\xc4\xe3\xba\xc3", 0o644);
+        write(dir.path(), "src/utf8.C", "// 你好\n".as_bytes(), 0o644);
+        write(dir.path(), "src/broken.c", b"\xff\xff", 0o644);
+        let mut changes = Vec::new();
+        let encoding = Encoding::for_label(b"gbk").unwrap();
+        transcode(dir.path(), Path::new(""), encoding, &[String::from("c")], &mut changes);
+        assert_eq!(actions(&changes), vec![NormalizeAction::Transcode]);
+        assert_eq!(changes[0].path, Path::new("src/main.c"));
+        assert_eq!(std::fs::read_to_string(dir.path().join("src/main.c")).unwrap(), "// 你好\n");
+        assert_eq!(std::fs::read(dir.path().join("src/data.bin")).unwrap(), b"\xc4\xe3\xba\xc3");
+        assert_eq!(std::fs::read(dir.path().join("src/broken.c")).unwrap(), b"\xff\xff");
+    }
+
+    #[test]
+    fn ignores_a_missing_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut changes = Vec::new();
+        let encoding = Encoding::for_label(b"gbk").unwrap();
+        transcode(dir.path().join("missing").as_path(), Path::new(""), encoding, &[String::from("c")], &mut changes);
+        assert!(changes.is_empty());
+    }
+}
@@ -0,0 +1,116 @@
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use log::*;
+use prettytable::Table;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::LogUnwrap;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Table,
+    Json,
+    Yaml,
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(Format::Table),
+            "json" => Ok(Format::Json),
+            "yaml" => Ok(Format::Yaml),
+            "csv" => Ok(Format::Csv),
+            _ => Err(format!("unknown output format {}", s))
+        }
+    }
+}
+
+static FORMAT: OnceLock<Format> = OnceLock::new();
+
+pub fn init(format: Format) {
+    if FORMAT.set(format).is_err() {
+        warn!("output format already initialized");
+    }
+}
+
+pub fn format() -> Format {
+    *FORMAT.get_or_init(|| Format::Table)
+}
+
+/// Colours are only written for tables on a terminal, and never with `NO_COLOR` set.
+pub fn colour() -> bool {
+    format() == Format::Table && std::env::var_os("NO_COLOR").is_none() && atty::is(atty::Stream::Stdout)
+}
+
+/// Nested objects become dotted keys, arrays stay as JSON text in a single cell.
+fn flatten(prefix: &str, value: &Value, result: &mut Vec<(String, String)>) {
+    match value {
+        Value::Object(map) => for (k, v) in map {
+            let key = if prefix.is_empty() { k.clone() } else { format!("{}.{}", prefix, k) };
+            flatten(key.as_str(), v, result);
+        },
+        Value::Null => result.push((prefix.to_string(), String::new())),
+        Value::String(x) => result.push((prefix.to_string(), x.clone())),
+        x => result.push((prefix.to_string(), x.to_string())),
+    }
+}
+
+fn csv(value: &Value) -> Result<(), String> {
+    let mut writer = csv::Writer::from_writer(std::io::stdout());
+    match value {
+        Value::Array(items) => {
+            let rows = items.iter()
+                .map(|x| {
+                    let mut row = Vec::new();
+                    flatten(if x.is_object() { "" } else { "value" }, x, &mut row);
+                    row
+                })
+                .collect::<Vec<_>>();
+            let mut header: Vec<&str> = Vec::new();
+            for (key, _) in rows.iter().flatten() {
+                if !header.contains(&key.as_str()) {
+                    header.push(key.as_str());
+                }
+            }
+            writer.write_record(&header).map_err(|x| x.to_string())?;
+            for row in &rows {
+                let record = header.iter()
+                    .map(|x| row.iter().find(|y| y.0 == *x).map(|y| y.1.as_str()).unwrap_or(""));
+                writer.write_record(record).map_err(|x| x.to_string())?;
+            }
+        }
+        _ => {
+            let mut fields = Vec::new();
+            flatten("", value, &mut fields);
+            writer.write_record(["field", "value"]).map_err(|x| x.to_string())?;
+            for (key, value) in &fields {
+                writer.write_record([key, value]).map_err(|x| x.to_string())?;
+            }
+        }
+    }
+    writer.flush().map_err(|x| x.to_string())
+}
+
+/// Tables shown along the way, such as before a question, go to stderr unless the format is `table`.
+pub fn show(table: &Table) {
+    if format() == Format::Table {
+        table.printstd();
+    } else if let Err(e) = table.print(&mut std::io::stderr()) {
+        warn!("unable to print the table: {}", e);
+    }
+}
+
+/// Print the value in the chosen format, `human` prints the usual table and only runs for `--format table`.
+pub fn emit<T: Serialize, F: FnOnce()>(value: &T, human: F) {
+    match format() {
+        Format::Table => human(),
+        Format::Json => println!("{}", serde_json::to_string_pretty(value).exit_on_failure()),
+        Format::Yaml => print!("{}", serde_yaml::to_string(value).exit_on_failure()),
+        Format::Csv => csv(&serde_json::to_value(value).exit_on_failure()).exit_on_failure(),
+    }
+}
@@ -60,40 +60,30 @@ pub fn handle(db: &DB, workdir: &Path, nutshell: &Path, print_result: bool, shel
 pub fn handle_destroy(db: &DB, workdir: &Path) {
     let mut status = force_get_json::<Status>(db, "status");
     if let Some(mount) = &status.mount {
-        info!("trying to umount {}", mount.display());
-        let umount = std::process::Command::new("sudo").arg("-k")
-            .arg("umount")
-            .arg("-R")
-            .arg(mount)
-            .spawn()
-            .and_then(|mut x| x.wait());
-        match umount {
-            Ok(e) => info!("umount exit with {}", e),
-            Err(e) => error!("umount failed with {}", e)
+        if let Err(e) = crate::recover::umount(mount) {
+            error!("{}, refusing to delete the data dir", e);
+            std::process::exit(1);
         }
-        let umount = std::process::Command::new("sudo")
-            .arg("umount")
-            .arg("-R")
-            .arg(mount)
+    }
+    let deleting_path = workdir.join("data");
+    let busy = crate::recover::mounted_under(deleting_path.canonicalize().unwrap_or_else(|_| deleting_path.clone()).as_path());
+    if !busy.is_empty() {
+        error!("{} is still mounted, refusing to delete the data dir", busy[0].mount_point.display());
+        std::process::exit(1);
+    }
+    if deleting_path.exists() {
+        warn!("deleting {}", deleting_path.display());
+        let deleting = std::process::Command::new("sudo").arg("-k")
+            .arg("rm")
+            .arg("-rf")
+            .arg(deleting_path)
             .spawn()
             .and_then(|mut x| x.wait());
-        match umount {
-            Ok(e) => info!("umount exit with {}", e),
-            Err(e) => error!("umount failed with {}", e)
+        match deleting {
+            Ok(e) => info!("deleting exit with {}", e),
+            Err(e) => error!("deleting failed with {}", e)
         }
     }
-    let deleting_path = workdir.join("data");
-    warn!("deleting {}", deleting_path.display());
-    let deleting = std::process::Command::new("sudo").arg("-k")
-        .arg("rm")
-        .arg("-rf")
-        .arg(deleting_path)
-        .spawn()
-        .and_then(|mut x| x.wait());
-    match deleting {
-        Ok(e) => info!("deleting exit with {}", e),
-        Err(e) => error!("deleting failed with {}", e)
-    }
     status.built = false;
     status.mount = None;
     db.put("status", serde_json::to_vec(&status).exit_on_failure()).exit_on_failure();
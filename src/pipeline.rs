@@ -0,0 +1,204 @@
+use std::path::Path;
+
+use log::*;
+use rocksdb::DB;
+
+use crate::{force_get_json, LogUnwrap, prompt};
+use crate::cli::StatusWatch;
+use crate::settings::{Config, Pipeline, PipelineProgress, PipelineStep, Status, Step, StepMode};
+
+pub fn get_pipeline(db: &DB) -> Pipeline {
+    db.get("pipeline")
+        .ok().flatten()
+        .and_then(|mut x| simd_json::serde::from_slice::<Pipeline>(x.as_mut_slice()).ok())
+        .unwrap_or_default()
+}
+
+fn get_progress(db: &DB) -> Option<PipelineProgress> {
+    db.get("pipeline_progress")
+        .ok().flatten()
+        .and_then(|mut x| simd_json::serde::from_slice::<PipelineProgress>(x.as_mut_slice()).ok())
+}
+
+pub struct AutoCurrent<'a> {
+    pub workdir: &'a Path,
+    pub nutshell: &'a Path,
+    pub tmp_size: Option<usize>,
+    pub mount_point: &'a Path,
+    pub shellcheck: &'a Path,
+    pub editor: &'a str,
+    pub reader: &'a Path,
+}
+
+fn ask(mode: StepMode, key: &str, question: &str, default: bool) -> bool {
+    match mode {
+        StepMode::Always => true,
+        StepMode::Ask => prompt::confirm(key, question, default),
+        StepMode::Skip => false,
+    }
+}
+
+impl<'a> AutoCurrent<'a> {
+    fn step(&self, db: &DB, step: &PipelineStep) {
+        let status = force_get_json::<Status>(db, "status");
+        let mode = step.mode;
+        match &step.step {
+            Step::Report => {
+                if status.in_progress.as_ref().and_then(|x| x.report.as_ref()).is_some()
+                    && ask(mode, "open-report", "This student has report, open it?", true) {
+                    crate::student::report(db, self.reader, self.workdir);
+                }
+            }
+            Step::Remount { tmp_size } => {
+                let mount = if status.mount.is_some() {
+                    let remount = ask(mode, "remount", "Already mount, re-init the overlay?", true);
+                    if remount {
+                        crate::overlay::handle_destroy(db, self.workdir);
+                    }
+                    remount
+                } else {
+                    mode != StepMode::Skip
+                };
+                if mount {
+                    crate::overlay::handle(
+                        db, self.workdir, self.nutshell, false, false, self.mount_point,
+                        tmp_size.or(self.tmp_size), false,
+                    );
+                    info!("overlay intialized");
+                }
+            }
+            Step::EnterOverlay => {
+                if ask(mode, "enter-overlay", "Enter the current overlay?", true) {
+                    self.require_mount(&status);
+                    let code = crate::build::build_nspawn(db, &status, self.workdir, false, false)
+                        .spawn()
+                        .exit_on_failure()
+                        .wait()
+                        .exit_on_failure();
+                    info!("nspawn {}", code);
+                }
+            }
+            Step::ViewBuildScript => {
+                if ask(mode, "view-build-script", "View the build script?", true) {
+                    crate::status::handle(db, StatusWatch::EditBuildScript {
+                        editor: self.editor.to_string(),
+                        shellcheck: self.shellcheck.to_path_buf(),
+                    }, self.workdir);
+                }
+            }
+            Step::Build => {
+                let build = if status.built {
+                    ask(mode, "rebuild", "Already build, re-build?", true)
+                } else {
+                    mode != StepMode::Skip
+                };
+                if build {
+                    crate::build::handle(db, true, self.workdir);
+                }
+            }
+            Step::EnterNspawn => {
+                if ask(mode, "enter-nspawn", "Enter the sandboxed overlay?", true) {
+                    self.require_mount(&status);
+                    let code = crate::build::build_nspawn(db, &status, self.workdir, false, true)
+                        .spawn()
+                        .exit_on_failure()
+                        .wait()
+                        .exit_on_failure();
+                    info!("nspawn {}", code);
+                }
+            }
+            Step::ViewRunScript => {
+                if ask(mode, "view-run-script", "View the run script?", true) {
+                    crate::status::handle(db, StatusWatch::EditRunScript {
+                        editor: self.editor.to_string(),
+                        shellcheck: self.shellcheck.to_path_buf(),
+                    }, self.workdir);
+                }
+            }
+            Step::Run { without_build } => {
+                if ask(mode, "run", "Start running?", true) {
+                    crate::run::run(db, *without_build);
+                }
+            }
+            Step::Test { case } => {
+                if force_get_json::<Config>(db, "config").test_cases.is_empty() {
+                    info!("no test case configured, skipping the tests");
+                } else if ask(mode, "test", "Run the test cases?", true) {
+                    crate::judge::handle(db, false, case.clone());
+                }
+            }
+            Step::EnterFirejail => {
+                if ask(mode, "enter-firejail", "Enter the firejailed overlay?", true) {
+                    self.require_mount(&status);
+                    let config = force_get_json::<Config>(db, "config");
+                    let code = crate::run::build_firejail(status.mount.as_ref().unwrap(), &config, true)
+                        .spawn()
+                        .exit_on_failure()
+                        .wait()
+                        .exit_on_failure();
+                    info!("firejail {}", code);
+                }
+            }
+        }
+    }
+
+    fn require_mount(&self, status: &Status) {
+        if status.mount.is_none() {
+            error!("please mount a overlay first");
+            std::process::exit(1);
+        }
+    }
+
+    pub fn handle(&self, db: &DB, resume: bool) {
+        let status = force_get_json::<Status>(db, "status");
+        if status.in_progress.is_none() {
+            error!("No current project");
+            std::process::exit(1);
+        }
+        if !status.image {
+            error!("No current image");
+            std::process::exit(1);
+        }
+        let student_id = status.in_progress.as_ref().unwrap().student_id.clone();
+        let pipeline = get_pipeline(db);
+
+        let digest = pipeline.digest();
+        let mut finished = match get_progress(db) {
+            Some(progress) if resume && progress.student_id == student_id && progress.pipeline == digest => {
+                info!("resuming after {} finished step(s)", progress.finished.len());
+                progress.finished
+            }
+            Some(progress) if resume && progress.student_id == student_id => {
+                warn!("the pipeline changed since the progress was saved, starting over");
+                Vec::new()
+            }
+            _ => {
+                if resume {
+                    warn!("no saved progress for {}, starting over", student_id);
+                }
+                Vec::new()
+            }
+        };
+        // the finished steps are the leading ones, as long as they are where they were
+        let start = pipeline.steps.iter()
+            .zip(finished.iter())
+            .take_while(|(step, id)| step.step.id() == id.as_str())
+            .count();
+        finished.truncate(start);
+
+        for (index, step) in pipeline.steps.iter().enumerate().skip(start) {
+            debug!("pipeline step {}: {:?}", index + 1, step);
+            self.step(db, step);
+            finished.push(step.step.id().to_string());
+            let progress = PipelineProgress {
+                student_id: student_id.clone(),
+                pipeline: digest.clone(),
+                finished: finished.clone(),
+            };
+            db.put("pipeline_progress", serde_json::to_vec(&progress).exit_on_failure()).exit_on_failure();
+        }
+
+        db.delete("pipeline_progress").exit_on_failure();
+        info!("pipeline finished");
+    }
+}
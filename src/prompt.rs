@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::OnceLock;
+
+use log::*;
+use serde_json::Value;
+
+use crate::credential::Secret;
+use crate::LogUnwrap;
+
+/// How questions are answered when tulip needs input from the grader.
+/// Answers from the answers file take priority over `--yes`/`--no`, which take priority over the terminal.
+struct Prompt {
+    assume: Option<bool>,
+    answers: HashMap<String, Value>,
+}
+
+static PROMPT: OnceLock<Prompt> = OnceLock::new();
+
+pub fn init(yes: bool, no: bool, answers: Option<&Path>) {
+    let answers = answers.map(|path| {
+        let mut content = std::fs::read(path).exit_on_failure();
+        simd_json::serde::from_slice::<HashMap<String, Value>>(content.as_mut_slice())
+            .map_err(|x| format!("invalid answers file {}: {}", path.display(), x))
+            .exit_on_failure()
+    }).unwrap_or_default();
+    let assume = if yes { Some(true) } else if no { Some(false) } else { None };
+    if PROMPT.set(Prompt { assume, answers }).is_err() {
+        warn!("prompt system already initialized");
+    }
+}
+
+fn prompt() -> &'static Prompt {
+    PROMPT.get_or_init(|| Prompt { assume: None, answers: HashMap::new() })
+}
+
+fn try_read_line(question: &str, hint: &str) -> Result<String, String> {
+    if !atty::is(atty::Stream::Stdin) {
+        return Err(format!("input is needed for \"{}\" but no TTY is attached, use --yes, --no or --answers", question));
+    }
+    print!("{} {} ", question, hint);
+    std::io::stdout().flush().map_err(|x| x.to_string())?;
+    let mut line = String::new();
+    if std::io::stdin().read_line(&mut line).map_err(|x| x.to_string())? == 0 {
+        return Err(format!("unexpected end of input for \"{}\"", question));
+    }
+    Ok(line.trim().to_string())
+}
+
+fn read_line(question: &str, hint: &str) -> String {
+    try_read_line(question, hint).exit_on_failure()
+}
+
+/// Turns the echo of the terminal off until it is dropped.
+struct NoEcho;
+
+impl NoEcho {
+    fn stty(flag: &str) {
+        if let Err(e) = Command::new("stty").arg(flag).stdin(Stdio::inherit()).status() {
+            warn!("unable to run stty: {}", e);
+        }
+    }
+
+    fn new() -> Self {
+        NoEcho::stty("-echo");
+        NoEcho
+    }
+}
+
+impl Drop for NoEcho {
+    fn drop(&mut self) {
+        NoEcho::stty("echo");
+        println!();
+    }
+}
+
+/// Ask a yes/no question, an empty answer gives the default shown in the hint.
+pub fn confirm(key: &str, question: &str, default: bool) -> bool {
+    let prompt = prompt();
+    if let Some(answer) = prompt.answers.get(key) {
+        let answer = match answer {
+            Value::Bool(x) => Some(*x),
+            Value::String(x) => parse_bool(x),
+            _ => None
+        };
+        match answer {
+            Some(x) => {
+                info!("{} {}", question, if x { "yes" } else { "no" });
+                return x;
+            }
+            None => warn!("ignoring invalid answer for {}", key)
+        }
+    }
+    if let Some(x) = prompt.assume {
+        info!("{} {}", question, if x { "yes" } else { "no" });
+        return x;
+    }
+    let hint = if default { "[Y/n]" } else { "[y/N]" };
+    loop {
+        let line = read_line(question, hint);
+        if line.is_empty() {
+            return default;
+        }
+        match parse_bool(line.as_str()) {
+            Some(x) => return x,
+            None => error!("please answer yes or no")
+        }
+    }
+}
+
+/// Ask a free text question, an empty answer gives the default if there is one.
+/// `--yes`/`--no` can only answer it with the default.
+pub fn input(key: &str, question: &str, default: Option<&str>) -> String {
+    let prompt = prompt();
+    match prompt.answers.get(key) {
+        Some(Value::String(x)) => return x.clone(),
+        Some(Value::Number(x)) => return x.to_string(),
+        Some(_) => warn!("ignoring invalid answer for {}", key),
+        None => ()
+    }
+    if prompt.assume.is_some() {
+        match default {
+            Some(x) => return x.to_string(),
+            None => {
+                error!("no default answer for \"{}\", please provide it in the answers file as {}", question, key);
+                std::process::exit(1);
+            }
+        }
+    }
+    let hint = default.map(|x| format!("[{}]", x)).unwrap_or_default();
+    let line = read_line(question, hint.as_str());
+    if line.is_empty() {
+        default.map(|x| x.to_string()).unwrap_or(line)
+    } else {
+        line
+    }
+}
+
+/// Read a secret from the terminal without echoing it, it is never taken from the answers file.
+pub fn secret(question: &str) -> Secret {
+    let line = {
+        let _echo = atty::is(atty::Stream::Stdin).then(NoEcho::new);
+        try_read_line(question, "")
+    };
+    // the echo is back before exiting on an error
+    Secret::from(line.exit_on_failure())
+}
+
+/// Whether the answer to the question comes from a script instead of the grader.
+pub fn scripted(key: &str) -> bool {
+    let prompt = prompt();
+    prompt.assume.is_some() || prompt.answers.contains_key(key)
+}
+
+fn parse_bool(answer: &str) -> Option<bool> {
+    match answer.to_ascii_lowercase().as_str() {
+        "y" | "yes" | "true" => Some(true),
+        "n" | "no" | "false" => Some(false),
+        _ => None
+    }
+}
@@ -6,6 +6,8 @@ use rocksdb::DB;
 use serde::*;
 
 use crate::{force_get, force_get_json, LogUnwrap};
+use crate::credential::{Authenticate, Identity};
+use crate::http::Dispatch;
 use crate::settings::{Config, Status};
 
 pub fn handle_local(db: &DB, workdir: &Path) {
@@ -23,7 +25,7 @@ pub fn handle_local(db: &DB, workdir: &Path) {
 pub fn handle(force: bool, db: &DB, backend: &str, workdir: &Path) {
     let server = force_get(db, "server");
 
-    let uuid = force_get(db, "uuid");
+    let identity = crate::credential::identity(db);
 
     let status = force_get_json::<Status>(db, "status");
 
@@ -32,8 +34,9 @@ pub fn handle(force: bool, db: &DB, backend: &str, workdir: &Path) {
         std::process::exit(1);
     }
     let request_url: Url = format!("{}/image.sfs", server).parse().exit_on_failure();
-    let auth = format!("Authorization: Bearer {}", uuid);
+    let auth = format!("Authorization: {}", crate::credential::authorization(&identity, "GET", &request_url, &[]));
     std::fs::create_dir_all(workdir.join("image")).exit_on_failure();
+    crate::http::check_downloader(backend);
     match backend {
         "wget" => {
             std::process::Command::new("wget")
@@ -43,6 +46,7 @@ pub fn handle(force: bool, db: &DB, backend: &str, workdir: &Path) {
                 .arg(request_url.as_str())
                 .arg("--header")
                 .arg(auth)
+                .args(crate::http::downloader_args(backend))
                 .arg("--show-progress")
                 .spawn()
                 .map_err(|x| x.to_string())
@@ -61,16 +65,32 @@ pub fn handle(force: bool, db: &DB, backend: &str, workdir: &Path) {
                 .arg("image.sfs")
                 .arg("--header")
                 .arg(auth)
+                .args(crate::http::downloader_args(backend))
                 .spawn()
                 .map_err(|x| x.to_string())
                 .and_then(|mut x| x.wait().map_err(|x| x.to_string()))
                 .and_then(|x| if x.success() { Ok(()) } else { Err(String::from("download failed")) })
                 .exit_on_failure();
         }
+        "builtin" => {
+            let partial = workdir.join("image/image.sfs.partial");
+            crate::http::client()
+                .get(request_url)
+                .authenticate(&identity)
+                .dispatch_idempotent()
+                .and_then(|x| x.error_for_status())
+                .map_err(|x| x.to_string())
+                .and_then(|mut x| {
+                    let mut file = std::fs::File::create(partial.as_path()).map_err(|x| x.to_string())?;
+                    x.copy_to(&mut file).map_err(|x| x.to_string())
+                })
+                .and_then(|_| std::fs::rename(partial.as_path(), workdir.join("image/image.sfs")).map_err(|x| x.to_string()))
+                .exit_on_failure();
+        }
         _ => unreachable!()
     }
     handle_local(db, workdir);
-    refresh_config(server.as_str(), uuid.as_str(), db);
+    refresh_config(server.as_str(), &identity, db);
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -78,11 +98,11 @@ pub struct ConfigResponse {
     config: Config
 }
 
-pub fn refresh_config(server: &str, uuid: &str, db: &DB) {
-    let config = reqwest::blocking::Client::new()
+pub fn refresh_config(server: &str, identity: &Identity, db: &DB) {
+    let config = crate::http::client()
         .get(format!("{}/config", server).as_str())
-        .bearer_auth(uuid)
-        .send()
+        .authenticate(identity)
+        .dispatch_idempotent()
         .and_then(|x| x.json::<ConfigResponse>())
         .exit_on_failure();
     if !config.config.notification.is_empty() {
@@ -0,0 +1,302 @@
+use std::path::{Path, PathBuf};
+
+use log::*;
+use prettytable::*;
+use rocksdb::DB;
+use serde::*;
+
+use crate::{force_get_json, LogUnwrap, prompt};
+use crate::settings::Status;
+
+#[derive(Debug)]
+pub struct MountEntry {
+    pub mount_point: PathBuf,
+    pub fs_type: String,
+    pub super_options: String,
+}
+
+impl MountEntry {
+    pub fn option(&self, name: &str) -> Option<&str> {
+        self.super_options.split(',')
+            .find_map(|x| x.strip_prefix(name).and_then(|x| x.strip_prefix('=')))
+    }
+}
+
+fn unescape(field: &str) -> String {
+    field.replace("\\040", " ")
+        .replace("\\011", "\t")
+        .replace("\\012", "\n")
+        .replace("\\134", "\\")
+}
+
+/// Parse `/proc/self/mountinfo`, see proc(5) for the format.
+pub fn mounts() -> Vec<MountEntry> {
+    let content = std::fs::read_to_string("/proc/self/mountinfo").unwrap_or_else(|e| {
+        warn!("unable to read mountinfo: {}", e);
+        String::new()
+    });
+    content.lines().filter_map(|line| {
+        let mut halves = line.splitn(2, " - ");
+        let fields = halves.next()?.split(' ').collect::<Vec<_>>();
+        let rest = halves.next()?.split(' ').collect::<Vec<_>>();
+        Some(MountEntry {
+            mount_point: PathBuf::from(unescape(fields.get(4)?)),
+            fs_type: rest.first()?.to_string(),
+            super_options: rest.get(2).map(|x| unescape(x)).unwrap_or_default(),
+        })
+    }).collect()
+}
+
+/// Mounts at or below the given path.
+pub fn mounted_under(path: &Path) -> Vec<MountEntry> {
+    mounts().into_iter().filter(|x| x.mount_point.starts_with(path)).collect()
+}
+
+#[derive(Serialize)]
+struct IssueShow {
+    kind: &'static str,
+    description: String,
+    repair: &'static str,
+}
+
+#[derive(Debug)]
+pub enum Issue {
+    StaleMount(PathBuf),
+    OrphanedOverlay(PathBuf),
+    LeftoverData(PathBuf),
+    BuiltWithoutUpper(PathBuf),
+    LeftoverProcess { pid: u32, command: String, mount: PathBuf },
+    MissingStudentDir(PathBuf),
+}
+
+impl Issue {
+    fn key(&self) -> &'static str {
+        match self {
+            Issue::StaleMount(_) => "recover.stale-mount",
+            Issue::OrphanedOverlay(_) => "recover.orphaned-overlay",
+            Issue::LeftoverData(_) => "recover.leftover-data",
+            Issue::BuiltWithoutUpper(_) => "recover.built-without-upper",
+            Issue::LeftoverProcess { .. } => "recover.leftover-process",
+            Issue::MissingStudentDir(_) => "recover.missing-student-dir",
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Issue::StaleMount(x) => format!("status records {} as mounted, but it is not", x.display()),
+            Issue::OrphanedOverlay(x) => format!("overlay at {} is mounted but not recorded", x.display()),
+            Issue::LeftoverData(x) => format!("{} exists without a mounted overlay", x.display()),
+            Issue::BuiltWithoutUpper(x) => format!("status records built, but the upper dir {} is empty", x.display()),
+            Issue::LeftoverProcess { pid, command, .. } => format!("process {} ({}) is still running in the sandbox", pid, command),
+            Issue::MissingStudentDir(x) => format!("a project is in progress, but {} is missing", x.display()),
+        }
+    }
+
+    fn repair_hint(&self) -> &'static str {
+        match self {
+            Issue::StaleMount(_) => "forget the mount record",
+            Issue::OrphanedOverlay(_) => "umount it",
+            Issue::LeftoverData(_) => "delete the data dir",
+            Issue::BuiltWithoutUpper(_) => "reset the built flag",
+            Issue::LeftoverProcess { .. } => "kill the process",
+            Issue::MissingStudentDir(_) => "run `tulip fetch --download-only`",
+        }
+    }
+}
+
+fn is_empty_dir(path: &Path) -> bool {
+    std::fs::read_dir(path).map(|mut x| x.next().is_none()).unwrap_or(true)
+}
+
+fn in_sandbox(pid: u32, mount_point: &Path) -> bool {
+    std::fs::read_link(format!("/proc/{}/root", pid))
+        .map(|x| x.starts_with(mount_point))
+        .unwrap_or(false)
+}
+
+fn command_of(pid: u32) -> String {
+    std::fs::read_to_string(format!("/proc/{}/comm", pid))
+        .map(|x| x.trim().to_string())
+        .unwrap_or_default()
+}
+
+fn sandbox_processes(mount_point: &Path) -> Vec<(u32, String)> {
+    let entries = match std::fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new()
+    };
+    entries.filter_map(|x| x.ok())
+        .filter_map(|x| x.file_name().to_str().and_then(|x| x.parse::<u32>().ok()))
+        .filter(|pid| in_sandbox(*pid, mount_point))
+        .map(|pid| (pid, command_of(pid)))
+        .collect()
+}
+
+/// Compare the recorded status with the mount table, the data dir and the running processes.
+pub fn check(status: &Status, workdir: &Path) -> Vec<Issue> {
+    let mut issues = Vec::new();
+    let data = workdir.join("data");
+    let data_canonical = data.canonicalize().unwrap_or_else(|_| data.clone());
+    let mounts = mounts();
+    let overlays = mounts.iter()
+        .filter(|x| x.fs_type == "overlay")
+        .filter(|x| x.option("upperdir").map(|x| Path::new(x).starts_with(data_canonical.as_path())).unwrap_or(false))
+        .collect::<Vec<_>>();
+
+    match &status.mount {
+        Some(mount) => {
+            // with a tmpfs the upper dir is not below the data dir, so look at every mount here
+            let current = mounts.iter().rev().find(|x| &x.mount_point == mount);
+            match current {
+                None => issues.push(Issue::StaleMount(mount.clone())),
+                Some(entry) => {
+                    if let Some(upper) = entry.option("upperdir").map(PathBuf::from) {
+                        if status.built && is_empty_dir(upper.as_path()) {
+                            issues.push(Issue::BuiltWithoutUpper(upper));
+                        }
+                    }
+                }
+            }
+            for (pid, command) in sandbox_processes(mount) {
+                issues.push(Issue::LeftoverProcess { pid, command, mount: mount.clone() });
+            }
+            for entry in overlays.iter().filter(|x| &x.mount_point != mount) {
+                issues.push(Issue::OrphanedOverlay(entry.mount_point.clone()));
+            }
+        }
+        None => {
+            for entry in &overlays {
+                issues.push(Issue::OrphanedOverlay(entry.mount_point.clone()));
+            }
+            if overlays.is_empty() && data.exists() && !is_empty_dir(data.as_path()) {
+                issues.push(Issue::LeftoverData(data.clone()));
+            }
+        }
+    }
+
+    if status.in_progress.is_some() && !workdir.join("student").exists() {
+        issues.push(Issue::MissingStudentDir(workdir.join("student")));
+    }
+    issues
+}
+
+fn sudo(args: &[&str], path: &Path) -> Result<(), String> {
+    let mut command = sudo_command(args);
+    command.arg(path);
+    wait(command)
+}
+
+fn sudo_args(args: &[&str]) -> Result<(), String> {
+    wait(sudo_command(args))
+}
+
+fn sudo_command(args: &[&str]) -> std::process::Command {
+    let mut command = std::process::Command::new("sudo");
+    command.arg("-k").args(args);
+    command
+}
+
+fn wait(mut command: std::process::Command) -> Result<(), String> {
+    command.spawn()
+        .and_then(|mut x| x.wait())
+        .map_err(|x| x.to_string())
+        .and_then(|x| if x.success() { Ok(()) } else { Err(format!("failed with {}", x)) })
+}
+
+pub fn umount(path: &Path) -> Result<(), String> {
+    for _ in 0..2 {
+        if mounted_under(path).is_empty() {
+            return Ok(());
+        }
+        info!("trying to umount {}", path.display());
+        if let Err(e) = sudo(&["umount", "-R"], path) {
+            warn!("umount failed: {}", e);
+        }
+    }
+    if mounted_under(path).is_empty() { Ok(()) } else { Err(format!("{} is still mounted", path.display())) }
+}
+
+fn repair(issue: &Issue, status: &mut Status) -> Result<(), String> {
+    match issue {
+        Issue::StaleMount(_) => {
+            status.mount = None;
+            status.built = false;
+            Ok(())
+        }
+        Issue::OrphanedOverlay(path) => umount(path),
+        Issue::LeftoverData(path) => {
+            if !mounted_under(path).is_empty() {
+                return Err(format!("{} is still mounted", path.display()));
+            }
+            sudo(&["rm", "-rf"], path)
+        }
+        Issue::BuiltWithoutUpper(_) => {
+            status.built = false;
+            Ok(())
+        }
+        Issue::LeftoverProcess { pid, command, mount } => {
+            // the pid may have been reused since the scan, so make sure it is still the same sandboxed process
+            if !in_sandbox(*pid, mount) || &command_of(*pid) != command {
+                return Err(format!("process {} is no longer running in the sandbox", pid));
+            }
+            sudo_args(&["kill", "-KILL", pid.to_string().as_str()])
+        }
+        Issue::MissingStudentDir(_) => Err(String::from("cannot be repaired automatically")),
+    }
+}
+
+pub fn issue_table(issues: &[Issue]) -> Table {
+    let mut table = Table::new();
+    table.add_row(row![bFb->"Issue", bFb->"Repair"]);
+    for i in issues {
+        table.add_row(row![bFr->i.describe(), i.repair_hint()]);
+    }
+    table
+}
+
+pub fn handle(db: &DB, workdir: &Path, dry_run: bool) {
+    let mut status = force_get_json::<Status>(db, "status");
+    let issues = check(&status, workdir);
+    if issues.is_empty() {
+        info!("recorded status is consistent");
+        return;
+    }
+    let shown = issues.iter()
+        .map(|x| IssueShow { kind: x.key(), description: x.describe(), repair: x.repair_hint() })
+        .collect::<Vec<_>>();
+    crate::output::emit(&shown, || { issue_table(&issues).printstd(); });
+    if dry_run {
+        return;
+    }
+    for issue in &issues {
+        if let Issue::MissingStudentDir(_) = issue {
+            warn!("{}, please {}", issue.describe(), issue.repair_hint());
+            continue;
+        }
+        let question = format!("{}, {}?", issue.describe(), issue.repair_hint());
+        if !prompt::confirm(issue.key(), question.as_str(), true) {
+            continue;
+        }
+        match repair(issue, &mut status) {
+            Ok(()) => info!("repaired: {}", issue.describe()),
+            Err(e) => error!("failed to repair \"{}\": {}", issue.describe(), e)
+        }
+    }
+    db.put("status", serde_json::to_vec(&status).exit_on_failure()).exit_on_failure();
+}
+
+/// A read-only check at startup that only warns about inconsistencies.
+pub fn startup_check(db: &DB, workdir: &Path) {
+    let status = match db.get("status").ok().flatten()
+        .and_then(|mut x| simd_json::serde::from_slice::<Status>(x.as_mut_slice()).ok()) {
+        Some(status) => status,
+        None => return
+    };
+    let issues = check(&status, workdir);
+    for i in &issues {
+        warn!("{}", i.describe());
+    }
+    if !issues.is_empty() {
+        warn!("recorded status is inconsistent, run `tulip recover` to repair it");
+    }
+}
@@ -7,42 +7,107 @@ use rocksdb::DB;
 use serde::*;
 
 use crate::clean_all::handle_clean;
-use crate::LogUnwrap;
+use crate::cli::HttpOptions;
+use crate::credential::{Authenticate, redact, Secret, Store};
+use crate::http::Dispatch;
+use crate::{force_get, LogUnwrap};
 use crate::settings::Status;
 
-#[repr(transparent)]
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RegisterResult {
-    token: String
+    token: String,
+    // servers taking signed requests acknowledge the public key, older ones only return the token
+    #[serde(default)]
+    signing: bool,
 }
 
-pub fn handle(tulip_dir: &Path, server: &str, token: &str, db: &DB, force: bool) {
-    if let Ok(Some(current)) = db.get("uuid") {
-        let current_uuid = String::from_utf8_lossy(current.as_ref());
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PublicKey {
+    public_key: String
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RekeyResult {
+    #[serde(default)]
+    signing: bool
+}
+
+/// The registration token from the option, the file or the terminal, in this order.
+pub fn token(token: Option<Secret>, token_file: Option<&Path>) -> Secret {
+    if let Some(token) = token {
+        return token;
+    }
+    match token_file {
+        Some(path) => Secret::from(std::fs::read_to_string(path).exit_on_failure().trim().to_string()),
+        None => crate::prompt::secret("Registration token:")
+    }
+}
+
+pub fn handle(tulip_dir: &Path, server: &str, token: &Secret, store: Store, http: &HttpOptions, db: &DB, force: bool) {
+    if crate::credential::registered(db) {
         if force {
-            warn!("already inited with {}, but I will do it anyway", current_uuid);
+            warn!("already registered, but I will do it anyway");
             if !handle_clean(tulip_dir.as_ref(), db, false) {
                 error!("clean failed");
                 std::process::exit(0);
             }
         } else {
-            error!("already inited with {}, exiting", current_uuid);
+            error!("already registered, exiting");
             std::process::exit(1);
         }
     }
+    // an internal CA or a proxy may be needed to reach the server at all
+    crate::http::configure(db, http, false);
     let mut seed = [0u8; 16];
     ring::rand::SystemRandom::new().fill(&mut seed).exit_on_failure();
     let config = argon2::Config::default();
-    let hash = argon2::hash_encoded(token.as_bytes(), &seed, &config).exit_on_failure();
-    let register = reqwest::blocking::Client::new()
+    let hash = argon2::hash_encoded(token.expose().as_bytes(), &seed, &config).exit_on_failure();
+    let (pkcs8, public) = crate::credential::generate();
+    let register = crate::http::client()
         .post(format!("{}/register", server).parse::<Url>().exit_on_failure())
         .bearer_auth(hash)
-        .send()
+        .json(&PublicKey { public_key: crate::credential::public_key_hex(public.as_slice()) })
+        .dispatch()
         .and_then(|x| x.error_for_status())
         .and_then(|res| res.json::<RegisterResult>())
         .exit_on_failure();
-    db.put("uuid", register.token.as_bytes()).exit_on_failure();
+    if !register.signing {
+        warn!("the server does not take signed requests, falling back to the bearer token");
+    }
     db.put("server", server).exit_on_failure();
-    info!("registered as {}", register.token);
+    crate::credential::save(db, register.token.as_str(), if register.signing { Some(pkcs8.as_slice()) } else { None },
+                            store, server);
+    info!("registered as {}", redact(register.token.as_str()));
     db.put("status", serde_json::to_string(&Status::default()).exit_on_failure()).exit_on_failure();
 }
+
+/// Replace the key pair of this client, or move a bearer client to signed requests.
+pub fn rekey(db: &DB) {
+    let server = force_get(db, "server");
+    let identity = crate::credential::identity(db);
+    if !crate::credential::signing(db) {
+        // nothing but the token proves a bearer client, whoever holds a copy of it could have rekeyed it first
+        warn!("moving a bearer client to signed requests, register again if the token may have been copied");
+    }
+    let (pkcs8, public) = crate::credential::generate();
+    // a signing client signs with the current key, so a copied token alone cannot take it over
+    let response = crate::http::client()
+        .post(format!("{}/rekey", server).parse::<Url>().exit_on_failure())
+        .json(&PublicKey { public_key: crate::credential::public_key_hex(public.as_slice()) })
+        .authenticate(&identity)
+        .dispatch()
+        .exit_on_failure();
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        error!("the server does not take signed requests, keeping the bearer token");
+        std::process::exit(1);
+    }
+    let result = response.error_for_status()
+        .and_then(|x| x.json::<RekeyResult>())
+        .exit_on_failure();
+    if !result.signing {
+        error!("the server refused the new key");
+        std::process::exit(1);
+    }
+    crate::credential::save(db, identity.token(), Some(pkcs8.as_slice()), crate::credential::current_store(db), server.as_str());
+    info!("key pair replaced");
+}
@@ -0,0 +1,288 @@
+use std::cmp::Ordering;
+
+use log::*;
+use prettytable::*;
+use rocksdb::DB;
+use serde::Serialize;
+
+use crate::{force_get, force_get_json, LogUnwrap};
+use crate::cli::RemoteQuery;
+use crate::output::emit;
+use crate::settings::Config;
+use crate::status::{remote_details, remote_ids, student_table, StudentDetail};
+
+const BUCKETS: usize = 10;
+const BAR_WIDTH: usize = 40;
+
+#[derive(Debug, Serialize)]
+pub struct Bucket {
+    pub from: f64,
+    pub to: f64,
+    pub count: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Stats {
+    pub total: usize,
+    pub graded: usize,
+    pub ungraded: usize,
+    pub marked: usize,
+    pub skipped: usize,
+    pub finished: usize,
+    pub mean: Option<f64>,
+    pub median: Option<f64>,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub max_grade: usize,
+    pub histogram: Vec<Bucket>,
+}
+
+fn grade(student: &StudentDetail) -> Option<f64> {
+    student.grades.as_ref().and_then(|x| x.graded)
+}
+
+fn marked(student: &StudentDetail) -> bool {
+    student.grades.as_ref().and_then(|x| x.mark).unwrap_or(false)
+}
+
+impl RemoteQuery {
+    /// Everything but the id pattern and the id order needs the detailed list.
+    fn needs_detail(&self) -> bool {
+        self.graded || self.ungraded || self.marked || self.skipped || self.finished || self.stats || self.sort == "grade"
+    }
+
+    fn matches(&self, student: &StudentDetail) -> bool {
+        (!self.graded || grade(student).is_some())
+            && (!self.ungraded || grade(student).is_none())
+            && (!self.marked || marked(student))
+            && (!self.skipped || student.status.skipped)
+            && (!self.finished || student.status.finished)
+            && self.id.as_ref().map(|x| x.is_match(student.student_id.as_str())).unwrap_or(true)
+    }
+
+    fn compare(&self, a: &StudentDetail, b: &StudentDetail) -> Ordering {
+        let order = match self.sort.as_str() {
+            // ungraded students go last
+            "grade" => match (grade(a), grade(b)) {
+                (Some(x), Some(y)) => x.partial_cmp(&y).unwrap_or(Ordering::Equal),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => Ordering::Equal,
+            },
+            _ => Ordering::Equal
+        };
+        let order = order.then_with(|| a.student_id.cmp(&b.student_id));
+        if self.reverse { order.reverse() } else { order }
+    }
+
+    fn page<T>(&self, mut items: Vec<T>) -> Vec<T> {
+        let page = match self.page {
+            Some(page) => page.max(1),
+            None => return items
+        };
+        let per_page = self.per_page.max(1);
+        let total = items.len();
+        info!("page {} of {}, {} student(s) in total", page, total.div_ceil(per_page).max(1), total);
+        let start = ((page - 1) * per_page).min(total);
+        items.truncate((start + per_page).min(total));
+        items.split_off(start)
+    }
+}
+
+fn median(sorted: &[f64]) -> Option<f64> {
+    match sorted.len() {
+        0 => None,
+        n if n % 2 == 0 => Some((sorted[n / 2 - 1] + sorted[n / 2]) / 2.0),
+        n => Some(sorted[n / 2])
+    }
+}
+
+fn stats(students: &[StudentDetail], max_grade: usize) -> Stats {
+    let mut grades = students.iter().filter_map(grade).collect::<Vec<_>>();
+    grades.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+    let width = max_grade as f64 / BUCKETS as f64;
+    let mut histogram = (0..BUCKETS)
+        .map(|x| Bucket { from: x as f64 * width, to: (x + 1) as f64 * width, count: 0 })
+        .collect::<Vec<_>>();
+    if max_grade > 0 {
+        for i in &grades {
+            // grades above the maximum, e.g. with a bonus, are counted in the last bucket
+            let index = ((i / width).floor().max(0.0) as usize).min(BUCKETS - 1);
+            histogram[index].count += 1;
+        }
+    }
+    Stats {
+        total: students.len(),
+        graded: grades.len(),
+        ungraded: students.len() - grades.len(),
+        marked: students.iter().filter(|x| marked(x)).count(),
+        skipped: students.iter().filter(|x| x.status.skipped).count(),
+        finished: students.iter().filter(|x| x.status.finished).count(),
+        mean: if grades.is_empty() { None } else { Some(grades.iter().sum::<f64>() / grades.len() as f64) },
+        median: median(grades.as_slice()),
+        min: grades.first().cloned(),
+        max: grades.last().cloned(),
+        max_grade,
+        histogram,
+    }
+}
+
+fn stats_table(stats: &Stats) {
+    let show = |x: Option<f64>| x.map(|x| format!("{:.2}", x)).unwrap_or_else(|| String::from("N/A"));
+    let mut table = Table::new();
+    table.add_row(row![bFb->"Students", stats.total]);
+    table.add_row(row![bFb->"Graded", bFg->stats.graded]);
+    table.add_row(row![bFb->"Ungraded", bFy->stats.ungraded]);
+    table.add_row(row![bFb->"Marked", bFr->stats.marked]);
+    table.add_row(row![bFb->"Skipped", stats.skipped]);
+    table.add_row(row![bFb->"Finished", stats.finished]);
+    table.add_row(row![bFb->"Mean", show(stats.mean)]);
+    table.add_row(row![bFb->"Median", show(stats.median)]);
+    table.add_row(row![bFb->"Min", show(stats.min)]);
+    table.add_row(row![bFb->"Max", show(stats.max)]);
+    table.printstd();
+
+    if stats.max_grade == 0 || stats.graded == 0 {
+        return;
+    }
+    let largest = stats.histogram.iter().map(|x| x.count).max().unwrap_or(0).max(1);
+    let mut histogram = Table::new();
+    histogram.add_row(row![bFb->"Grade", bFb->"Count", bFb->format!("out of {}", stats.max_grade)]);
+    for i in &stats.histogram {
+        histogram.add_row(row![format!("{:.1} - {:.1}", i.from, i.to), i.count,
+            Fg->"#".repeat(i.count * BAR_WIDTH / largest)]);
+    }
+    histogram.printstd();
+}
+
+fn id_table<S: AsRef<str>>(ids: &[S]) {
+    let mut table = Table::new();
+    table.add_row(row![bFb->"Student List"]);
+    for i in ids {
+        table.add_row(row![bFy->i.as_ref()]);
+    }
+    table.printstd();
+}
+
+pub fn handle(db: &DB, detail: bool, query: &RemoteQuery) {
+    let server = force_get(db, "server");
+    let identity = crate::credential::identity(db);
+    if !detail && !query.needs_detail() {
+        let mut ids = remote_ids(server.as_str(), &identity).exit_on_failure();
+        ids.retain(|x| query.id.as_ref().map(|y| y.is_match(x)).unwrap_or(true));
+        ids.sort();
+        if query.reverse {
+            ids.reverse();
+        }
+        let ids = query.page(ids);
+        emit(&ids, || id_table(ids.as_slice()));
+        return;
+    }
+
+    let mut students = remote_details(server.as_str(), &identity).exit_on_failure();
+    students.retain(|x| query.matches(x));
+    if query.stats {
+        let config = force_get_json::<Config>(db, "config");
+        let stats = stats(students.as_slice(), config.max_grade);
+        emit(&stats, || stats_table(&stats));
+        return;
+    }
+    students.sort_by(|a, b| query.compare(a, b));
+    let students = query.page(students);
+    if detail {
+        emit(&students, || student_table(students.as_slice()));
+    } else {
+        let ids = students.iter().map(|x| x.student_id.as_str()).collect::<Vec<_>>();
+        emit(&ids, || id_table(ids.as_slice()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::status::{StudentStatus, Submission};
+
+    use super::*;
+
+    fn student(id: &str, graded: Option<f64>) -> StudentDetail {
+        StudentDetail {
+            student_id: id.to_string(),
+            grades: graded.map(|x| Submission { graded: Some(x), ..Submission::default() }),
+            status: StudentStatus { skipped: false, finished: graded.is_some() },
+        }
+    }
+
+    fn counts(stats: &Stats) -> Vec<usize> {
+        stats.histogram.iter().map(|x| x.count).collect()
+    }
+
+    fn query(page: Option<usize>, per_page: usize) -> RemoteQuery {
+        RemoteQuery {
+            graded: false,
+            ungraded: false,
+            marked: false,
+            skipped: false,
+            finished: false,
+            id: None,
+            sort: String::from("id"),
+            reverse: false,
+            page,
+            per_page,
+            stats: false,
+        }
+    }
+
+    #[test]
+    fn median_of_odd_even_and_empty() {
+        assert_eq!(median(&[]), None);
+        assert_eq!(median(&[4.0]), Some(4.0));
+        assert_eq!(median(&[1.0, 2.0, 7.0]), Some(2.0));
+        assert_eq!(median(&[1.0, 2.0, 3.0, 7.0]), Some(2.5));
+    }
+
+    #[test]
+    fn stats_without_grades() {
+        let result = stats(&[student("a", None)], 100);
+        assert_eq!((result.total, result.graded, result.ungraded), (1, 0, 1));
+        assert_eq!((result.mean, result.median, result.min, result.max), (None, None, None, None));
+        assert_eq!(counts(&result), vec![0; BUCKETS]);
+        assert_eq!(counts(&stats(&[], 100)), vec![0; BUCKETS]);
+    }
+
+    #[test]
+    fn buckets_include_their_lower_bound() {
+        let students = [0.0, 9.99, 10.0, 55.0, 100.0].iter()
+            .enumerate()
+            .map(|(i, x)| student(i.to_string().as_str(), Some(*x)))
+            .collect::<Vec<_>>();
+        let stats = stats(students.as_slice(), 100);
+        assert_eq!(counts(&stats), vec![2, 1, 0, 0, 0, 1, 0, 0, 0, 1]);
+        assert_eq!((stats.histogram[0].from, stats.histogram[0].to), (0.0, 10.0));
+        assert_eq!(stats.histogram[BUCKETS - 1].to, 100.0);
+        assert_eq!((stats.min, stats.max, stats.median), (Some(0.0), Some(100.0), Some(10.0)));
+    }
+
+    #[test]
+    fn out_of_range_grades_go_to_the_outer_buckets() {
+        let stats = stats(&[student("a", Some(-5.0)), student("b", Some(120.0))], 100);
+        assert_eq!(counts(&stats), vec![1, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(stats.mean, Some(57.5));
+    }
+
+    #[test]
+    fn no_histogram_without_a_maximum() {
+        let stats = stats(&[student("a", Some(3.0))], 0);
+        assert_eq!(counts(&stats), vec![0; BUCKETS]);
+        assert_eq!(stats.graded, 1);
+    }
+
+    #[test]
+    fn pages_stay_inside_the_list() {
+        let items = || (1..=5).collect::<Vec<_>>();
+        assert_eq!(query(None, 2).page(items()), vec![1, 2, 3, 4, 5]);
+        assert_eq!(query(Some(1), 2).page(items()), vec![1, 2]);
+        assert_eq!(query(Some(0), 2).page(items()), vec![1, 2]);
+        assert_eq!(query(Some(3), 2).page(items()), vec![5]);
+        assert!(query(Some(4), 2).page(items()).is_empty());
+        assert_eq!(query(Some(2), 0).page(items()), vec![2]);
+    }
+}
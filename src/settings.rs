@@ -1,5 +1,6 @@
 use std::path::PathBuf;
 
+use chrono::{DateTime, Utc};
 use prettytable::*;
 use serde::*;
 use serde::export::fmt::Display;
@@ -94,6 +95,102 @@ pub struct FirejailConfig {
     pub whilelist: Vec<PathBuf>,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Comparator {
+    #[default]
+    Exact,
+    TrailingWhitespace,
+    Token,
+    CaseInsensitive,
+    Float {
+        #[serde(default)]
+        absolute: f64,
+        #[serde(default)]
+        relative: f64,
+    },
+    // every expected line is a regex that must match the whole actual line
+    Regex,
+    UnorderedLines,
+    Checker {
+        // relative path based on the chroot
+        path: PathBuf,
+        shell: Option<String>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct TestCase {
+    pub name: String,
+    // relative path based on the chroot
+    pub input: Option<PathBuf>,
+    // relative path based on the chroot
+    pub output: PathBuf,
+    #[serde(default)]
+    pub comparator: Comparator,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct CaseWeight {
+    pub case: String,
+    pub points: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct TestGroup {
+    pub name: String,
+    pub cases: Vec<String>,
+    pub points: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct ScoringPolicy {
+    // test case scores are scaled by their partial score
+    #[serde(default)]
+    pub weights: Vec<CaseWeight>,
+    // a group only gets its points when all cases in it pass
+    #[serde(default)]
+    pub groups: Vec<TestGroup>,
+    pub cap: Option<f64>,
+    #[serde(default)]
+    pub build_warning_penalty: f64,
+    #[serde(default)]
+    pub lint_penalty: f64,
+    // findings less severe than this are not penalized
+    #[serde(default)]
+    pub lint_severity: LintSeverity,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct Level {
+    pub name: String,
+    pub points: f64,
+    #[serde(default)]
+    pub description: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct Criterion {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub min: f64,
+    pub max: f64,
+    #[serde(default)]
+    pub levels: Vec<Level>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct LatePolicy {
+    // deduction for every started day after the deadline
+    pub per_day: f64,
+    // whether `per_day` is a percentage of the base score instead of points
+    #[serde(default)]
+    pub percent: bool,
+    pub max: Option<f64>,
+}
+
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub struct Config {
     pub systemd_nspawn: NSpawnConfig,
@@ -101,6 +198,72 @@ pub struct Config {
     pub notification: String,
     pub max_grade: usize,
     pub stdin: Option<PathBuf>,
+    #[serde(default)]
+    pub test_cases: Vec<TestCase>,
+    // maximum size of stdout in byte
+    #[serde(default)]
+    pub output_limit: Option<usize>,
+    #[serde(default)]
+    pub scoring: Option<ScoringPolicy>,
+    #[serde(default)]
+    pub rubric: Vec<Criterion>,
+    // scores must be a multiple of the step, e.g. 0.5 for half points
+    #[serde(default)]
+    pub grade_step: Option<f64>,
+    #[serde(default)]
+    pub deadline: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub late_policy: Option<LatePolicy>,
+    // send the shellcheck findings along with the submission
+    #[serde(default)]
+    pub lint_feedback: bool,
+    // `None` to use the built-in rules
+    #[serde(default)]
+    pub risk_rules: Option<Vec<RiskRule>>,
+    #[serde(default)]
+    pub normalize: Option<NormalizePolicy>,
+    #[serde(default)]
+    pub similarity: Option<SimilarityPolicy>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct SimilarityPolicy {
+    // tokens in a k-gram
+    pub k: usize,
+    // k-grams in a winnowing window
+    pub window: usize,
+    // extensions of the source files to fingerprint
+    pub extensions: Vec<String>,
+    // paths of the starter files relative to the project, they are not fingerprinted
+    pub templates: Vec<PathBuf>,
+}
+
+impl Default for SimilarityPolicy {
+    fn default() -> Self {
+        SimilarityPolicy {
+            k: 12,
+            window: 8,
+            extensions: ["c", "h", "cc", "cpp", "hpp", "rs", "py", "java", "go", "js", "ts", "hs", "ml", "sh"]
+                .iter().map(|x| x.to_string()).collect(),
+            templates: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct NormalizePolicy {
+    // fix line endings, byte order mark, shebang and exec bits of the build and run scripts
+    pub scripts: bool,
+    // added to scripts without one, `/bin/sh` by default
+    #[serde(default)]
+    pub shebang: Option<String>,
+    // a legacy encoding label such as `gbk`, text files which are not UTF-8 are converted from it
+    #[serde(default)]
+    pub encoding: Option<String>,
+    // extensions of the text files to convert
+    #[serde(default)]
+    pub extensions: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default)]
@@ -110,13 +273,15 @@ pub struct StudentConfig {
     pub run_shell: PathBuf,
     pub notification: String,
     pub report: Option<PathBuf>,
+    #[serde(default)]
+    pub submitted_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub struct Status {
     pub mount: Option<PathBuf>,
     pub built: bool,
-    pub graded: Option<usize>,
+    pub graded: Option<f64>,
     pub comment: Option<String>,
     pub in_progress: Option<StudentConfig>,
     pub submitted: bool,
@@ -126,20 +291,353 @@ pub struct Status {
     pub stderr: Option<String>,
     pub build_stdout: Option<String>,
     pub build_stderr: Option<String>,
+    #[serde(default)]
+    pub test_results: Vec<TestResult>,
+    #[serde(default)]
+    pub verdict: Option<Verdict>,
+    // `None` if shellcheck has not run
+    #[serde(default)]
+    pub lint: Option<Vec<LintFinding>>,
+    #[serde(default)]
+    pub suggested: Option<SuggestedGrade>,
+    #[serde(default)]
+    pub rubric: Vec<CriterionScore>,
+    #[serde(default)]
+    pub adjustments: Vec<Adjustment>,
+    #[serde(default)]
+    pub risks: Vec<RiskFinding>,
+    // found by `tulip inspect`
+    #[serde(default)]
+    pub notable: Vec<Notable>,
+    // changes made to the submission after extraction
+    #[serde(default)]
+    pub normalized: Vec<NormalizeChange>,
+    // previously seen submissions above the similarity threshold
+    #[serde(default)]
+    pub similar: Vec<SimilarPair>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SimilarPair {
+    pub student_id: String,
+    pub score: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum NormalizeAction {
+    StripBom,
+    CrlfToLf,
+    AddShebang,
+    SetExecutable,
+    Transcode,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct NormalizeChange {
+    // relative to the student dir
+    pub path: PathBuf,
+    pub action: NormalizeAction,
+    pub detail: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum NotableKind {
+    Binary,
+    Object,
+    BuildOutput,
+    Vcs,
+    Oversized,
+    NonUtf8,
+    MissingScript,
+    NotExecutable,
+    OutsideProject,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Notable {
+    pub kind: NotableKind,
+    // relative to the student dir
+    pub path: PathBuf,
+    pub detail: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RiskLevel {
+    Low,
+    Medium,
+    High,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RiskRule {
+    pub name: String,
+    // a regex matched against every line
+    pub pattern: String,
+    pub level: RiskLevel,
+    pub message: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RiskFinding {
+    pub rule: String,
+    pub level: RiskLevel,
+    // relative to the student dir
+    pub file: PathBuf,
+    pub line: usize,
+    pub text: String,
+    pub message: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AdjustmentKind {
+    Bonus,
+    LatePenalty,
+    AcademicIntegrity,
+    Other,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Adjustment {
+    pub kind: AdjustmentKind,
+    // signed, penalties are negative
+    pub points: f64,
+    pub reason: String,
+    // computed by tulip, replaced whenever the grade changes
+    #[serde(default)]
+    pub automatic: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CriterionScore {
+    pub criterion: String,
+    pub score: f64,
+    pub comment: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ScoreItem {
+    pub description: String,
+    pub points: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SuggestedGrade {
+    pub score: f64,
+    pub items: Vec<ScoreItem>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Verdict {
+    Accepted,
+    WrongAnswer,
+    TimeLimitExceeded,
+    MemoryLimitExceeded,
+    RuntimeError {
+        signal: Option<String>,
+        code: Option<i32>,
+    },
+    OutputLimitExceeded,
+    SandboxError {
+        reason: String
+    },
+}
+
+// from the most severe to the least
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, PartialOrd, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LintSeverity {
+    Error,
+    #[default]
+    Warning,
+    Info,
+    Style,
+}
+
+// as reported by `shellcheck -f json`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LintFinding {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub level: LintSeverity,
+    pub code: u32,
+    pub message: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TestResult {
+    pub name: String,
+    pub verdict: Verdict,
+    pub passed: bool,
+    pub score: f64,
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
 pub struct Submission {
-    pub graded: Option<usize>,
+    // the total after adjustments
+    pub graded: Option<f64>,
+    pub base: Option<f64>,
+    pub adjustments: Vec<Adjustment>,
     pub comment: Option<String>,
     pub mark: bool,
     pub stdout: Option<String>,
     pub stderr: Option<String>,
     pub build_stdout: Option<String>,
     pub build_stderr: Option<String>,
+    pub test_results: Vec<TestResult>,
+    pub verdict: Option<Verdict>,
+    pub suggested: Option<SuggestedGrade>,
+    pub rubric: Vec<CriterionScore>,
+    pub lint: Option<Vec<LintFinding>>,
     pub r#override: bool,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum StepMode {
+    Always,
+    Ask,
+    Skip,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Step {
+    Report,
+    Remount {
+        #[serde(default)]
+        tmp_size: Option<usize>,
+    },
+    EnterOverlay,
+    ViewBuildScript,
+    Build,
+    EnterNspawn,
+    ViewRunScript,
+    Run {
+        #[serde(default)]
+        without_build: bool,
+    },
+    Test {
+        #[serde(default)]
+        case: Option<String>,
+    },
+    EnterFirejail,
+}
+
+impl Step {
+    /// The name of the step as in the pipeline file.
+    pub fn id(&self) -> &'static str {
+        match self {
+            Step::Report => "report",
+            Step::Remount { .. } => "remount",
+            Step::EnterOverlay => "enter_overlay",
+            Step::ViewBuildScript => "view_build_script",
+            Step::Build => "build",
+            Step::EnterNspawn => "enter_nspawn",
+            Step::ViewRunScript => "view_run_script",
+            Step::Run { .. } => "run",
+            Step::Test { .. } => "test",
+            Step::EnterFirejail => "enter_firejail",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PipelineStep {
+    pub step: Step,
+    pub mode: StepMode,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Pipeline {
+    pub steps: Vec<PipelineStep>,
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        let steps = vec![
+            Step::Report,
+            Step::Remount { tmp_size: None },
+            Step::EnterOverlay,
+            Step::ViewBuildScript,
+            Step::Build,
+            Step::EnterNspawn,
+            Step::ViewRunScript,
+            Step::Run { without_build: false },
+            Step::Test { case: None },
+            Step::EnterFirejail,
+        ];
+        Pipeline {
+            steps: steps.into_iter().map(|step| PipelineStep { step, mode: StepMode::Ask }).collect()
+        }
+    }
+}
+
+impl Pipeline {
+    /// Hex SHA-256 digest of the pipeline, the saved progress only holds for the same one.
+    pub fn digest(&self) -> String {
+        let json = serde_json::to_vec(self).unwrap_or_default();
+        ring::digest::digest(&ring::digest::SHA256, json.as_slice()).as_ref().iter()
+            .map(|x| format!("{:02x}", x))
+            .collect()
+    }
+}
+
+/// A student `batch --hold` gave back for review, the server only records the skip.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HeldStudent {
+    pub student_id: String,
+    pub reasons: Vec<String>,
+    pub held_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct PipelineProgress {
+    pub student_id: String,
+    // digest of the pipeline the steps were finished in
+    pub pipeline: String,
+    // ids of the finished steps, in order
+    pub finished: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct HttpSettings {
+    // a PEM bundle of certificates trusted besides the system ones
+    pub ca_bundle: Option<PathBuf>,
+    // base64 SHA-256 digests of subject public key infos, the server must present one of them
+    pub pins: Vec<String>,
+    pub proxy: Option<String>,
+    // in seconds
+    pub connect_timeout: Option<u64>,
+    // of a whole request, including the download of its body
+    pub timeout: Option<u64>,
+    // attempts after the first one, only for requests which can safely be sent again
+    pub retries: u32,
+    // delay before the first retry in milliseconds, doubled after each one
+    pub backoff: u64,
+}
+
+impl Default for HttpSettings {
+    fn default() -> Self {
+        HttpSettings {
+            ca_bundle: None,
+            pins: Vec::new(),
+            proxy: None,
+            connect_timeout: Some(10),
+            timeout: None,
+            retries: 3,
+            backoff: 500,
+        }
+    }
+}
+
 trait ToTableItem {
     fn to_table_item(&self) -> Box<dyn Display>;
 }
@@ -205,15 +703,27 @@ impl ToTableItem for Value {
 
 
 impl Status {
-    pub fn get_submission(&self, r#override: bool) -> Submission {
+    /// The base grade with every adjustment applied, never below zero.
+    pub fn total(&self) -> Option<f64> {
+        self.graded.map(|x| (x + self.adjustments.iter().map(|x| x.points).sum::<f64>()).max(0.0))
+    }
+
+    pub fn get_submission(&self, r#override: bool, lint_feedback: bool) -> Submission {
         Submission {
-            graded: self.graded.clone(),
+            graded: self.total(),
+            base: self.graded,
+            adjustments: self.adjustments.clone(),
             comment: self.comment.clone(),
             mark: self.mark,
             stdout: self.stdout.clone(),
             stderr: self.stderr.clone(),
             build_stdout: self.build_stderr.clone(),
             build_stderr: self.build_stderr.clone(),
+            test_results: self.test_results.clone(),
+            verdict: self.verdict.clone(),
+            suggested: self.suggested.clone(),
+            rubric: self.rubric.clone(),
+            lint: if lint_feedback { self.lint.clone() } else { None },
             r#override,
         }
     }
@@ -0,0 +1,536 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use log::*;
+use prettytable::*;
+use rocksdb::DB;
+use serde::*;
+
+use crate::{force_get_json, LogUnwrap};
+use crate::cache::Cache;
+use crate::settings::{Config, SimilarPair, SimilarityPolicy, Status};
+use crate::validate::url_id;
+
+// larger files are usually generated or data, not worth comparing
+const MAX_FILE_SIZE: u64 = 256 * 1024;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FileEntry {
+    pub path: PathBuf,
+    // selected fingerprints with the line they start at
+    pub fingerprints: Vec<(u64, usize)>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Entry {
+    pub student_id: String,
+    pub indexed_at: chrono::DateTime<chrono::Utc>,
+    // hash of the cached tarball the source is read from again
+    #[serde(default)]
+    pub tarball: Option<String>,
+    pub files: Vec<FileEntry>,
+}
+
+impl Entry {
+    fn hashes(&self) -> HashSet<u64> {
+        self.files.iter().flat_map(|x| x.fingerprints.iter().map(|x| x.0)).collect()
+    }
+}
+
+fn index_dir(workdir: &Path) -> PathBuf {
+    workdir.join("similarity")
+}
+
+fn entry_path(workdir: &Path, student_id: &str) -> PathBuf {
+    index_dir(workdir).join(format!("{}.json", url_id(student_id)))
+}
+
+fn policy(config: Option<&Config>) -> SimilarityPolicy {
+    config.and_then(|x| x.similarity.clone()).unwrap_or_default()
+}
+
+/// Split the source into tokens with their line numbers. Identifiers, numbers and strings are
+/// replaced by a placeholder so renaming does not hide a copy, comments and whitespace are dropped.
+fn tokens(source: &str) -> Vec<(String, usize)> {
+    let chars = source.chars().collect::<Vec<_>>();
+    let mut result = Vec::new();
+    let mut line = 1;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c == '\n' {
+            line += 1;
+            i += 1;
+        } else if c.is_whitespace() {
+            i += 1;
+        } else if (c == '/' && chars.get(i + 1) == Some(&'/')) || c == '#' {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+        } else if c == '/' && chars.get(i + 1) == Some(&'*') {
+            i += 2;
+            while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                if chars[i] == '\n' {
+                    line += 1;
+                }
+                i += 1;
+            }
+            i += 2;
+        } else if c.is_alphabetic() || c == '_' {
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            result.push((String::from("i"), line));
+        } else if c.is_ascii_digit() {
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '.') {
+                i += 1;
+            }
+            result.push((String::from("n"), line));
+        } else if c == '"' || c == '\'' {
+            let start = line;
+            i += 1;
+            while i < chars.len() && chars[i] != c {
+                if chars[i] == '\\' {
+                    i += 1;
+                } else if chars[i] == '\n' {
+                    line += 1;
+                }
+                i += 1;
+            }
+            i += 1;
+            result.push((String::from("s"), start));
+        } else {
+            result.push((c.to_string(), line));
+            i += 1;
+        }
+    }
+    result
+}
+
+fn fnv(data: &[&str]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for token in data {
+        for byte in token.bytes().chain(std::iter::once(0)) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+/// Winnowing (Schleimer et al.): hash every k-gram of tokens and keep the minimum of each window.
+fn winnow(tokens: &[(String, usize)], k: usize, window: usize) -> Vec<(u64, usize)> {
+    if k == 0 || tokens.len() < k {
+        return Vec::new();
+    }
+    let grams = tokens.windows(k)
+        .map(|x| (fnv(x.iter().map(|x| x.0.as_str()).collect::<Vec<_>>().as_slice()), x[0].1))
+        .collect::<Vec<_>>();
+    let window = window.max(1).min(grams.len());
+    let mut selected: Vec<(u64, usize)> = Vec::new();
+    let mut last = None;
+    for start in 0..=grams.len() - window {
+        // the rightmost minimum, so equal hashes in a row are only recorded once
+        let (index, gram) = grams[start..start + window].iter().enumerate()
+            .fold(None, |acc: Option<(usize, &(u64, usize))>, (i, x)| match acc {
+                Some((_, y)) if y.0 < x.0 => acc,
+                _ => Some((start + i, x)),
+            })
+            .unwrap();
+        if last != Some(index) {
+            selected.push(*gram);
+            last = Some(index);
+        }
+    }
+    selected
+}
+
+fn is_template(policy: &SimilarityPolicy, path: &Path) -> bool {
+    policy.templates.iter().any(|x| path == x.as_path())
+}
+
+fn collect(policy: &SimilarityPolicy, dir: &Path, relative: &Path, files: &mut Vec<FileEntry>) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return
+    };
+    for entry in entries.filter_map(|x| x.ok()) {
+        let name = entry.file_name();
+        let path = entry.path();
+        let relative = relative.join(&name);
+        let meta = match std::fs::symlink_metadata(path.as_path()) {
+            Ok(meta) => meta,
+            Err(_) => continue
+        };
+        if meta.is_dir() && name != ".git" {
+            collect(policy, path.as_path(), relative.as_path(), files);
+            continue;
+        }
+        let matched = path.extension()
+            .and_then(|x| x.to_str())
+            .map(|x| policy.extensions.iter().any(|y| y.eq_ignore_ascii_case(x)))
+            .unwrap_or(false);
+        if !meta.is_file() || !matched || meta.len() > MAX_FILE_SIZE || is_template(policy, relative.as_path()) {
+            continue;
+        }
+        let source = match std::fs::read(path.as_path()) {
+            Ok(x) => String::from_utf8_lossy(x.as_slice()).to_string(),
+            Err(_) => continue
+        };
+        let fingerprints = winnow(tokens(source.as_str()).as_slice(), policy.k, policy.window);
+        if fingerprints.is_empty() {
+            continue;
+        }
+        files.push(FileEntry {
+            path: relative,
+            fingerprints,
+        });
+    }
+}
+
+/// Fingerprint the current student and save it into the index, only fingerprints and line numbers are kept.
+pub fn index(config: Option<&Config>, status: &Status, workdir: &Path, tarball: Option<String>) {
+    let student_id = match status.in_progress.as_ref() {
+        Some(x) => x.student_id.as_str(),
+        None => return
+    };
+    let policy = policy(config);
+    let mut files = Vec::new();
+    collect(&policy, workdir.join("student").as_path(), Path::new(""), &mut files);
+    let entry = Entry {
+        student_id: student_id.to_string(),
+        indexed_at: chrono::Utc::now(),
+        tarball,
+        files,
+    };
+    let result = std::fs::create_dir_all(index_dir(workdir))
+        .map_err(|x| x.to_string())
+        .and_then(|_| serde_json::to_vec(&entry).map_err(|x| x.to_string()))
+        .and_then(|x| std::fs::write(entry_path(workdir, student_id), x).map_err(|x| x.to_string()));
+    match result {
+        Ok(()) => debug!("{} file(s) of {} fingerprinted", entry.files.len(), student_id),
+        Err(e) => warn!("failed to update the similarity index: {}", e)
+    }
+}
+
+fn load(path: &Path) -> Option<Entry> {
+    let mut content = std::fs::read(path).ok()?;
+    simd_json::serde::from_slice::<Entry>(content.as_mut_slice())
+        .map_err(|x| warn!("ignoring broken index entry {}: {}", path.display(), x))
+        .ok()
+}
+
+struct Region {
+    file: usize,
+    lines: (usize, usize),
+    other_file: usize,
+    other_lines: (usize, usize),
+}
+
+/// Merge the lines of shared fingerprints into regions of both submissions.
+fn regions(current: &Entry, other: &Entry) -> Vec<Region> {
+    let mut positions = HashMap::<u64, Vec<(usize, usize)>>::new();
+    for (file, entry) in other.files.iter().enumerate() {
+        for (hash, line) in &entry.fingerprints {
+            positions.entry(*hash).or_default().push((file, *line));
+        }
+    }
+    let mut pairs = Vec::new();
+    for (file, entry) in current.files.iter().enumerate() {
+        for (hash, line) in &entry.fingerprints {
+            if let Some((other_file, other_line)) = positions.get(hash).and_then(|x| x.first()) {
+                pairs.push((file, *other_file, *line, *other_line));
+            }
+        }
+    }
+    pairs.sort_unstable();
+    let mut result: Vec<Region> = Vec::new();
+    for (file, other_file, line, other_line) in pairs {
+        if let Some(last) = result.last_mut() {
+            if last.file == file && last.other_file == other_file && line <= last.lines.1 + 3
+                && other_line + 3 >= last.other_lines.0 && other_line <= last.other_lines.1 + 3 {
+                last.lines.1 = last.lines.1.max(line);
+                last.other_lines.0 = last.other_lines.0.min(other_line);
+                last.other_lines.1 = last.other_lines.1.max(other_line);
+                continue;
+            }
+        }
+        result.push(Region { file, lines: (line, line), other_file, other_lines: (other_line, other_line) });
+    }
+    result
+}
+
+/// Lines of a file of the project, empty if the source is not available.
+fn source_lines(dir: Option<&Path>, path: &Path) -> Vec<String> {
+    dir.and_then(|x| crate::validate::contained(x, path).ok())
+        .and_then(|x| std::fs::read(x).ok())
+        .map(|x| String::from_utf8_lossy(x.as_slice()).lines().map(|x| x.to_string()).collect())
+        .unwrap_or_default()
+}
+
+fn snippet(lines: &[String], range: (usize, usize)) -> String {
+    // a fingerprint starts at its line and covers a few more
+    let end = (range.1 + 2).min(lines.len());
+    (range.0..=end)
+        .filter_map(|x| lines.get(x - 1).map(|y| format!("{:>4} {}", x, y)))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn regions_table(current: &Entry, current_dir: &Path, other: &Entry, other_dir: Option<&Path>) -> Table {
+    let mut table = Table::new();
+    table.add_row(row![bFb->current.student_id, bFb->other.student_id]);
+    for region in regions(current, other) {
+        let a = &current.files[region.file];
+        let b = &other.files[region.other_file];
+        table.add_row(row![
+            bFy->format!("{}:{}-{}", a.path.display(), region.lines.0, region.lines.1),
+            bFy->format!("{}:{}-{}", b.path.display(), region.other_lines.0, region.other_lines.1)
+        ]);
+        let a_lines = source_lines(Some(current_dir), a.path.as_path());
+        let b_lines = source_lines(other_dir, b.path.as_path());
+        table.add_row(row![snippet(a_lines.as_slice(), region.lines), snippet(b_lines.as_slice(), region.other_lines)]);
+    }
+    table
+}
+
+#[derive(Serialize)]
+struct MatchShow<'a> {
+    student_id: &'a str,
+    shared: usize,
+    score: f64,
+    indexed_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Serialize)]
+struct RegionShow<'a> {
+    student_id: &'a str,
+    file: &'a Path,
+    lines: (usize, usize),
+    other_file: &'a Path,
+    other_lines: (usize, usize),
+}
+
+#[derive(Serialize)]
+struct SimilarityShow<'a> {
+    matches: Vec<MatchShow<'a>>,
+    regions: Vec<RegionShow<'a>>,
+}
+
+pub struct Similarity {
+    pub top: usize,
+    pub threshold: f64,
+    pub show: Option<String>,
+    pub mark: bool,
+}
+
+impl Similarity {
+    pub fn handle(&self, db: &DB, cache: &Cache, workdir: &Path) {
+        let mut status = force_get_json::<Status>(db, "status");
+        let student_id = match status.in_progress.as_ref() {
+            Some(x) => x.student_id.clone(),
+            None => {
+                error!("no current project");
+                std::process::exit(1);
+            }
+        };
+        let path = entry_path(workdir, student_id.as_str());
+        if !path.exists() {
+            let config = db.get("config").ok().flatten()
+                .and_then(|mut x| simd_json::serde::from_slice::<Config>(x.as_mut_slice()).ok());
+            let tarball = cache.versions(student_id.as_str()).first().map(|x| x.hash.clone());
+            index(config.as_ref(), &status, workdir, tarball);
+        }
+        let current = load(path.as_path()).unwrap_or_else(|| {
+            error!("unable to fingerprint the current project");
+            std::process::exit(1);
+        });
+        let hashes = current.hashes();
+        if hashes.is_empty() {
+            warn!("no source files to compare");
+            return;
+        }
+
+        let mut matches = std::fs::read_dir(index_dir(workdir))
+            .exit_on_failure()
+            .filter_map(|x| x.ok())
+            .map(|x| x.path())
+            .filter(|x| x != &path)
+            .filter_map(|x| load(x.as_path()))
+            .map(|x| {
+                let shared = x.hashes().intersection(&hashes).count();
+                // shared fingerprints relative to the smaller submission, so a partial copy still stands out
+                let score = shared as f64 / x.hashes().len().min(hashes.len()).max(1) as f64;
+                (score, shared, x)
+            })
+            .filter(|x| x.1 > 0)
+            .collect::<Vec<_>>();
+        matches.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+
+        let shown = match &self.show {
+            Some(id) => matches.iter().find(|x| &x.2.student_id == id).map(|x| &x.2),
+            None => matches.first().filter(|x| x.0 >= self.threshold).map(|x| &x.2),
+        };
+        if let (None, Some(id)) = (shown, &self.show) {
+            warn!("no shared fingerprints with {}", id);
+        }
+        let show = SimilarityShow {
+            matches: matches.iter().take(self.top)
+                .map(|(score, shared, entry)| MatchShow {
+                    student_id: entry.student_id.as_str(),
+                    shared: *shared,
+                    score: *score,
+                    indexed_at: entry.indexed_at,
+                })
+                .collect(),
+            regions: shown.map(|other| regions(&current, other).into_iter()
+                .map(|x| RegionShow {
+                    student_id: other.student_id.as_str(),
+                    file: current.files[x.file].path.as_path(),
+                    lines: x.lines,
+                    other_file: other.files[x.other_file].path.as_path(),
+                    other_lines: x.other_lines,
+                })
+                .collect())
+                .unwrap_or_default(),
+        };
+        crate::output::emit(&show, || {
+            let mut table = Table::new();
+            table.add_row(row![bFb->"ID", bFb->"Shared", bFb->"Similarity", bFb->"Indexed At"]);
+            for i in &show.matches {
+                let percent = format!("{:.1}%", i.score * 100.0);
+                if i.score >= self.threshold {
+                    table.add_row(row![i.student_id, i.shared, bFr->percent, i.indexed_at]);
+                } else {
+                    table.add_row(row![i.student_id, i.shared, percent, i.indexed_at]);
+                }
+            }
+            table.printstd();
+
+            if let Some(other) = shown {
+                let cached = cache.versions(other.student_id.as_str()).into_iter()
+                    .find(|x| Some(&x.hash) == other.tarball.as_ref());
+                if cached.is_none() {
+                    warn!("the tarball of {} is no longer cached, showing the line numbers only", other.student_id);
+                }
+                let extracted = cached.map(|x| crate::diff::extract(cache.blob_path(&x).as_path()));
+                let student_dir = workdir.join("student");
+                regions_table(&current, student_dir.as_path(), other, extracted.as_ref().map(|x| x.path()))
+                    .printstd();
+            }
+        });
+
+        let strong = matches.iter()
+            .filter(|x| x.0 >= self.threshold)
+            .map(|x| SimilarPair { student_id: x.2.student_id.clone(), score: x.0 })
+            .collect::<Vec<_>>();
+        if !strong.is_empty() {
+            warn!("{} submission(s) are at least {:.0}% similar", strong.len(), self.threshold * 100.0);
+            if self.mark {
+                status.mark = true;
+                info!("marked for review");
+            }
+        }
+        status.similar = strong;
+        db.put("status", serde_json::to_string(&status).exit_on_failure()).exit_on_failure();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(files: Vec<Vec<(u64, usize)>>) -> Entry {
+        Entry {
+            student_id: String::new(),
+            indexed_at: chrono::Utc::now(),
+            tarball: None,
+            files: files.into_iter().enumerate()
+                .map(|(i, fingerprints)| FileEntry { path: PathBuf::from(format!("{}.c", i)), fingerprints })
+                .collect(),
+        }
+    }
+
+    fn kinds(source: &str) -> Vec<String> {
+        tokens(source).into_iter().map(|x| x.0).collect()
+    }
+
+    #[test]
+    fn renaming_and_comments_do_not_change_the_tokens() {
+        let original = "int sum(int a, int b) { return a + b; }";
+        let renamed = "// adds\nlong total(long x, long y) {\n  /* the sum */ return x + y; # done\n}";
+        assert_eq!(kinds(original), kinds(renamed));
+        assert_eq!(kinds("x = 1.5e3; y = \"a\\\"b\";"), kinds("z = 42; w = 'c';"));
+    }
+
+    #[test]
+    fn tokens_keep_their_line() {
+        let lines = tokens("a\n/* one\ntwo */ b\n\"multi\nline\" c").into_iter().map(|x| x.1).collect::<Vec<_>>();
+        assert_eq!(lines, vec![1, 3, 4, 5]);
+    }
+
+    #[test]
+    fn unterminated_tokens_end_the_source() {
+        assert!(tokens("").is_empty());
+        assert_eq!(kinds("a /* open"), vec!["i"]);
+        assert_eq!(kinds("a \"open\\"), vec!["i", "s"]);
+        assert_eq!(kinds("/"), vec!["/"]);
+    }
+
+    #[test]
+    fn winnowing_short_or_empty_input() {
+        let tokens = tokens("a + b");
+        assert!(winnow(&[], 3, 4).is_empty());
+        assert!(winnow(tokens.as_slice(), 4, 4).is_empty());
+        assert!(winnow(tokens.as_slice(), 0, 4).is_empty());
+        assert_eq!(winnow(tokens.as_slice(), 3, 4).len(), 1);
+    }
+
+    #[test]
+    fn winnowing_keeps_one_minimum_per_window() {
+        let tokens = tokens("for (i = 0; i < n; i++) { s += a[i] * b[i]; }");
+        let grams = tokens.len() - 2;
+        assert_eq!(winnow(tokens.as_slice(), 3, 1).len(), grams);
+        assert_eq!(winnow(tokens.as_slice(), 3, 0).len(), grams);
+        assert_eq!(winnow(tokens.as_slice(), 3, grams + 10).len(), 1);
+        let selected = winnow(tokens.as_slice(), 3, 4);
+        assert!(!selected.is_empty() && selected.len() < grams);
+        // every window of 4 k-grams contains a selected one
+        assert!(selected.len() >= grams / 4);
+        assert_eq!(selected, winnow(tokens.as_slice(), 3, 4));
+    }
+
+    #[test]
+    fn repeated_code_is_recorded_once() {
+        let tokens = tokens("x; x; x; x; x; x; x; x;");
+        let selected = winnow(tokens.as_slice(), 2, 3);
+        // every window holds both k-grams, only the smaller one is kept
+        assert_eq!(selected.iter().map(|x| x.0).collect::<HashSet<_>>().len(), 1);
+        assert!(selected.len() < tokens.len() - 1);
+    }
+
+    #[test]
+    fn shared_fingerprints_merge_into_regions() {
+        let current = entry(vec![vec![(1, 10), (2, 11), (3, 13), (9, 40)], vec![(4, 1)]]);
+        let other = entry(vec![vec![(1, 20), (2, 21), (3, 24), (4, 30)]]);
+        let regions = regions(&current, &other);
+        assert_eq!(regions.len(), 2);
+        assert_eq!((regions[0].file, regions[0].lines, regions[0].other_file, regions[0].other_lines), (0, (10, 13), 0, (20, 24)));
+        assert_eq!((regions[1].file, regions[1].lines, regions[1].other_lines), (1, (1, 1), (30, 30)));
+    }
+
+    #[test]
+    fn no_regions_without_shared_fingerprints() {
+        assert!(regions(&entry(vec![vec![(1, 1)]]), &entry(vec![vec![(2, 1)]])).is_empty());
+        assert!(regions(&entry(Vec::new()), &entry(vec![vec![(2, 1)]])).is_empty());
+    }
+
+    #[test]
+    fn snippets_stay_inside_the_file() {
+        let lines = ["a", "b", "c", "d"].iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        assert_eq!(snippet(&lines, (3, 3)), "   3 c\n   4 d");
+        assert_eq!(snippet(&lines, (1, 1)), "   1 a\n   2 b\n   3 c");
+        assert_eq!(snippet(&lines, (9, 9)), "");
+        assert_eq!(snippet(&[], (1, 2)), "");
+    }
+}
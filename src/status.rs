@@ -1,4 +1,4 @@
-use std::io::{Read, stdin, Write};
+use std::io::{Read, Write};
 use std::path::Path;
 
 use log::*;
@@ -8,8 +8,23 @@ use rocksdb::DB;
 use serde::*;
 
 use crate::{force_get, force_get_json, LogUnwrap};
+use crate::credential::{Authenticate, Identity};
+use crate::http::Dispatch;
 use crate::cli::StatusWatch;
+use crate::output::emit;
 use crate::settings::*;
+use crate::validate::url_id;
+
+#[derive(Serialize)]
+struct Uuid<'a> {
+    uuid: &'a str,
+    auth: &'a str,
+}
+
+#[derive(Serialize)]
+struct Server<'a> {
+    server: &'a str,
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct StudentList {
@@ -18,27 +33,63 @@ pub struct StudentList {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct StudentStatus {
-    skipped: bool,
-    finished: bool,
+    pub skipped: bool,
+    pub finished: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
 pub struct Submission {
     pub mark: Option<bool>,
-    // TODO: FIXME
-    pub graded: Option<usize>,
+    pub graded: Option<f64>,
     pub comment: Option<String>,
     pub stdout: Option<String>,
     pub stderr: Option<String>,
     pub build_stdout: Option<String>,
     pub build_stderr: Option<String>,
+    // missing in grades recorded by older clients
+    #[serde(default)]
+    pub base: Option<f64>,
+    #[serde(default)]
+    pub adjustments: Vec<Adjustment>,
+    #[serde(default)]
+    pub test_results: Vec<TestResult>,
+    #[serde(default)]
+    pub verdict: Option<Verdict>,
+    #[serde(default)]
+    pub suggested: Option<SuggestedGrade>,
+    #[serde(default)]
+    pub rubric: Vec<CriterionScore>,
+    #[serde(default)]
+    pub lint: Option<Vec<LintFinding>>,
+}
+
+impl From<Submission> for crate::settings::Submission {
+    fn from(x: Submission) -> Self {
+        crate::settings::Submission {
+            graded: x.graded,
+            base: x.base.or(x.graded),
+            adjustments: x.adjustments,
+            comment: x.comment,
+            mark: x.mark.unwrap_or(false),
+            stdout: x.stdout,
+            stderr: x.stderr,
+            build_stdout: x.build_stdout,
+            build_stderr: x.build_stderr,
+            test_results: x.test_results,
+            verdict: x.verdict,
+            suggested: x.suggested,
+            rubric: x.rubric,
+            lint: x.lint,
+            r#override: false,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct StudentDetail {
-    student_id: String,
-    grades: Option<Submission>,
-    status: StudentStatus,
+    pub student_id: String,
+    pub grades: Option<Submission>,
+    pub status: StudentStatus,
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
@@ -52,6 +103,9 @@ pub fn in_progress_table(student: &StudentConfig) -> Table {
     table.add_row(row![bFb->"Notification", bFr->student.notification.as_str()]);
     table.add_row(row![bFb->"Build Shell", bFr->student.build_shell.to_string_lossy()]);
     table.add_row(row![bFb->"Run Shell", bFr->student.run_shell.to_string_lossy()]);
+    if let Some(time) = &student.submitted_at {
+        table.add_row(row![bFb->"Submitted At", bFr->time]);
+    }
     table
 }
 
@@ -61,13 +115,47 @@ pub fn current_table(status: &Status, io_data: bool) {
         .unwrap_or_else(||String::from("N/A"))]);
     table.add_row(row![bFb->"Built", bFr->status.built]);
     table.add_row(row![bFb->"Grade", bFr->status.graded.map(|x|x.to_string()).unwrap_or_else(|| String::from("N/A"))]);
+    if !status.adjustments.is_empty() {
+        table.add_row(row![bFb->"Adjustments", crate::grade::score_table(status)]);
+    }
+    if !status.rubric.is_empty() {
+        table.add_row(row![bFb->"Rubric", crate::rubric::rubric_table(&status.rubric)]);
+    }
     table.add_row(row![bFb->"Comment", bFr->status.comment.clone().unwrap_or_else(String::new)]);
     table.add_row(row![bFb->"Submitted", bFr->status.submitted]);
     table.add_row(row![bFb->"Image Ready", bFr->status.image]);
     table.add_row(row![bFb->"Mark", bFr->status.mark]);
+    if let Some(verdict) = &status.verdict {
+        table.add_row(Row::new(vec![Cell::new("Verdict").style_spec("bFb"), verdict.cell()]));
+    }
     if let Some(student) = &status.in_progress {
         table.add_row(row![bFb->"In Progress", bFr->in_progress_table(student)]);
     }
+    if !status.test_results.is_empty() {
+        table.add_row(row![bFb->"Test Results", crate::judge::results_table(&status.test_results)]);
+    }
+    if !status.similar.is_empty() {
+        let pairs = status.similar.iter()
+            .map(|x| format!("{} ({:.1}%)", x.student_id, x.score * 100.0))
+            .collect::<Vec<_>>()
+            .join("\n");
+        table.add_row(row![bFb->"Similar To", bFr->pairs]);
+    }
+    if !status.normalized.is_empty() {
+        table.add_row(row![bFb->"Normalized", crate::normalize::manifest_table(&status.normalized)]);
+    }
+    if !status.notable.is_empty() {
+        table.add_row(row![bFb->"Notable Files", crate::inspect::notable_table(&status.notable)]);
+    }
+    if !status.risks.is_empty() {
+        table.add_row(row![bFb->"Risks", crate::risk::risk_table(&status.risks)]);
+    }
+    if let Some(findings) = &status.lint {
+        table.add_row(row![bFb->"Shellcheck", crate::lint::lint_table(findings)]);
+    }
+    if let Some(suggested) = &status.suggested {
+        table.add_row(row![bFb->"Suggested Grade", crate::scoring::suggestion_table(suggested)]);
+    }
     if io_data {
         table.add_row(row![bFb->"Stdout", bFr->status.stdout.clone().unwrap_or_else(String::new)]);
         table.add_row(row![bFb->"Stderr", bFr->status.stderr.clone().unwrap_or_else(String::new)]);
@@ -77,7 +165,7 @@ pub fn current_table(status: &Status, io_data: bool) {
     table.printstd();
 }
 
-pub fn student_table(data: &Vec<StudentDetail>) {
+pub fn student_table(data: &[StudentDetail]) {
 
     // Create the table
     let mut table = Table::new();
@@ -94,61 +182,58 @@ pub fn student_table(data: &Vec<StudentDetail>) {
     table.printstd();
 }
 
+pub fn remote_ids(server: &str, identity: &Identity) -> Result<Vec<String>, String> {
+    crate::http::client()
+        .get(format!("{}/students", server).parse::<Url>().map_err(|x| x.to_string())?)
+        .authenticate(identity)
+        .dispatch_idempotent()
+        .and_then(|x| x.error_for_status())
+        .and_then(|x| x.json::<StudentList>())
+        .map(|x| x.students)
+        .map_err(|x| x.to_string())
+}
+
+pub fn remote_details(server: &str, identity: &Identity) -> Result<Vec<StudentDetail>, String> {
+    crate::http::client()
+        .get(format!("{}/students?detail", server).parse::<Url>().map_err(|x| x.to_string())?)
+        .authenticate(identity)
+        .dispatch_idempotent()
+        .and_then(|x| x.error_for_status())
+        .and_then(|x| x.json::<DetailResponse>())
+        .map(|x| x.students)
+        .map_err(|x| x.to_string())
+}
+
 pub fn handle(db: &DB, command: StatusWatch, workdir: &Path) {
     match command {
         StatusWatch::Global => {
             let ans = force_get_json::<Config>(db, "config");
-            let a = to_table(&ans).exit_on_failure();
-            a.printstd();
+            emit(&ans, || {
+                to_table(&ans).exit_on_failure().printstd();
+            });
         }
         StatusWatch::Current { io_data } => {
             let ans = force_get_json::<Status>(db, "status");
-            current_table(&ans, io_data);
+            emit(&ans, || current_table(&ans, io_data));
         }
-        StatusWatch::Remote { detail } => {
-            let server = force_get(db, "server");
-            let uuid = force_get(db, "uuid");
-            let client = reqwest::blocking::Client::new();
-            if !detail {
-                let ans = client.get(format!("{}/students", server).parse::<Url>().exit_on_failure())
-                    .bearer_auth(uuid)
-                    .send()
-                    .exit_on_failure()
-                    .error_for_status()
-                    .exit_on_failure()
-                    .json::<StudentList>()
-                    .exit_on_failure();
-                let mut table = Table::new();
-                table.add_row(row![bFb->"Student List"]);
-                for i in ans.students {
-                    table.add_row(row![bFy->i.as_str()]);
-                }
-                table.printstd();
-            } else {
-                let ans = client.get(format!("{}/students?detail", server).parse::<Url>().exit_on_failure())
-                    .bearer_auth(uuid)
-                    .send()
-                    .exit_on_failure()
-                    .error_for_status()
-                    .exit_on_failure()
-                    .json::<DetailResponse>()
-                    .exit_on_failure();
-                student_table(&ans.students);
-            }
+        StatusWatch::Remote { detail, query } => {
+            crate::remote::handle(db, detail, &query);
         }
         StatusWatch::RemoteID { id } => {
             let server = force_get(db, "server");
-            let uuid = force_get(db, "uuid");
-            let ans = reqwest::blocking::Client::new()
-                .get(format!("{}/student/{}/info", server, id).parse::<Url>().exit_on_failure())
-                .bearer_auth(uuid)
-                .send()
+            let identity = crate::credential::identity(db);
+            let ans = crate::http::client()
+                .get(format!("{}/student/{}/info", server, url_id(id.as_str())).parse::<Url>().exit_on_failure())
+                .authenticate(&identity)
+                .dispatch_idempotent()
                 .exit_on_failure()
                 .error_for_status()
                 .exit_on_failure()
                 .json::<StudentConfig>()
                 .exit_on_failure();
-            to_table(&ans).exit_on_failure().printstd();
+            emit(&ans, || {
+                to_table(&ans).exit_on_failure().printstd();
+            });
         }
         StatusWatch::EditCurrent { editor } => {
             let mut status = db.get("status")
@@ -200,44 +285,84 @@ pub fn handle(db: &DB, command: StatusWatch, workdir: &Path) {
             db.put("config", serde_json::to_string(&config)
                 .exit_on_failure()).exit_on_failure();
         }
-        StatusWatch::Uuid => {
-            let uuid = force_get(db, "uuid");
-            println!("uuid: {}", uuid);
+        StatusWatch::EditPipeline { editor } => {
+            let mut pipeline = serde_json::to_vec_pretty(&crate::pipeline::get_pipeline(db))
+                .exit_on_failure();
+            let mut file = tempfile::NamedTempFile::new()
+                .exit_on_failure();
+            file.write_all(pipeline.as_slice()).exit_on_failure();
+            file.flush().exit_on_failure();
+            std::process::Command::new(editor)
+                .arg(file.path())
+                .spawn()
+                .exit_on_failure()
+                .wait()
+                .map_err(|x| x.to_string())
+                .and_then(|x| if x.success() { Ok(()) } else { Err(format!("editor exit with error: {}", x)) })
+                .exit_on_failure();
+            pipeline.clear();
+            file.reopen().exit_on_failure().read_to_end(&mut pipeline).exit_on_failure();
+            let pipeline = simd_json::serde::from_slice::<Pipeline>(pipeline.as_mut_slice()).exit_on_failure();
+            db.put("pipeline", serde_json::to_string(&pipeline)
+                .exit_on_failure()).exit_on_failure();
+        }
+        StatusWatch::Held => {
+            let held = crate::batch::held(db);
+            emit(&held, || {
+                crate::batch::held_table(held.as_slice()).printstd();
+            });
+        }
+        StatusWatch::Uuid { reveal } => {
+            let identity = crate::credential::identity(db);
+            let uuid = if reveal { identity.token().to_string() } else { crate::credential::redact(identity.token()) };
+            let auth = if crate::credential::signing(db) { "ed25519" } else { "bearer" };
+            emit(&Uuid { uuid: uuid.as_str(), auth }, || {
+                println!("uuid: {}", uuid);
+                println!("auth: {}", auth);
+            });
         }
         StatusWatch::Server { change_to } => {
             if let Some(new) = change_to {
                 db.put("server", new).exit_on_failure();
             } else {
                 let server = force_get(db, "server");
-                println!("server: {}", server);
+                emit(&Server { server: server.as_str() }, || println!("server: {}", server));
             }
         }
+        StatusWatch::Http { options, reset } => {
+            let settings = if reset || !options.is_empty() {
+                crate::http::configure(db, &options, reset)
+            } else {
+                crate::http::settings()
+            };
+            emit(&settings, || {
+                to_table(&settings).exit_on_failure().printstd();
+            });
+        }
         StatusWatch::EditBuildScript { editor, shellcheck } => {
-            let ans = force_get_json::<Status>(db, "status");
-            edit_script(editor.as_str(), true, shellcheck.as_path(), &ans, workdir);
+            edit_script(db, editor.as_str(), true, shellcheck.as_path(), workdir);
         }
         StatusWatch::EditRunScript { editor, shellcheck } => {
-            let ans = force_get_json::<Status>(db, "status");
-            edit_script(editor.as_str(), false, shellcheck.as_path(), &ans, workdir);
+            edit_script(db, editor.as_str(), false, shellcheck.as_path(), workdir);
         }
         StatusWatch::ResetSkip { id  } => {
             let server = force_get(db, "server");
-            let uuid = force_get(db, "uuid");
-            reqwest::blocking::Client::new()
-                .delete(format!("{}/student/{}/skip", server, id).parse::<Url>().exit_on_failure())
-                .bearer_auth(uuid)
-                .send()
+            let identity = crate::credential::identity(db);
+            crate::http::client()
+                .delete(format!("{}/student/{}/skip", server, url_id(id.as_str())).parse::<Url>().exit_on_failure())
+                .authenticate(&identity)
+                .dispatch()
                 .and_then(|x|x.error_for_status())
                 .exit_on_failure();
             info!("target student skipping status reset successfully");
         }
         StatusWatch::ResetGrade { id } => {
             let server = force_get(db, "server");
-            let uuid = force_get(db, "uuid");
-            reqwest::blocking::Client::new()
-                .delete(format!("{}/student/{}/grades", server, id).parse::<Url>().exit_on_failure())
-                .bearer_auth(uuid)
-                .send()
+            let identity = crate::credential::identity(db);
+            crate::http::client()
+                .delete(format!("{}/student/{}/grades", server, url_id(id.as_str())).parse::<Url>().exit_on_failure())
+                .authenticate(&identity)
+                .dispatch()
                 .and_then(|x|x.error_for_status())
                 .exit_on_failure();
             info!("target student grading status reset successfully");
@@ -245,17 +370,27 @@ pub fn handle(db: &DB, command: StatusWatch, workdir: &Path) {
     }
 }
 
-fn edit_script(editor: &str, build_or_run: bool, shellcheck: &Path, status: &Status, workdir: &Path) {
+fn edit_script(db: &DB, editor: &str, build_or_run: bool, shellcheck: &Path, workdir: &Path) {
+    let mut status = force_get_json::<Status>(db, "status");
     if status.in_progress.is_none() {
         error!("no current project");
         std::process::exit(1);
     }
     let project = status.in_progress.as_ref().unwrap();
-    let path = if build_or_run {
-        workdir.join("student").join(project.build_shell.as_path())
+    let student_dir = workdir.join("student");
+    let script = if build_or_run {
+        project.build_shell.as_path()
     } else {
-        workdir.join("student").join(project.run_shell.as_path())
+        project.run_shell.as_path()
     };
+    let path = if std::fs::symlink_metadata(student_dir.join(script)).is_ok() {
+        crate::validate::contained(student_dir.as_path(), script)
+    } else {
+        // a missing script is created by the editor, its directory must be inside
+        let parent = script.parent().unwrap_or_else(|| Path::new(""));
+        crate::validate::contained(student_dir.as_path(), parent)
+            .map(|x| x.join(script.file_name().unwrap_or_default()))
+    }.exit_on_failure();
     info!("editing {} with {}", path.display(), editor);
     std::process::Command::new(editor)
         .arg(path.as_path())
@@ -264,20 +399,10 @@ fn edit_script(editor: &str, build_or_run: bool, shellcheck: &Path, status: &Sta
         .map_err(|x| x.to_string())
         .and_then(|x| if x.success() { Ok(()) } else { Err(format!("failed with {}", x)) })
         .exit_on_failure();
-    print!("Runshell checking? [Y/n] ");
-    std::io::stdout().flush().exit_on_failure();
-    let mut line = String::new();
-    stdin().read_line(&mut line).exit_on_failure();
-    if "y" == line.trim().to_ascii_lowercase() {
-        match std::process::Command::new(shellcheck)
-            .arg(path.as_path())
-            .spawn()
-            .and_then(|mut x| x.wait())
-            .map_err(|x| x.to_string())
-            .and_then(|x| if x.success() { Ok(format!("exited with {}", x)) } else { Err(format!("failed with {}", x)) })
-        {
-            Ok(t) => { info!("{}", t) }
-            Err(t) => { error!("{}", t) }
-        }
+    crate::lint::check(&mut status, shellcheck, workdir);
+    if let Some(findings) = &status.lint {
+        crate::output::show(&crate::lint::lint_table(findings));
     }
-}
\ No newline at end of file
+    db.put("status", serde_json::to_string(&status)
+        .exit_on_failure()).exit_on_failure();
+}
@@ -1,14 +1,16 @@
-use std::io::{stdout, Write};
 use std::path::Path;
 
 use log::*;
-use reqwest::{blocking, Url};
+use reqwest::Url;
 use rocksdb::DB;
 use serde::*;
 
-use crate::{clear_status, force_get, force_get_json, LogUnwrap};
-use crate::cli::StatusWatch;
-use crate::settings::{Status, StudentConfig, to_table};
+use crate::cache::Cache;
+use crate::credential::Authenticate;
+use crate::http::Dispatch;
+use crate::{clear_status, force_get, force_get_json, LogUnwrap, prompt};
+use crate::settings::{Config, Status, StudentConfig, to_table};
+use crate::validate::url_id;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct StudentConfigResponse {
@@ -16,9 +18,12 @@ pub struct StudentConfigResponse {
     failure: Option<String>,
 }
 
-pub fn handle_request(db: &DB, backend: &str, workdir: &Path, download_only: bool, shellcheck: &Path, id: Option<String>) {
+/// Exit code of `fetch` when the server has no student to assign.
+pub const NO_STUDENT: i32 = 2;
+
+pub fn handle_request(db: &DB, backend: &str, cache: &Cache, workdir: &Path, download_only: bool, shellcheck: &Path, id: Option<String>) {
     let server = force_get(db, "server");
-    let uuid = force_get(db, "uuid");
+    let identity = crate::credential::identity(db);
     let mut status = force_get_json::<Status>(db, "status");
     if let Some(t) = id {
         clear_status(db, &mut status, workdir);
@@ -43,11 +48,21 @@ pub fn handle_request(db: &DB, backend: &str, workdir: &Path, download_only: boo
             stderr: None,
             build_stdout: None,
             build_stderr: None,
+            test_results: Vec::new(),
+            verdict: None,
+            lint: None,
+            suggested: None,
+            rubric: Vec::new(),
+            adjustments: Vec::new(),
+            risks: Vec::new(),
+            notable: Vec::new(),
+            normalized: Vec::new(),
+            similar: Vec::new(),
         };
-        let mut new_student = reqwest::blocking::Client::new()
+        let mut new_student = crate::http::client()
             .get(format!("{}/next", server).parse::<Url>().exit_on_failure())
-            .bearer_auth(uuid.as_str())
-            .send()
+            .authenticate(&identity)
+            .dispatch()
             .and_then(|x| x.error_for_status())
             .map_err(|x| x.to_string())
             .and_then(|x| {
@@ -57,68 +72,41 @@ pub fn handle_request(db: &DB, backend: &str, workdir: &Path, download_only: boo
                     simd_json::serde::from_str::<StudentConfigResponse>(x.as_mut_str())
                         .map_err(|x| x.to_string()))
             }).exit_on_failure();
-        if let Some(f) = new_student.failure {
-            error!("failed to get next student: {}", f);
-            std::process::exit(1);
-        }
+        let student = match new_student.student.take() {
+            Some(student) => crate::validate::student(student).exit_on_failure(),
+            None => {
+                match new_student.failure {
+                    Some(f) => error!("failed to get next student: {}", f),
+                    None => error!("server returned no student"),
+                }
+                std::process::exit(NO_STUDENT);
+            }
+        };
         clear_status(db, &mut status, workdir);
-        status.in_progress.replace(new_student.student.take().unwrap());
+        status.in_progress.replace(student);
     } else {
         if status.in_progress.is_none() {
             error!("current project not existing, exiting");
             std::process::exit(1);
         }
-        let ans = reqwest::blocking::Client::new()
-            .get(format!("{}/student/{}/info", server, status.in_progress.as_ref().unwrap().student_id.as_str())
+        let ans = crate::http::client()
+            .get(format!("{}/student/{}/info", server, url_id(status.in_progress.as_ref().unwrap().student_id.as_str()))
                 .parse::<Url>().exit_on_failure())
-            .bearer_auth(uuid.as_str())
-            .send()
+            .authenticate(&identity)
+            .dispatch_idempotent()
             .and_then(|x| x.error_for_status())
             .exit_on_failure()
             .json::<StudentConfig>()
+            .map_err(|x| x.to_string())
+            .and_then(crate::validate::student)
             .exit_on_failure();
         clear_status(db, &mut status, workdir);
         status.in_progress.replace(ans);
     }
+    crate::batch::restore_mark(db, &mut status);
     db.put("status", serde_json::to_vec(&status).exit_on_failure()).exit_on_failure();
-    let auth = format!("Authorization: Bearer {}", uuid.as_str());
     let student = status.in_progress.as_ref().unwrap();
-    match backend {
-        "wget" => {
-            std::process::Command::new("wget")
-                .arg("--show-progress")
-                .arg("-O")
-                .arg(format!("/tmp/{}", student.student_id.as_str()))
-                .arg("--header")
-                .arg(auth)
-                .arg(format!("{}/student/{}/tar", server, student.student_id.as_str()))
-                .spawn()
-                .exit_on_failure()
-                .wait()
-                .map_err(|x| x.to_string())
-                .and_then(|x| if x.success() { Ok(()) } else { Err(format!("wget failed with: {}", x)) })
-                .exit_on_failure();
-        }
-        "aria2c" => {
-            std::process::Command::new("aria2c")
-                .arg("--optimize-concurrent-downloads")
-                .arg("--auto-file-renaming=false")
-                .arg("--dir")
-                .arg("/tmp")
-                .arg("-o")
-                .arg(student.student_id.as_str())
-                .arg("--header")
-                .arg(auth)
-                .arg(format!("{}/student/{}/tar", server, student.student_id.as_str()))
-                .spawn()
-                .exit_on_failure()
-                .wait()
-                .map_err(|x| x.to_string())
-                .and_then(|x| if x.success() { Ok(()) } else { Err(format!("aria2c failed with: {}", x)) })
-                .exit_on_failure();
-        }
-        _ => unreachable!()
-    }
+    let tarball = cache.fetch(backend, server.as_str(), &identity, student.student_id.as_str());
 
     let student_dir = workdir.join("student");
 
@@ -128,37 +116,26 @@ pub fn handle_request(db: &DB, backend: &str, workdir: &Path, download_only: boo
         .arg("-C")
         .arg(workdir.join("student").canonicalize().exit_on_failure())
         .arg("-xf")
-        .arg(format!("/tmp/{}", student.student_id.as_str()))
+        .arg(tarball.as_path())
         .spawn()
         .exit_on_failure()
         .wait()
         .map_err(|x| x.to_string())
-        .and_then(|x| if x.success() { Ok(()) } else { Err(format!("aria2c failed with: {}", x)) })
+        .and_then(|x| if x.success() { Ok(()) } else { Err(format!("tar failed with: {}", x)) })
         .exit_on_failure();
 
-    info!("shellchecking build script");
-
-    if let Err(e) = std::process::Command::new(shellcheck)
-        .arg(student_dir.join(student.build_shell.as_path()))
-        .spawn()
-        .and_then(|mut x| x.wait())
-        .map_err(|x| x.to_string())
-        .and_then(|x| if x.success() { Ok(()) } else { Err(format!("failed with {}", x)) })
-    {
-        warn!("failed to shellcheck build script: {}", e);
-    };
-
-    info!("shellchecking run script");
-
-    if let Err(e) = std::process::Command::new(shellcheck)
-        .arg(student_dir.join(student.run_shell.as_path()))
-        .spawn()
-        .and_then(|mut x| x.wait())
-        .map_err(|x| x.to_string())
-        .and_then(|x| if x.success() { Ok(()) } else { Err(format!("failed with {}", x)) })
-    {
-        warn!("failed to shellcheck run script: {}", e);
-    };
+    let config = db.get("config")
+        .ok().flatten()
+        .and_then(|mut x| simd_json::serde::from_slice::<Config>(x.as_mut_slice()).ok());
+    if let Some(policy) = config.as_ref().and_then(|x| x.normalize.as_ref()) {
+        crate::normalize::handle(policy, &mut status, workdir);
+    }
+    crate::lint::check(&mut status, shellcheck, workdir);
+    let hash = tarball.file_name().map(|x| x.to_string_lossy().to_string());
+    crate::similarity::index(config.as_ref(), &status, workdir, hash);
+    crate::risk::scan(config.as_ref(), &mut status, workdir);
+    db.put("status", serde_json::to_vec(&status).exit_on_failure()).exit_on_failure();
+    let student = status.in_progress.as_ref().unwrap();
 
     info!("student information synced");
 
@@ -168,8 +145,8 @@ pub fn handle_request(db: &DB, backend: &str, workdir: &Path, download_only: boo
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-struct SubmissionResponse {
-    failure: Option<String>
+pub(crate) struct SubmissionResponse {
+    pub failure: Option<String>
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -178,37 +155,38 @@ struct InProgressShow<'a> {
     comment: Option<&'a str>,
     built: bool,
     mark: bool,
-    graded: Option<usize>,
+    graded: Option<f64>,
 }
 
 pub fn handle_submit(db: &DB, r#override: bool) {
     let server = force_get(db, "server");
-    let uuid = force_get(db, "uuid");
+    let identity = crate::credential::identity(db);
     let mut status = force_get_json::<Status>(db, "status");
     if status.in_progress.is_none() {
         println!("no current project");
         std::process::exit(1);
     }
+    let lint_feedback = db.get("config")
+        .ok().flatten()
+        .and_then(|mut x| simd_json::serde::from_slice::<Config>(x.as_mut_slice()).ok())
+        .map(|x| x.lint_feedback)
+        .unwrap_or(false);
     let in_progess = status.in_progress.as_ref().unwrap();
     let show = InProgressShow {
         student_id: in_progess.student_id.as_str().clone(),
         comment: status.comment.as_ref().map(|x| x.as_str()),
         built: status.built,
         mark: status.mark,
-        graded: status.graded,
+        graded: status.total(),
     };
-    to_table(&show).exit_on_failure().printstd();
-    print!("Are you sure to submit? [Y/n] ");
-    stdout().flush().exit_on_failure();
-    let mut buffer = String::new();
-    std::io::stdin().read_line(&mut buffer).exit_on_failure();
-    if "y" != buffer.trim().to_ascii_lowercase() { return; }
-    blocking::Client::new()
+    crate::output::show(&to_table(&show).exit_on_failure());
+    if !prompt::confirm("submit", "Are you sure to submit?", true) { return; }
+    crate::http::client()
         .put(format!("{}/student/{}/grades", server,
-                     status.in_progress.as_ref().unwrap().student_id).parse::<Url>().exit_on_failure())
-        .bearer_auth(uuid)
-        .json(&status.get_submission(r#override))
-        .send()
+                     url_id(status.in_progress.as_ref().unwrap().student_id.as_str())).parse::<Url>().exit_on_failure())
+        .json(&status.get_submission(r#override, lint_feedback))
+        .authenticate(&identity)
+        .dispatch()
         .and_then(|x| x.error_for_status())
         .and_then(|x| x.json::<SubmissionResponse>())
         .map_err(|x| x.to_string())
@@ -225,16 +203,16 @@ pub fn handle_submit(db: &DB, r#override: bool) {
 
 pub fn skip(db: &DB, force: bool, workdir: &Path) {
     let server = force_get(db, "server");
-    let uuid = force_get(db, "uuid");
+    let identity = crate::credential::identity(db);
     let mut status = force_get_json::<Status>(db, "status");
     let student = status.in_progress.as_ref().unwrap_or_else(|| {
         error!("nothing to skip");
         std::process::exit(1);
     });
-    let code = blocking::Client::new()
-        .put(format!("{}/student/{}/skip", server, student.student_id).parse::<Url>().exit_on_failure())
-        .bearer_auth(uuid)
-        .send()
+    let code = crate::http::client()
+        .put(format!("{}/student/{}/skip", server, url_id(student.student_id.as_str())).parse::<Url>().exit_on_failure())
+        .authenticate(&identity)
+        .dispatch()
         .map(|x| x.status().is_success())
         .unwrap_or_else(|x| {
             error!("{}", x);
@@ -247,147 +225,14 @@ pub fn skip(db: &DB, force: bool, workdir: &Path) {
     clear_status(db, &mut status, workdir);
 }
 
-pub fn pull(workdir: &Path, id: String, db: &DB, backend: &str, shellcheck: &Path) {
+pub fn pull(workdir: &Path, id: String, db: &DB, backend: &str, cache: &Cache, shellcheck: &Path) {
+    crate::validate::student_id(id.as_str()).exit_on_failure();
     let status = force_get_json::<Status>(db, "status");
     if status.in_progress.is_some() && !status.submitted {
         error!("current project is not submitted");
         std::process::exit(1);
     }
-    handle_request(db, backend, workdir, true, shellcheck, Some(id));
-}
-
-pub fn auto_current(workdir: &Path, db: &DB, nutshell: &Path, tmp_size: Option<usize>, mount_point: &Path, shellcheck: &Path, editor: &str, reader: &Path) {
-    let mut status = force_get_json::<Status>(db, "status");
-    if status.in_progress.is_none() {
-        error!("No current project");
-        std::process::exit(1);
-    }
-    if !status.image {
-        error!("No current image");
-        std::process::exit(1);
-    }
-    if status.in_progress.as_ref().and_then(|x| x.report.as_ref()).is_some() {
-        let mut result = String::new();
-        print!("This student has report, open it? [Y/n] ");
-        std::io::stdout().flush().exit_on_failure();
-        std::io::stdin().read_line(&mut result).exit_on_failure();
-        if "y" == result.trim().to_ascii_lowercase() {
-            report(db, reader, workdir);
-        }
-    }
-    let mut mount = true;
-    if status.mount.is_some() {
-        let mut result = String::new();
-        print!("Already mount, re-init the overlay? [Y/n] ");
-        std::io::stdout().flush().exit_on_failure();
-        std::io::stdin().read_line(&mut result).exit_on_failure();
-        mount = "y" == result.trim().to_ascii_lowercase();
-        if mount {
-            crate::overlay::handle_destroy(db, workdir);
-        }
-    }
-    if mount {
-        crate::overlay::handle(
-            db, workdir, nutshell, false, false, mount_point, tmp_size, false,
-        );
-    }
-    status = force_get_json::<Status>(db, "status");
-    info!("overlay intialized");
-
-    {
-        let mut result = String::new();
-        print!("Enter the current overlay? [Y/n] ");
-        std::io::stdout().flush().exit_on_failure();
-        std::io::stdin().read_line(&mut result).exit_on_failure();
-        if "y" == result.trim().to_ascii_lowercase() {
-            let code = crate::build::build_nspawn(db, &status, workdir, false, false)
-                .spawn()
-                .exit_on_failure()
-                .wait()
-                .exit_on_failure();
-            info!("nspawn {}", code);
-        }
-    }
-
-    {
-        let mut result = String::new();
-        print!("View the build script? [Y/n] ");
-        std::io::stdout().flush().exit_on_failure();
-        std::io::stdin().read_line(&mut result).exit_on_failure();
-        if "y" == result.trim().to_ascii_lowercase() {
-            crate::status::handle(db, StatusWatch::EditBuildScript {
-                editor: editor.to_string(),
-                shellcheck: shellcheck.to_path_buf(),
-            }, workdir);
-        }
-    }
-
-    let mut build = true;
-    if status.built {
-        let mut result = String::new();
-        print!("Already build, re-build? [Y/n] ");
-        std::io::stdout().flush().exit_on_failure();
-        std::io::stdin().read_line(&mut result).exit_on_failure();
-        build = "y" == result.trim().to_ascii_lowercase();
-    }
-
-    if build {
-        crate::build::handle(db, true, workdir);
-    }
-
-    {
-        let mut result = String::new();
-        print!("Enter the sandboxed overlay? [Y/n] ");
-        std::io::stdout().flush().exit_on_failure();
-        std::io::stdin().read_line(&mut result).exit_on_failure();
-        if "y" == result.trim().to_ascii_lowercase() {
-            let code = crate::build::build_nspawn(db, &status, workdir, false, true)
-                .spawn()
-                .exit_on_failure()
-                .wait()
-                .exit_on_failure();
-            info!("nspawn {}", code);
-        }
-    }
-
-    {
-        let mut result = String::new();
-        print!("View the run script? [Y/n] ");
-        std::io::stdout().flush().exit_on_failure();
-        std::io::stdin().read_line(&mut result).exit_on_failure();
-        if "y" == result.trim().to_ascii_lowercase() {
-            crate::status::handle(db, StatusWatch::EditRunScript {
-                editor: editor.to_string(),
-                shellcheck: shellcheck.to_path_buf(),
-            }, workdir);
-        }
-    }
-
-    {
-        let mut result = String::new();
-        print!("Start running? [Y/n] ");
-        std::io::stdout().flush().exit_on_failure();
-        std::io::stdin().read_line(&mut result).exit_on_failure();
-        if "y" == result.trim().to_ascii_lowercase() {
-            crate::run::run(db, false);
-        }
-    }
-
-    {
-        let mut result = String::new();
-        print!("Enter the firejailed overlay? [Y/n] ");
-        std::io::stdout().flush().exit_on_failure();
-        std::io::stdin().read_line(&mut result).exit_on_failure();
-        if "y" == result.trim().to_ascii_lowercase() {
-            let config = force_get_json(db, "config");
-            let code = crate::run::build_firejail(mount_point, &config, true)
-                .spawn()
-                .exit_on_failure()
-                .wait()
-                .exit_on_failure();
-            info!("nspawn {}", code);
-        }
-    }
+    handle_request(db, backend, cache, workdir, true, shellcheck, Some(id));
 }
 
 pub fn report(db: &DB, reader: &Path, workdir: &Path) {
@@ -401,11 +246,13 @@ pub fn report(db: &DB, reader: &Path, workdir: &Path) {
         error!("This student has no report");
         std::process::exit(1);
     }
-    let report = student.report.as_ref().unwrap();
+    let report = crate::validate::relative_path("report", student.report.as_ref().unwrap())
+        .and_then(|x| crate::validate::contained(workdir.join("student").as_path(), x.as_path()))
+        .exit_on_failure();
     std::process::Command::new("firejail")
         .arg("--overlay-tmpfs")
         .arg(reader)
-        .arg(workdir.join("student").join(report))
+        .arg(report)
         .spawn()
         .exit_on_failure()
         .wait()
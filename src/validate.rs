@@ -0,0 +1,149 @@
+use std::path::{Component, Path, PathBuf};
+
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
+
+use crate::settings::StudentConfig;
+
+// everything except the unreserved characters of RFC 3986
+const SEGMENT: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'_').remove(b'.').remove(b'~');
+
+/// Encode a student id as a single URL path segment.
+pub fn url_id(id: &str) -> String {
+    utf8_percent_encode(id, SEGMENT).to_string()
+}
+
+/// A student id is also used as a file name, so it must be a single harmless path component.
+pub fn student_id(id: &str) -> Result<(), String> {
+    let problem = if id.is_empty() {
+        Some("must not be empty")
+    } else if id.len() > 128 {
+        Some("must not be longer than 128 bytes")
+    } else if id == "." || id == ".." {
+        Some("must not be a relative directory")
+    } else if id.starts_with('-') || id.starts_with('.') {
+        Some("must not start with '-' or '.'")
+    } else if id.chars().any(|x| x == '/' || x == '\\' || x.is_control()) {
+        Some("must not contain slashes or control characters")
+    } else {
+        None
+    };
+    match problem {
+        Some(problem) => Err(format!("invalid student_id {:?}: {}", id, problem)),
+        None => Ok(())
+    }
+}
+
+/// Normalise a path that must stay inside the student directory.
+pub fn relative_path(field: &str, path: &Path) -> Result<PathBuf, String> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(x) => {
+                if x.to_string_lossy().chars().any(|x| x.is_control()) {
+                    return Err(format!("invalid {} {:?}: must not contain control characters", field, path));
+                }
+                normalized.push(x)
+            }
+            Component::CurDir => (),
+            Component::ParentDir => return Err(format!("invalid {} {:?}: must not contain '..'", field, path)),
+            Component::RootDir | Component::Prefix(_) => return Err(format!("invalid {} {:?}: must be relative", field, path)),
+        }
+    }
+    if normalized.as_os_str().is_empty() {
+        return Err(format!("invalid {} {:?}: must name a file", field, path));
+    }
+    Ok(normalized)
+}
+
+/// Validate a student config from the server before it touches the filesystem.
+pub fn student(mut config: StudentConfig) -> Result<StudentConfig, String> {
+    student_id(config.student_id.as_str())?;
+    config.build_shell = relative_path("build_shell", config.build_shell.as_path())?;
+    config.run_shell = relative_path("run_shell", config.run_shell.as_path())?;
+    if let Some(report) = config.report.take() {
+        config.report.replace(relative_path("report", report.as_path())?);
+    }
+    Ok(config)
+}
+
+/// Resolve symbolic links and make sure the path is still inside the directory.
+pub fn contained(dir: &Path, path: &Path) -> Result<PathBuf, String> {
+    let dir = dir.canonicalize().map_err(|x| format!("{}: {}", dir.display(), x))?;
+    let resolved = dir.join(path).canonicalize().map_err(|x| format!("{}: {}", path.display(), x))?;
+    if resolved.starts_with(dir.as_path()) {
+        Ok(resolved)
+    } else {
+        Err(format!("{} points outside of {}", path.display(), dir.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_an_id_as_one_segment() {
+        assert_eq!(url_id("2019-abc_1.x~"), "2019-abc_1.x~");
+        assert_eq!(url_id("a/b?c#d"), "a%2Fb%3Fc%23d");
+        assert_eq!(url_id("ü b"), "%C3%BC%20b");
+    }
+
+    #[test]
+    fn accepts_a_plain_id() {
+        assert!(student_id("2019011234").is_ok());
+        assert!(student_id("jane.doe-2").is_ok());
+    }
+
+    #[test]
+    fn rejects_a_harmful_id() {
+        for id in &["", ".", "..", "-rf", ".hidden", "a/b", "..\\b", "a\nb"] {
+            assert!(student_id(id).is_err(), "{:?} accepted", id);
+        }
+        assert!(student_id("x".repeat(129).as_str()).is_err());
+    }
+
+    #[test]
+    fn normalizes_a_relative_path() {
+        assert_eq!(relative_path("run_shell", Path::new("./scripts/./run.sh")).unwrap(), PathBuf::from("scripts/run.sh"));
+    }
+
+    #[test]
+    fn rejects_an_escaping_path() {
+        assert!(relative_path("run_shell", Path::new("../run.sh")).is_err());
+        assert!(relative_path("run_shell", Path::new("scripts/../../run.sh")).is_err());
+        assert!(relative_path("run_shell", Path::new("/bin/sh")).is_err());
+        assert!(relative_path("run_shell", Path::new(".")).is_err());
+        assert!(relative_path("run_shell", Path::new("")).is_err());
+    }
+
+    #[test]
+    fn follows_a_symlink_inside_the_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("scripts")).unwrap();
+        std::fs::write(dir.path().join("scripts/run.sh"), "").unwrap();
+        std::os::unix::fs::symlink("scripts/run.sh", dir.path().join("run.sh")).unwrap();
+        let resolved = contained(dir.path(), Path::new("run.sh")).unwrap();
+        assert_eq!(resolved, dir.path().canonicalize().unwrap().join("scripts/run.sh"));
+    }
+
+    #[test]
+    fn rejects_a_symlink_escaping_the_directory() {
+        let outside = tempfile::tempdir().unwrap();
+        std::fs::write(outside.path().join("secret"), "").unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(outside.path().join("secret"), dir.path().join("run.sh")).unwrap();
+        std::os::unix::fs::symlink(outside.path(), dir.path().join("scripts")).unwrap();
+        assert!(contained(dir.path(), Path::new("run.sh")).is_err());
+        assert!(contained(dir.path(), Path::new("scripts/secret")).is_err());
+    }
+
+    #[test]
+    fn rejects_a_parent_path_and_a_missing_file() {
+        let parent = tempfile::tempdir().unwrap();
+        std::fs::write(parent.path().join("secret"), "").unwrap();
+        std::fs::create_dir(parent.path().join("student")).unwrap();
+        let dir = parent.path().join("student");
+        assert!(contained(dir.as_path(), Path::new("../secret")).is_err());
+        assert!(contained(dir.as_path(), Path::new("missing.sh")).is_err());
+    }
+}
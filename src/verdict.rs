@@ -0,0 +1,278 @@
+use std::fmt::{Display, Formatter};
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+use std::time::{Duration, Instant};
+
+use log::*;
+use prettytable::Cell;
+
+use crate::settings::{Config, Verdict};
+
+const SIGNALS: [&str; 31] = [
+    "SIGHUP", "SIGINT", "SIGQUIT", "SIGILL", "SIGTRAP", "SIGABRT", "SIGBUS", "SIGFPE",
+    "SIGKILL", "SIGUSR1", "SIGSEGV", "SIGUSR2", "SIGPIPE", "SIGALRM", "SIGTERM", "SIGSTKFLT",
+    "SIGCHLD", "SIGCONT", "SIGSTOP", "SIGTSTP", "SIGTTIN", "SIGTTOU", "SIGURG", "SIGXCPU",
+    "SIGXFSZ", "SIGVTALRM", "SIGPROF", "SIGWINCH", "SIGIO", "SIGPWR", "SIGSYS",
+];
+
+pub const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+const ALLOCATION_FAILURES: [&str; 4] = [
+    "std::bad_alloc", "Cannot allocate memory", "MemoryError", "out of memory",
+];
+
+pub fn signal_name(signal: i32) -> String {
+    SIGNALS.get((signal - 1) as usize)
+        .map(|x| x.to_string())
+        .unwrap_or_else(|| format!("SIG{}", signal))
+}
+
+/// Records what is needed to classify a sandboxed run before it is started.
+pub struct Probe {
+    started: Instant,
+    // the cgroup of this run only, removed when the probe is dropped
+    cgroup: Option<PathBuf>,
+}
+
+impl Probe {
+    /// Give the sandbox a cgroup of its own, limited to `mem_limit` if there is one, so that its `oom_kill`
+    /// counter only counts kills in the sandbox. Call it before the program is added to the firejail command.
+    pub fn start(command: &mut Command, config: &Config) -> Self {
+        let cgroup = sandbox_cgroup(config);
+        if let Some(cgroup) = &cgroup {
+            command.arg(format!("--cgroup={}", cgroup.join("cgroup.procs").display()));
+        }
+        Probe {
+            started: Instant::now(),
+            cgroup,
+        }
+    }
+
+    /// Classify a finished firejail run. `firejail --deterministic-exit-code` reports
+    /// a program killed by a signal as `128 + signal`.
+    pub fn classify(&self, config: &Config, status: &ExitStatus, output_exceeded: bool, stderr: &str) -> Verdict {
+        let elapsed = self.started.elapsed();
+        if self.cgroup.as_deref().and_then(oom_kills).unwrap_or(0) > 0 {
+            return Verdict::MemoryLimitExceeded;
+        }
+        if output_exceeded {
+            return Verdict::OutputLimitExceeded;
+        }
+        if let Some(signal) = status.signal() {
+            return Verdict::SandboxError { reason: format!("firejail terminated by {}", signal_name(signal)) };
+        }
+        if timeout(config).map(|x| elapsed >= x).unwrap_or(false) {
+            return Verdict::TimeLimitExceeded;
+        }
+        match status.code() {
+            Some(0) => Verdict::Accepted,
+            Some(code) if code > 128 && code <= 128 + SIGNALS.len() as i32 => {
+                let signal = code - 128;
+                let mem_limited = config.firejail.limit.as_ref().and_then(|x| x.mem_limit).is_some();
+                match signal_name(signal).as_str() {
+                    "SIGXCPU" => Verdict::TimeLimitExceeded,
+                    "SIGXFSZ" => Verdict::OutputLimitExceeded,
+                    "SIGABRT" | "SIGSEGV" | "SIGKILL"
+                    if mem_limited && ALLOCATION_FAILURES.iter().any(|x| stderr.contains(x)) => {
+                        Verdict::MemoryLimitExceeded
+                    }
+                    name => Verdict::RuntimeError { signal: Some(name.to_string()), code: None }
+                }
+            }
+            code => Verdict::RuntimeError { signal: None, code }
+        }
+    }
+}
+
+fn timeout(config: &Config) -> Option<Duration> {
+    config.firejail.timeout.as_ref().map(|x| {
+        Duration::from_secs(x.hour as u64 * 3600 + x.minute as u64 * 60 + x.second as u64)
+    })
+}
+
+impl Drop for Probe {
+    fn drop(&mut self) {
+        if let Some(cgroup) = &self.cgroup {
+            if let Err(e) = std::fs::remove_dir(cgroup) {
+                debug!("unable to remove {}: {}", cgroup.display(), e);
+            }
+        }
+    }
+}
+
+/// A new cgroup v2 group under `tulip/` with the memory controller, `None` without cgroup v2 or the permission,
+/// in which case out of memory kills are only guessed from stderr.
+fn sandbox_cgroup(config: &Config) -> Option<PathBuf> {
+    let root = Path::new(CGROUP_ROOT);
+    if !root.join("cgroup.controllers").exists() {
+        debug!("cgroup v2 is not mounted");
+        return None;
+    }
+    let parent = root.join("tulip");
+    let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|x| x.subsec_nanos()).unwrap_or(0);
+    let cgroup = parent.join(format!("run-{}-{}", std::process::id(), nanos));
+    let created = std::fs::create_dir_all(parent.as_path())
+        .and_then(|_| std::fs::write(parent.join("cgroup.subtree_control"), "+memory"))
+        .and_then(|_| std::fs::create_dir(cgroup.as_path()));
+    if let Err(e) = created {
+        debug!("unable to create a cgroup for the sandbox: {}", e);
+        return None;
+    }
+    if !cgroup.join("memory.events").exists() {
+        debug!("no memory controller in {}", cgroup.display());
+        std::fs::remove_dir(cgroup.as_path()).ok();
+        return None;
+    }
+    if let Some(mem) = config.firejail.limit.as_ref().and_then(|x| x.mem_limit) {
+        if let Err(e) = std::fs::write(cgroup.join("memory.max"), mem.to_string()) {
+            warn!("unable to limit the memory of the sandbox cgroup: {}", e);
+        }
+    }
+    Some(cgroup)
+}
+
+/// Read the `oom_kill` counter of the cgroup of the sandbox.
+fn oom_kills(cgroup: &Path) -> Option<u64> {
+    let events = std::fs::read_to_string(cgroup.join("memory.events"))
+        .map_err(|x| debug!("unable to read memory events: {}", x))
+        .ok()?;
+    events.lines()
+        .find_map(|x| x.strip_prefix("oom_kill "))
+        .and_then(|x| x.trim().parse().ok())
+}
+
+impl Verdict {
+    pub fn cell(&self) -> Cell {
+        let style = match self {
+            Verdict::Accepted => "bFg",
+            Verdict::WrongAnswer => "bFr",
+            Verdict::TimeLimitExceeded | Verdict::MemoryLimitExceeded | Verdict::OutputLimitExceeded => "bFy",
+            Verdict::RuntimeError { .. } => "bFm",
+            Verdict::SandboxError { .. } => "bFw",
+        };
+        Cell::new(self.to_string().as_str()).style_spec(style)
+    }
+}
+
+impl Display for Verdict {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Verdict::Accepted => write!(f, "Accepted"),
+            Verdict::WrongAnswer => write!(f, "Wrong Answer"),
+            Verdict::TimeLimitExceeded => write!(f, "Time Limit Exceeded"),
+            Verdict::MemoryLimitExceeded => write!(f, "Memory Limit Exceeded"),
+            Verdict::RuntimeError { signal: Some(signal), .. } => write!(f, "Runtime Error ({})", signal),
+            Verdict::RuntimeError { code: Some(code), .. } => write!(f, "Runtime Error (exit code {})", code),
+            Verdict::RuntimeError { .. } => write!(f, "Runtime Error"),
+            Verdict::OutputLimitExceeded => write!(f, "Output Limit Exceeded"),
+            Verdict::SandboxError { reason } => write!(f, "Sandbox Error ({})", reason),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::settings::{Limit, Timeout};
+
+    use super::*;
+
+    fn config(timeout: u8, mem_limit: Option<usize>) -> Config {
+        let mut config = Config::default();
+        config.firejail.timeout = Some(Timeout { hour: 0, minute: 0, second: timeout });
+        config.firejail.limit = Some(Limit { mem_limit, ..Limit::default() });
+        config
+    }
+
+    fn probe(elapsed: u64, oom_kill: Option<u64>) -> (Probe, Option<tempfile::TempDir>) {
+        let cgroup = oom_kill.map(|x| {
+            let dir = tempfile::tempdir().unwrap();
+            std::fs::write(dir.path().join("memory.events"), format!("low 0\nhigh 0\nmax 3\noom 1\noom_kill {}\n", x)).unwrap();
+            dir
+        });
+        let probe = Probe {
+            started: Instant::now().checked_sub(Duration::from_secs(elapsed)).unwrap(),
+            cgroup: cgroup.as_ref().map(|x| x.path().to_path_buf()),
+        };
+        (probe, cgroup)
+    }
+
+    // `from_raw` takes a wait status: the code in the second byte, or the signal in the low bits
+    fn exited(code: i32) -> ExitStatus {
+        ExitStatus::from_raw(code << 8)
+    }
+
+    fn killed(signal: i32) -> ExitStatus {
+        ExitStatus::from_raw(signal)
+    }
+
+    #[test]
+    fn names_the_signals() {
+        assert_eq!(signal_name(9), "SIGKILL");
+        assert_eq!(signal_name(24), "SIGXCPU");
+        assert_eq!(signal_name(64), "SIG64");
+    }
+
+    #[test]
+    fn accepts_a_clean_exit() {
+        let (probe, _cgroup) = probe(0, Some(0));
+        assert_eq!(probe.classify(&config(10, None), &exited(0), false, ""), Verdict::Accepted);
+    }
+
+    #[test]
+    fn an_oom_kill_comes_first() {
+        let (probe, _cgroup) = probe(20, Some(1));
+        assert_eq!(probe.classify(&config(10, None), &killed(9), true, ""), Verdict::MemoryLimitExceeded);
+    }
+
+    #[test]
+    fn the_output_limit_comes_before_signals_and_time() {
+        let (probe, _cgroup) = probe(20, Some(0));
+        assert_eq!(probe.classify(&config(10, None), &killed(9), true, ""), Verdict::OutputLimitExceeded);
+        assert_eq!(probe.classify(&config(10, None), &exited(137), true, ""), Verdict::OutputLimitExceeded);
+    }
+
+    #[test]
+    fn a_killed_firejail_is_a_sandbox_error() {
+        let (probe, _cgroup) = probe(20, None);
+        assert_eq!(probe.classify(&config(10, None), &killed(15), false, ""),
+                   Verdict::SandboxError { reason: String::from("firejail terminated by SIGTERM") });
+    }
+
+    #[test]
+    fn the_timeout_comes_before_the_exit_code() {
+        let (probe, _cgroup) = probe(10, None);
+        assert_eq!(probe.classify(&config(10, None), &exited(137), false, ""), Verdict::TimeLimitExceeded);
+        assert_eq!(probe.classify(&config(10, None), &exited(0), false, ""), Verdict::TimeLimitExceeded);
+        assert_eq!(probe.classify(&config(11, None), &exited(0), false, ""), Verdict::Accepted);
+    }
+
+    #[test]
+    fn maps_the_limit_signals() {
+        let (probe, _cgroup) = probe(0, None);
+        assert_eq!(probe.classify(&config(10, None), &exited(128 + 24), false, ""), Verdict::TimeLimitExceeded);
+        assert_eq!(probe.classify(&config(10, None), &exited(128 + 25), false, ""), Verdict::OutputLimitExceeded);
+    }
+
+    #[test]
+    fn guesses_an_allocation_failure_only_with_a_memory_limit() {
+        let (probe, _cgroup) = probe(0, None);
+        let stderr = "terminate called after throwing an instance of 'std::bad_alloc'";
+        assert_eq!(probe.classify(&config(10, Some(1 << 20)), &exited(128 + 6), false, stderr), Verdict::MemoryLimitExceeded);
+        assert_eq!(probe.classify(&config(10, None), &exited(128 + 6), false, stderr),
+                   Verdict::RuntimeError { signal: Some(String::from("SIGABRT")), code: None });
+        assert_eq!(probe.classify(&config(10, Some(1 << 20)), &exited(128 + 6), false, "assertion failed"),
+                   Verdict::RuntimeError { signal: Some(String::from("SIGABRT")), code: None });
+    }
+
+    #[test]
+    fn reports_other_failures_as_runtime_errors() {
+        let (probe, _cgroup) = probe(0, None);
+        assert_eq!(probe.classify(&config(10, None), &exited(128 + 11), false, ""),
+                   Verdict::RuntimeError { signal: Some(String::from("SIGSEGV")), code: None });
+        assert_eq!(probe.classify(&config(10, None), &exited(1), false, ""),
+                   Verdict::RuntimeError { signal: None, code: Some(1) });
+        assert_eq!(probe.classify(&config(10, None), &exited(200), false, ""),
+                   Verdict::RuntimeError { signal: None, code: Some(200) });
+    }
+}